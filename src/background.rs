@@ -0,0 +1,430 @@
+#[path = "collider.rs"] mod collider;
+pub use collider::*;
+use std::sync::{Arc, Mutex};
+
+
+// Offset of the interval of `cdf` (ascending, starting at zero and ending at one) that contains `u`
+fn find_interval(cdf: &[f32], u: f32) -> usize {
+    cdf.partition_point(|c| *c <= u).saturating_sub(1).min(cdf.len() - 2)
+}
+
+// Normalized running sums of `func`, `func.len() + 1` entries starting at zero. Returns the sums and
+// the integral of `func` as a step function over [0, 1). Zero functions get a uniform distribution.
+fn cumulative(func: &[f32]) -> (Vec<f32>, f32) {
+    let n = func.len();
+    let mut cdf = Vec::with_capacity(n + 1);
+    cdf.push(0.0);
+    for (i, f) in func.iter().enumerate() {
+        cdf.push(cdf[i] + f / n as f32);
+    }
+    let integral = cdf[n];
+    for (i, c) in cdf.iter_mut().enumerate() {
+        *c = if integral > 0.0 { *c / integral } else { i as f32 / n as f32 };
+    }
+    (cdf, integral)
+}
+
+
+// Piecewise constant density over [0, 1)^2 with one cell per entry of a `width` x `height` grid.
+// Points are drawn by picking a row from the marginal distribution and then a column from the
+// conditional distribution of that row.
+pub struct Distribution2D {
+    width: usize,
+    height: usize,
+    func: Vec<f32>,
+    conditional_cdfs: Vec<Vec<f32>>,
+    marginal_cdf: Vec<f32>,
+    integral: f32,
+}
+
+impl Distribution2D {
+    pub fn new(func: Vec<f32>, width: usize, height: usize) -> Self {
+        let mut conditional_cdfs = Vec::with_capacity(height);
+        let mut row_integrals = Vec::with_capacity(height);
+        for row in func.chunks(width) {
+            let (cdf, integral) = cumulative(row);
+            conditional_cdfs.push(cdf);
+            row_integrals.push(integral);
+        }
+        let (marginal_cdf, integral) = cumulative(&row_integrals);
+        Self {
+            width: width,
+            height: height,
+            func: func,
+            conditional_cdfs: conditional_cdfs,
+            marginal_cdf: marginal_cdf,
+            integral: integral,
+        }
+    }
+
+    // Point for the uniform numbers `u`, with its density
+    pub fn sample(&self, u: (f32, f32)) -> (f32, f32, f32) {
+        let row = find_interval(&self.marginal_cdf, u.1);
+        let dv = (u.1 - self.marginal_cdf[row]) / (self.marginal_cdf[row + 1] - self.marginal_cdf[row]);
+        let cdf = &self.conditional_cdfs[row];
+        let col = find_interval(cdf, u.0);
+        let du = (u.0 - cdf[col]) / (cdf[col + 1] - cdf[col]);
+
+        let x = (col as f32 + clamp(du, 0.0, 1.0)) / self.width as f32;
+        let y = (row as f32 + clamp(dv, 0.0, 1.0)) / self.height as f32;
+        return (x.min(1.0 - f32::EPSILON / 2.0), y.min(1.0 - f32::EPSILON / 2.0), self.pdf(x, y));
+    }
+    pub fn pdf(&self, x: f32, y: f32) -> f32 {
+        if self.integral <= 0.0 {
+            return 1.0;
+        }
+        let col = ((x * self.width as f32) as usize).min(self.width - 1);
+        let row = ((y * self.height as f32) as usize).min(self.height - 1);
+        self.func[row * self.width + col] / self.integral
+    }
+}
+
+
+// Axes of maps that are not turned
+fn world_axes() -> [Vec3; 3] {
+    [Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0)]
+}
+
+// Equirectangular image coordinates of the world space direction `v` in a map with the given axes,
+// and the sine of its angle to the map's pole
+fn equirect_uv(axes: &[Vec3; 3], v: Vec3) -> (f32, f32, f32) {
+    let d = Vec3::new(axes[0].dot(v), axes[1].dot(v), axes[2].dot(v)).normalize();
+    let theta = clamp(d.y, -1.0, 1.0).acos();
+    let phi = (-d.z).atan2(d.x) + PI;
+    (clamp(phi / TAU, 0.0, 1.0), clamp(theta / PI, 0.0, 1.0), theta.sin())
+}
+fn equirect_direction(axes: &[Vec3; 3], u: f32, v: f32) -> Vec3 {
+    let (sin_theta, cos_theta) = (PI * v).sin_cos();
+    let (sin_phi, cos_phi) = (TAU * u).sin_cos();
+    axes[0] * (-sin_theta * cos_phi) + axes[1] * cos_theta + axes[2] * (sin_theta * sin_phi)
+}
+
+// Perez et al. distribution of sky brightness over the angle from the zenith and the angle `gamma`
+// from the sun
+fn perez(coeffs: &[f32; 5], cos_theta: f32, gamma: f32, cos_gamma: f32) -> f32 {
+    (1.0 + coeffs[0] * (coeffs[1] / cos_theta).exp()) * (1.0 + coeffs[2] * (coeffs[3] * gamma).exp() + coeffs[4] * cos_gamma * cos_gamma)
+}
+
+// CIE xyY to linear sRGB
+fn xyy_to_rgb(x: f32, y: f32, lum: f32) -> Rgb {
+    if y <= 0.0 {
+        return Rgb::origin();
+    }
+    let cx = x / y * lum;
+    let cz = (1.0 - x - y) / y * lum;
+    Rgb::new(
+        3.2406 * cx - 1.5372 * lum - 0.4986 * cz,
+        -0.9689 * cx + 1.8758 * lum + 0.0415 * cz,
+        0.0557 * cx - 0.2040 * lum + 1.0570 * cz
+    )
+}
+
+// Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight" (1999). Radiance is in
+// kcd/m^2 and zero below the horizon. Turbidity runs from 2 for a clear sky to about 10 for haze.
+#[derive(Clone)]
+pub struct PreethamSky {
+    sun_direction: Vec3,
+    // Perez coefficients of the luminance and of the x and y chromaticities
+    coeffs: [[f32; 5]; 3],
+    // Luminance and chromaticities at the zenith, divided by their Perez value there
+    zenith: [f32; 3],
+}
+
+impl PreethamSky {
+    pub fn new(sun_direction: Vec3, turbidity: f32) -> Self {
+        let t = turbidity;
+        let coeffs = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+
+        // The fit only covers suns above the horizon
+        let theta_s = clamp(sun_direction.y, -1.0, 1.0).acos().min(PI / 2.0 - 1e-3);
+        let (t2, th2, th3) = (t * t, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_lum = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let zenith_x = t2 * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * theta_s)
+            + t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * theta_s + 0.00394)
+            + (0.11693 * th3 - 0.21196 * th2 + 0.06052 * theta_s + 0.25886);
+        let zenith_y = t2 * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * theta_s)
+            + t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * theta_s + 0.00516)
+            + (0.15346 * th3 - 0.26756 * th2 + 0.06670 * theta_s + 0.26688);
+
+        let mut zenith = [zenith_lum, zenith_x, zenith_y];
+        for k in 0..3 {
+            zenith[k] /= perez(&coeffs[k], 1.0, theta_s, theta_s.cos());
+        }
+        Self {
+            sun_direction: sun_direction,
+            coeffs: coeffs,
+            zenith: zenith,
+        }
+    }
+
+    pub fn radiance(&self, direction: Vec3) -> Rgb {
+        let d = direction.normalize();
+        if d.y <= 0.0 {
+            return Rgb::origin();
+        }
+        // Keep the brightening towards the horizon finite
+        let cos_theta = d.y.max(0.01);
+        let cos_gamma = clamp(d.dot(self.sun_direction), -1.0, 1.0);
+        let gamma = cos_gamma.acos();
+        let lum = self.zenith[0] * perez(&self.coeffs[0], cos_theta, gamma, cos_gamma);
+        let x = self.zenith[1] * perez(&self.coeffs[1], cos_theta, gamma, cos_gamma);
+        let y = self.zenith[2] * perez(&self.coeffs[2], cos_theta, gamma, cos_gamma);
+        xyy_to_rgb(x, y, lum)
+    }
+}
+
+// Radiance of the sun disk (kcd/m^2) after Rayleigh and aerosol extinction along its path through the
+// atmosphere, evaluated at a red, green and blue wavelength
+fn sun_radiance(sun_direction: Vec3, turbidity: f32) -> Rgb {
+    if sun_direction.y <= 0.0 {
+        return Rgb::origin();
+    }
+    // Relative air mass (Kasten and Young 1989)
+    let elevation = to_degrees(sun_direction.y.asin());
+    let air_mass = 1.0 / (sun_direction.y + 0.50572 * (elevation + 6.07995).powf(-1.6364));
+    // Angstrom turbidity coefficient
+    let beta = (0.04608 * turbidity - 0.04586).max(0.0);
+    let transmittance = |lambda: f32| -> f32 {
+        let tau_rayleigh = 0.008735 * lambda.powf(-4.08);
+        let tau_aerosol = beta * lambda.powf(-1.3);
+        (-(tau_rayleigh + tau_aerosol) * air_mass).exp()
+    };
+    // Luminance of the sun outside the atmosphere
+    Rgb::new(transmittance(0.68), transmittance(0.55), transmittance(0.44)) * 1.96e6
+}
+
+
+#[derive(Clone)]
+pub enum BackgroundType {
+    Constant(Rgb),
+    // Equirectangular image in the atlas with its size, an intensity scale, the map's axes in world
+    // space and the distribution of its luminance over the image
+    EnvironmentMap(usize, u32, u32, f32, [Vec3; 3], Arc<Distribution2D>),
+    // Preetham sky, radiance of the sun disk and the cosine of its angular radius, an intensity scale,
+    // the probability of sampling the sun and the distribution of the sky's luminance
+    Sky(PreethamSky, Rgb, f32, f32, f32, Arc<Distribution2D>),
+}
+
+// What rays leaving the scene see. Environment maps also light the scene and are importance sampled
+// by their luminance.
+#[derive(Clone)]
+pub struct Background {
+    pub background_type: BackgroundType,
+}
+
+impl Background {
+    pub fn constant(color: Rgb) -> Self {
+        Self {
+            background_type: BackgroundType::Constant(color),
+        }
+    }
+    // Loads the equirectangular image at `path` (HDR formats keep their full range) into the atlas,
+    // with the top of the image pointing up the y axis. `rotations` turn the map about the world axes,
+    // applied in order.
+    pub fn environment_map(atlas: &Arc<Mutex<ImageTextureAtlas>>, path: &str, intensity: f32, rotations: &[(Axis, f32)]) -> Result<Self, String> {
+        let mut axes = world_axes();
+        for (axis, angle) in rotations.iter() {
+            let (sin_theta, cos_theta) = to_radians(*angle).sin_cos();
+            let others = axis.others();
+            for v in axes.iter_mut() {
+                let original = *v;
+                v[others.0] = cos_theta * original[others.0] - sin_theta * original[others.1];
+                v[others.1] = sin_theta * original[others.0] + cos_theta * original[others.1];
+            }
+        }
+
+        let mut atlas = atlas.lock().unwrap();
+        let idx = atlas.load(path)?;
+        let (width, height) = atlas.dimensions(idx);
+        // Rows near the poles cover less solid angle
+        let mut func = Vec::with_capacity((width * height) as usize);
+        for j in 0..height {
+            let sin_theta = (PI * (j as f32 + 0.5) / height as f32).sin();
+            for i in 0..width {
+                let color = atlas.texel(idx, i, j);
+                func.push((0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z) * sin_theta);
+            }
+        }
+
+        Ok(Self {
+            background_type: BackgroundType::EnvironmentMap(idx, width, height, intensity, axes, Arc::new(Distribution2D::new(func, width as usize, height as usize))),
+        })
+    }
+
+    // Procedural daylight: a Preetham sky and a sun disk of angular diameter `sun_size` degrees at the
+    // given elevation above the horizon and azimuth (0 along +z, 90 along +x). Radiance is in kcd/m^2
+    // times `intensity`, so a midday sun lights white surfaces to around 30.
+    pub fn sky(elevation: f32, azimuth: f32, turbidity: f32, intensity: f32, sun_size: f32) -> Self {
+        let (sin_el, cos_el) = to_radians(elevation).sin_cos();
+        let (sin_az, cos_az) = to_radians(azimuth).sin_cos();
+        let sun_direction = Vec3::new(cos_el * sin_az, sin_el, cos_el * cos_az);
+        let model = PreethamSky::new(sun_direction, turbidity);
+        let sun = sun_radiance(sun_direction, turbidity);
+        let cos_sun_radius = to_radians(sun_size / 2.0).cos();
+
+        // The sky is sampled from a table like an environment map, the sun uniformly over its disk
+        let (width, height) = (128, 64);
+        let mut func = Vec::with_capacity(width * height);
+        let mut sky_power = 0.0;
+        for j in 0..height {
+            for i in 0..width {
+                let v = (j as f32 + 0.5) / height as f32;
+                let color = model.radiance(equirect_direction(&world_axes(), (i as f32 + 0.5) / width as f32, v));
+                let value = (0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z).max(0.0) * (PI * v).sin();
+                sky_power += value * (TAU / width as f32) * (PI / height as f32);
+                func.push(value);
+            }
+        }
+        let sun_power = (0.2126 * sun.x + 0.7152 * sun.y + 0.0722 * sun.z) * TAU * (1.0 - cos_sun_radius);
+        let sun_probability = if sun_power > 0.0 { clamp(sun_power / (sun_power + sky_power), 0.1, 0.9) } else { 0.0 };
+
+        Self {
+            background_type: BackgroundType::Sky(model, sun, cos_sun_radius, intensity, sun_probability, Arc::new(Distribution2D::new(func, width, height))),
+        }
+    }
+
+    // Whether the background emits light worth sampling directly
+    pub fn is_light(&self) -> bool {
+        matches!(&self.background_type, BackgroundType::EnvironmentMap(..) | BackgroundType::Sky(..))
+    }
+
+    fn radiance_environment_map(&self, idx: usize, width: u32, height: u32, intensity: f32, axes: &[Vec3; 3], direction: Vec3, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Rgb {
+        let (u, v, _sin_theta) = equirect_uv(axes, direction);
+        let i = ((u * width as f32) as u32).min(width - 1);
+        let j = ((v * height as f32) as u32).min(height - 1);
+        atlas.lock().unwrap().texel(idx, i, j) * intensity
+    }
+    fn pdf_value_environment_map(&self, axes: &[Vec3; 3], distribution: &Distribution2D, direction: Vec3) -> f32 {
+        let (u, v, sin_theta) = equirect_uv(axes, direction);
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        // The map covers 2 pi by pi radians of longitude and colatitude
+        distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+    fn random_environment_map(&self, axes: &[Vec3; 3], distribution: &Distribution2D, sampler: &mut Sampler) -> Vec3 {
+        let (u, v, _pdf) = distribution.sample(sampler.get_2d());
+        equirect_direction(axes, u, v)
+    }
+
+    fn radiance_sky(&self, model: &PreethamSky, sun: Rgb, cos_sun_radius: f32, intensity: f32, direction: Vec3) -> Rgb {
+        let mut radiance = model.radiance(direction);
+        if direction.normalize().dot(model.sun_direction) >= cos_sun_radius {
+            radiance = radiance + sun;
+        }
+        radiance * intensity
+    }
+    fn pdf_value_sky(&self, model: &PreethamSky, cos_sun_radius: f32, sun_probability: f32, distribution: &Distribution2D, direction: Vec3) -> f32 {
+        let mut pdf = (1.0 - sun_probability) * self.pdf_value_environment_map(&world_axes(), distribution, direction);
+        if direction.normalize().dot(model.sun_direction) >= cos_sun_radius {
+            pdf += sun_probability / (TAU * (1.0 - cos_sun_radius));
+        }
+        pdf
+    }
+    fn random_sky(&self, model: &PreethamSky, cos_sun_radius: f32, sun_probability: f32, distribution: &Distribution2D, sampler: &mut Sampler) -> Vec3 {
+        if sampler.get_1d() < sun_probability {
+            let (u, v) = sampler.get_2d();
+            let z = 1.0 + u * (cos_sun_radius - 1.0);
+            let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+            let phi = TAU * v;
+            return ONB::build_from_w(model.sun_direction).local(Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z));
+        }
+        self.random_environment_map(&world_axes(), distribution, sampler)
+    }
+
+    // Radiance arriving along `-direction` from infinitely far away
+    pub fn radiance(&self, direction: Vec3, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Rgb {
+        match &self.background_type {
+            BackgroundType::Constant(color) => *color,
+            BackgroundType::EnvironmentMap(idx, width, height, intensity, axes, _distribution) => self.radiance_environment_map(*idx, *width, *height, *intensity, axes, direction, atlas),
+            BackgroundType::Sky(model, sun, cos_sun_radius, intensity, _sun_probability, _distribution) => self.radiance_sky(model, *sun, *cos_sun_radius, *intensity, direction),
+        }
+    }
+    pub fn pdf_value(&self, direction: Vec3) -> f32 {
+        match &self.background_type {
+            BackgroundType::EnvironmentMap(_idx, _width, _height, _intensity, axes, distribution) => self.pdf_value_environment_map(axes, distribution, direction),
+            BackgroundType::Sky(model, _sun, cos_sun_radius, _intensity, sun_probability, distribution) => self.pdf_value_sky(model, *cos_sun_radius, *sun_probability, distribution, direction),
+            _ => 0.0
+        }
+    }
+    pub fn random(&self, sampler: &mut Sampler) -> Vec3 {
+        match &self.background_type {
+            BackgroundType::EnvironmentMap(_idx, _width, _height, _intensity, axes, distribution) => self.random_environment_map(axes, distribution, sampler),
+            BackgroundType::Sky(model, _sun, cos_sun_radius, _intensity, sun_probability, distribution) => self.random_sky(model, *cos_sun_radius, *sun_probability, distribution, sampler),
+            _ => Vec3::new(0.0, 1.0, 0.0)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cumulative_sums_are_normalized() {
+        let (cdf, integral) = cumulative(&[1.0, 3.0]);
+        assert_eq!(cdf, [0.0, 0.25, 1.0]);
+        assert_eq!(integral, 2.0);
+        let (cdf, integral) = cumulative(&[0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(cdf, [0.0, 0.25, 0.5, 0.75, 1.0]);
+        assert_eq!(integral, 0.0);
+    }
+
+    #[test]
+    fn samples_carry_the_density_of_their_cell() {
+        let func = vec![1.0, 0.0, 2.0, 4.0, 0.5, 3.0];
+        let distribution = Distribution2D::new(func.clone(), 3, 2);
+        let integral = func.iter().sum::<f32>() / 6.0;
+        for i in 0..16 {
+            for j in 0..16 {
+                let u = ((i as f32 + 0.5) / 16.0, (j as f32 + 0.5) / 16.0);
+                let (x, y, pdf) = distribution.sample(u);
+                assert!((0.0..1.0).contains(&x) && (0.0..1.0).contains(&y));
+                assert_eq!(pdf, distribution.pdf(x, y));
+                let cell = func[(y * 2.0) as usize * 3 + (x * 3.0) as usize];
+                assert!(cell > 0.0, "sampled an empty cell at ({}, {})", x, y);
+                assert!((pdf - cell / integral).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn density_integrates_to_one() {
+        let func = vec![0.0, 1.0, 5.0, 2.0, 0.0, 0.0, 7.0, 1.0];
+        let distribution = Distribution2D::new(func, 4, 2);
+        let total: f32 = (0..8).map(|i| distribution.pdf((i % 4) as f32 / 4.0 + 0.1, (i / 4) as f32 / 2.0 + 0.1) / 8.0).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn sample_frequencies_follow_the_density() {
+        let distribution = Distribution2D::new(vec![1.0, 3.0], 2, 1);
+        let n = 1000;
+        let right = (0..n).filter(|i| distribution.sample(((*i as f32 + 0.5) / n as f32, 0.5)).0 >= 0.5).count();
+        assert_eq!(right, 750);
+    }
+
+    #[test]
+    fn zero_functions_sample_uniformly() {
+        let distribution = Distribution2D::new(vec![0.0; 4], 2, 2);
+        let (x, y, pdf) = distribution.sample((0.3, 0.8));
+        assert!((x - 0.3).abs() < 1e-6 && (y - 0.8).abs() < 1e-6);
+        assert_eq!(pdf, 1.0);
+    }
+
+    #[test]
+    fn equirect_directions_round_trip() {
+        let axes = world_axes();
+        for (u, v) in [(0.1, 0.2), (0.5, 0.5), (0.9, 0.7), (0.3, 0.95)] {
+            let (u2, v2, sin_theta) = equirect_uv(&axes, equirect_direction(&axes, u, v));
+            assert!((u - u2).abs() < 1e-4 && (v - v2).abs() < 1e-4, "({}, {}) came back as ({}, {})", u, v, u2, v2);
+            assert!((sin_theta - (PI * v).sin()).abs() < 1e-4);
+        }
+    }
+}
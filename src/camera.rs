@@ -73,13 +73,25 @@ impl Camera {
             lower_left: origin - horizontal / 2.0 - vertical / 2.0 - w * focus_dist,
         }
     }
-    pub fn get_ray(&self, u: f32, v: f32) -> Ray {
-        let rd = random_in_unit_disk() * self.lens_radius;
+    pub fn with_aspect_ratio(&self, aspect_ratio: f32) -> Self {
+        Self::new(
+            self.look_from,
+            self.look_at,
+            self.vup,
+            to_degrees(self.theta),
+            self.aperture,
+            self.focus_dist,
+            aspect_ratio,
+            self.shutter_open, self.shutter_close
+        )
+    }
+    pub fn get_ray(&self, u: f32, v: f32, sampler: &mut Sampler) -> Ray {
+        let rd = random_in_unit_disk(sampler) * self.lens_radius;
         let offset = self.u * rd.x + self.v * rd.y;
         Ray::new(
             self.origin + offset, 
             self.lower_left + self.horizontal * u + self.vertical * v - self.origin - offset,
-            randrange(self.shutter_open, self.shutter_close)
+            self.shutter_open + (self.shutter_close - self.shutter_open) * sampler.get_1d()
         )
     }
 }
\ No newline at end of file
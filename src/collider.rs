@@ -1,7 +1,5 @@
-#[path = "aabb.rs"] mod aabb;
-pub use aabb::*;
-use std::fs::File;
-use std::io::{BufReader, BufRead};
+#[path = "mesh.rs"] mod mesh;
+pub use mesh::*;
 use std::sync::{Arc, Mutex};
 use std::cmp::Ordering;
 
@@ -19,6 +17,42 @@ fn box_compare(a: &Geometry, b: &Geometry, axis: usize) -> Ordering {
     }
 }
 
+// Probabilities of picking each of `colliders` when sampling them as lights: proportional to their
+// emitted power, or to their area when none of them is known to emit
+fn light_selection(colliders: &Vec<Geometry>) -> Vec<f32> {
+    let mut weights: Vec<f32> = colliders.iter().map(|c| c.power()).collect();
+    if weights.iter().sum::<f32>() <= 0.0 {
+        weights = colliders.iter().map(|c| c.area()).collect();
+    }
+    let total: f32 = weights.iter().sum();
+    if total <= 0.0 {
+        return vec![1.0 / colliders.len() as f32; colliders.len()];
+    }
+    weights.iter().map(|w| w / total).collect()
+}
+
+fn pick_index(probabilities: &[f32], u: f32) -> usize {
+    let mut cumulative = 0.0;
+    for (i, p) in probabilities.iter().enumerate() {
+        cumulative += p;
+        if u < cumulative {
+            return i;
+        }
+    }
+    // Rounding can leave the total a little below one
+    probabilities.iter().rposition(|p| *p > 0.0).unwrap_or(0)
+}
+
+// Rotates `v` by the angle with the given sine and cosine about `axis`, the way rotation instances
+// move rays into object space
+fn rotate_about(v: Vec3, axis: &Axis, sin_theta: f32, cos_theta: f32) -> Vec3 {
+    let others = axis.others();
+    let mut rotated = v;
+    rotated[others.0] = cos_theta * v[others.0] - sin_theta * v[others.1];
+    rotated[others.1] = sin_theta * v[others.0] + cos_theta * v[others.1];
+    rotated
+}
+
 fn box_x_compare(a: &Geometry, b: &Geometry) -> Ordering {
     box_compare(a, b, 0)
 }
@@ -41,9 +75,14 @@ pub enum GeometryType {
     ConstantMedium(Box<Geometry>, f32),
     TranslateInstance(Box<Geometry>, Vec3),
     YRotationInstance(Box<Geometry>, Axis, f32, f32, f32, bool, AABB),
-    BVHNode(Box<Geometry>, Box<Geometry>, Option<AABB>, usize),
-    ColliderList(Vec<Geometry>),
+    // Children, bounds, split axis, the total area of the triangles or shapes below and whether the
+    // node is over a single object, which it then holds on both sides
+    BVHNode(Box<Geometry>, Box<Geometry>, Option<AABB>, usize, f32, bool),
+    // Members and the probabilities of picking each of them for light sampling
+    ColliderList(Vec<Geometry>, Vec<f32>),
     Triangle(Point3, Point3, Point3, Vec3),
+    // Shared so that instances of a mesh don't copy its buffers
+    Mesh(Arc<MeshData>),
 }
 
 #[derive(Clone)]
@@ -171,8 +210,21 @@ impl Geometry {
         }
     }
     pub fn bvh_node(colliders: &Vec<Geometry>, time_0: f32, time_1: f32, start: usize, end: usize) -> Self {
-        let axis = randuint(0, 2);
-        let comparator = if axis == 0 { box_x_compare } else if axis == 2 { box_y_compare } else { box_z_compare };
+        // Split along the axis over which the minima of the children's boxes, which they are sorted by,
+        // are spread the widest
+        let mut lo = Vec3::new(f32::MAX, f32::MAX, f32::MAX);
+        let mut hi = Vec3::new(f32::MIN, f32::MIN, f32::MIN);
+        for collider in colliders[start..end].iter() {
+            if let Some(b) = collider.bounding_box(time_0, time_1) {
+                for c in 0..3 {
+                    lo[c] = lo[c].min(b.minimum[c]);
+                    hi[c] = hi[c].max(b.minimum[c]);
+                }
+            }
+        }
+        let extent = hi - lo;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        let comparator = if axis == 0 { box_x_compare } else if axis == 1 { box_y_compare } else { box_z_compare };
         let obj_span = end - start;
         let mut temp_colliders = colliders.clone();
         let left: Geometry;
@@ -197,6 +249,7 @@ impl Geometry {
             right = Geometry::bvh_node(temp_colliders.clone().as_ref(), time_0, time_1, mid, end);
         }
 
+        let area = if obj_span == 1 { left.area() } else { left.area() + right.area() };
         let box_left = left.bounding_box(time_0, time_1);
         let box_right = right.bounding_box(time_0, time_1);
         let bounding_box: Option<AABB> = match (box_left, box_right) {
@@ -207,12 +260,12 @@ impl Geometry {
         };
         Self {
             material: left.material.clone(),
-            geometry_type: GeometryType::BVHNode(Box::new(left), Box::new(right), bounding_box, axis),
+            geometry_type: GeometryType::BVHNode(Box::new(left), Box::new(right), bounding_box, axis, area, obj_span == 1),
         }
     }
     pub fn collider_list(colliders: Vec<Geometry>) -> Self {
         Self {
-            geometry_type: GeometryType::ColliderList(colliders),
+            geometry_type: GeometryType::ColliderList(colliders.clone(), light_selection(&colliders)),
             material: Material::lambertian(Texture::solid_color(Rgb::origin()))
         }
     }
@@ -224,46 +277,14 @@ impl Geometry {
             material: material,
         }
     }
-    pub fn load_obj(path: &str, scale: f32, material: Material) -> Self {
-        let mut vertices = Vec::new();
-        let mut triangles: Vec<(usize, usize, usize)> = Vec::new();
-
-        let file = File::open(path).unwrap();
-        for line in BufReader::new(file).lines() {
-            let res_line = line.unwrap();
-            let mut splitted = res_line.split_whitespace();
-            
-            let action_option = splitted.next();
-            let action;
-            match action_option {
-                Some(val) => action = val,
-                None => continue
-            }
-
-            if action.starts_with("vt"){
-            }
-            else if action.starts_with("v") {
-                vertices.push(Vec3::new(splitted.next().unwrap().parse::<f32>().unwrap() * scale, splitted.next().unwrap().parse::<f32>().unwrap() * scale, splitted.next().unwrap().parse::<f32>().unwrap() * scale))
-            }
-            else if action.starts_with("f") {
-                if splitted.clone().next().unwrap().split("/").count() == 1 {
-                    triangles.push((splitted.next().unwrap().parse().unwrap(), splitted.next().unwrap().parse().unwrap(), splitted.next().unwrap().parse().unwrap()))
-                }
-                else {
-                    let mut p1 = splitted.next().unwrap().split("/");
-                    let mut p2 = splitted.next().unwrap().split("/");
-                    let mut p3 = splitted.next().unwrap().split("/");
-                    triangles.push((p1.nth(0).unwrap().parse().unwrap(), p2.nth(0).unwrap().parse().unwrap(), p3.nth(0).unwrap().parse().unwrap()))
-                }
-            }
-        };
-
-        let mut tris = Vec::new();
-        for t in triangles.iter() {
-            tris.push(Geometry::triangle(vertices[t.0 - 1], vertices[t.1 - 1], vertices[t.2 - 1], material.clone()));
-        };
-
-        Geometry::bvh_node(&tris, 0., 1., 0, tris.len())
+    // The triangles of a mesh carry their own materials; the geometry's is only a stand-in, taken from
+    // the first of them, which `MeshData::new` makes sure exists
+    pub fn mesh(data: MeshData) -> Self {
+        let material = data.materials[0].clone();
+        Self {
+            geometry_type: GeometryType::Mesh(Arc::new(data)),
+            material: material,
+        }
     }
 
     fn moving_sphere_center(&self, center0: Point3, center1: Point3, time0: f32, time1: f32, time: f32) -> Point3 {
@@ -357,6 +378,11 @@ impl Geometry {
         rec.material = self.material.clone();
         return Some(rec);
     }
+    fn random_xyrect(&self, x0: f32, x1: f32, y0: f32, y1: f32, k: f32, origin: Point3, sampler: &mut Sampler) -> Vec3 {
+        let (u, v) = sampler.get_2d();
+        let rand_point = Point3::new(x0 + (x1 - x0) * u, y0 + (y1 - y0) * v, k);
+        rand_point - origin
+    }
     fn bounding_box_xyrect(&self, x0: f32, x1: f32, y0: f32, y1: f32, k: f32, _time_0: f32, _time_1: f32) -> Option<AABB> {
         Some(AABB::new(
             Point3::new(x0, y0, k - 0.0001),
@@ -386,19 +412,9 @@ impl Geometry {
             Point3::new(x1, k + 0.0001, z1)
         ))
     }
-    fn pdf_value_xzrect(&self, x0: f32, x1: f32, z0: f32, z1: f32, k: f32, origin: Point3, v: Vec3) -> f32 {
-        match self.intersect_xzrect(x0, x1, z0, z1, k, Ray::new(origin, v, 0.0), 0.001, f32::MAX) {
-            Some(rec) => {
-                let area = (x1 - x0) * (z1 - z0);
-                let dist_sqrd = rec.t * rec.t * v.length_squared();
-                let cosine = (v.dot(rec.normal) / v.length()).abs();
-                return dist_sqrd / (cosine * area);
-            },
-            None => 0.0
-        }
-    }
-    fn random_xzrect(&self, x0: f32, x1: f32, z0: f32, z1: f32, k: f32, origin: Point3) -> Vec3 {
-        let rand_point = Point3::new(randrange(x0, x1), k, randrange(z0, z1));
+    fn random_xzrect(&self, x0: f32, x1: f32, z0: f32, z1: f32, k: f32, origin: Point3, sampler: &mut Sampler) -> Vec3 {
+        let (u, v) = sampler.get_2d();
+        let rand_point = Point3::new(x0 + (x1 - x0) * u, k, z0 + (z1 - z0) * v);
         rand_point - origin
     }
 
@@ -418,6 +434,11 @@ impl Geometry {
         rec.material = self.material.clone();
         return Some(rec);
     }
+    fn random_yzrect(&self, z0: f32, z1: f32, y0: f32, y1: f32, k: f32, origin: Point3, sampler: &mut Sampler) -> Vec3 {
+        let (u, v) = sampler.get_2d();
+        let rand_point = Point3::new(k, y0 + (y1 - y0) * u, z0 + (z1 - z0) * v);
+        rand_point - origin
+    }
     fn bounding_box_yzrect(&self, y0: f32, y1: f32, z0: f32, z1: f32, k: f32, _time_0: f32, _time_1: f32) -> Option<AABB> {
         Some(AABB::new(
             Point3::new(k - 0.0001, y0, z0),
@@ -460,7 +481,14 @@ impl Geometry {
 
         let ray_length = r.direction.length();
         let dist_inside_bound = (rec2.t - rec1.t) * ray_length;
-        let hit_dist = neg_inv_density * random().log10();
+        // Intersection has no sampler to draw from, so the free path is derived from the ray itself.
+        // Every ray is distinct, which keeps the result deterministic without correlating samples.
+        let mut rng = Pcg32::from_hash(&[
+            r.origin.x.to_bits(), r.origin.y.to_bits(), r.origin.z.to_bits(),
+            r.direction.x.to_bits(), r.direction.y.to_bits(), r.direction.z.to_bits(),
+            r.time.to_bits()
+        ]);
+        let hit_dist = neg_inv_density * (1.0 - rng.random()).log10();
 
         if hit_dist > dist_inside_bound {
             return None;
@@ -553,7 +581,7 @@ impl Geometry {
                     }
                 }
                 None => {
-                    let right_hit = left.intersect(r, t_min, t_max);
+                    let right_hit = right.intersect(r, t_min, t_max);
                     match right_hit {
                         Some(right_hit) => Some(right_hit),
                         None => None,
@@ -624,44 +652,12 @@ impl Geometry {
         return out_box;
     }
 
-    fn intersect_triangle(&self, p0: Point3, p1: Point3, p2: Point3, plane_normal: Vec3, r: Ray, _t_min: f32, _t_max: f32) -> Option<HitRecord> {
-        let n_dot_dir = plane_normal.dot(r.direction);
-        if n_dot_dir.abs() < 0.000001 {
-            return None;
-        }
-
-        let d = -plane_normal.dot(p0);
-        let dist = -(plane_normal.dot(r.origin) + d) / n_dot_dir;
-
-        if dist < 0.0 {
-            return None;
-        }
-
-        let hit_pos = r.origin + r.direction * dist;
-
-        let edge0 = p1 - p0;
-        let vp0 = hit_pos - p0;
-        let plane_perp = edge0.cross(vp0);
-        if plane_normal.dot(plane_perp) < 0.0 {
-            return None;
-        }
-        
-        let edge1 = p2 - p1;
-        let vp1 = hit_pos - p1;
-        let plane_perp = edge1.cross(vp1);
-        if plane_normal.dot(plane_perp) < 0.0 {
-            return None;
-        }
-        
-        let edge2 = p0 - p2;
-        let vp2 = hit_pos - p2;
-        let plane_perp = edge2.cross(vp2);
-        if plane_normal.dot(plane_perp) < 0.0 {
-            return None;
-        }
-
-        let mut rec = HitRecord::new(hit_pos, dist);
+    fn intersect_triangle(&self, p0: Point3, p1: Point3, p2: Point3, plane_normal: Vec3, r: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let (dist, b1, b2) = hit_triangle(p0, p1, p2, r, t_min, t_max)?;
+        let mut rec = HitRecord::new(r.at(dist), dist);
         rec.set_face_normal(r, plane_normal);
+        rec.barycentric = (b1, b2);
+        rec.set_uv((b1, b2));
         rec.material = self.material.clone();
         return Some(rec);
     }
@@ -676,7 +672,9 @@ impl Geometry {
             p0.y.min(p1.y.min(p2.y)),
             p0.z.min(p1.z.min(p2.z)),
         );
-        return Some(AABB::new(minp, maxp));
+        // Padded like the rectangles, so triangles lying in an axis plane don't get a flat box
+        let pad = Vec3::new(0.0001, 0.0001, 0.0001);
+        return Some(AABB::new(minp - pad, maxp + pad));
     }
 
     pub fn intersect(&self, r: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
@@ -690,9 +688,10 @@ impl Geometry {
             GeometryType::ConstantMedium(boundary, neg_inv_density) => self.intersect_constant_medium(boundary, *neg_inv_density, r, t_min, t_max),
             GeometryType::TranslateInstance(geometry, displacement) => self.intersect_translate_instance(geometry, displacement, r, t_min, t_max),
             GeometryType::YRotationInstance(geometry, axis, angle, sin_theta, cos_theta, has_box, aabb) => self.intersect_rot_instance(geometry, axis, *sin_theta, *cos_theta, r, t_min, t_max),
-            GeometryType::BVHNode(left, right, bounding_box, axis) => self.intersect_bvh(left, right, bounding_box, *axis, r, t_min, t_max),
-            GeometryType::ColliderList(colliders) => self.intersect_collider_list(colliders, r, t_min, t_max),
-            GeometryType::Triangle(p0, p1, p2, plane_normal) => self.intersect_triangle(*p0, *p1, *p2, *plane_normal, r, t_min, t_max)
+            GeometryType::BVHNode(left, right, bounding_box, axis, _area, _single) => self.intersect_bvh(left, right, bounding_box, *axis, r, t_min, t_max),
+            GeometryType::ColliderList(colliders, _probabilities) => self.intersect_collider_list(colliders, r, t_min, t_max),
+            GeometryType::Triangle(p0, p1, p2, plane_normal) => self.intersect_triangle(*p0, *p1, *p2, *plane_normal, r, t_min, t_max),
+            GeometryType::Mesh(data) => data.intersect(r, t_min, t_max),
         }
     }
     pub fn bounding_box(&self, time_0: f32, time_1: f32) -> Option<AABB> {
@@ -706,20 +705,240 @@ impl Geometry {
             GeometryType::ConstantMedium(boundary, _neg_inv_density) => self.bounding_box_constant_medium(boundary, time_0, time_1),
             GeometryType::TranslateInstance(geometry, displacement) => self.bounding_box_translate_instance(geometry, displacement, time_0, time_1),
             GeometryType::YRotationInstance(_geometry, _axis, _angle, _sin_theta, _cos_theta, has_box, aabb) => self.bounding_box_rot_instance(*has_box, aabb, time_0, time_1),
-            GeometryType::BVHNode(_left, _right, bounding_box, _axis) => self.bounding_box_bvh(bounding_box, time_0, time_1),
-            GeometryType::ColliderList(colliders) => self.bounding_box_collider_list(colliders, time_0, time_1),
+            GeometryType::BVHNode(_left, _right, bounding_box, _axis, _area, _single) => self.bounding_box_bvh(bounding_box, time_0, time_1),
+            GeometryType::ColliderList(colliders, _probabilities) => self.bounding_box_collider_list(colliders, time_0, time_1),
             GeometryType::Triangle(p0, p1, p2, _plane_normal) => self.bounding_box_triangle(p0, p1, p2, time_0, time_1),
+            GeometryType::Mesh(data) => data.bounds(),
+        }
+    }
+    // Surface area light samples can land on. Moving spheres and media can't be sampled and have none.
+    pub fn area(&self) -> f32 {
+        match &self.geometry_type {
+            GeometryType::Sphere(_center, radius) => 4.0 * PI * radius * radius,
+            GeometryType::XYRect(a0, a1, b0, b1, _k) | GeometryType::XZRect(a0, a1, b0, b1, _k) | GeometryType::YZRect(a0, a1, b0, b1, _k) => (a1 - a0) * (b1 - b0),
+            GeometryType::Cuboid(_p0, _p1, sides) => sides.objects.iter().map(|side| side.area()).sum(),
+            GeometryType::TranslateInstance(geometry, _displacement) => geometry.area(),
+            GeometryType::YRotationInstance(geometry, ..) => geometry.area(),
+            GeometryType::BVHNode(_left, _right, _bounding_box, _axis, area, _single) => *area,
+            GeometryType::ColliderList(colliders, _probabilities) => colliders.iter().map(|c| c.area()).sum(),
+            GeometryType::Triangle(p0, p1, p2, _plane_normal) => 0.5 * (*p1 - *p0).cross(*p2 - *p0).length(),
+            GeometryType::Mesh(data) => data.area(),
+            _ => 0.0
+        }
+    }
+    // Gives emissive materials at or below this geometry that were set by power the intensity that
+    // spreads it over `area`
+    pub fn resolve_emission_power(&mut self, area: f32) {
+        self.material.resolve_emission_power(area);
+        match &mut self.geometry_type {
+            GeometryType::Cuboid(_p0, _p1, sides) => sides.objects.iter_mut().for_each(|side| side.resolve_emission_power(area)),
+            GeometryType::ConstantMedium(geometry, ..) | GeometryType::TranslateInstance(geometry, ..) | GeometryType::YRotationInstance(geometry, ..) => geometry.resolve_emission_power(area),
+            GeometryType::BVHNode(left, right, ..) => {
+                left.resolve_emission_power(area);
+                right.resolve_emission_power(area);
+            },
+            GeometryType::ColliderList(colliders, _probabilities) => colliders.iter_mut().for_each(|c| c.resolve_emission_power(area)),
+            GeometryType::Mesh(data) => Arc::make_mut(data).materials.iter_mut().for_each(|m| m.resolve_emission_power(area)),
+            _ => ()
         }
     }
+    // Rough emitted power, used to pick between lights
+    pub fn power(&self) -> f32 {
+        match &self.geometry_type {
+            GeometryType::ColliderList(colliders, _probabilities) => colliders.iter().map(|c| c.power()).sum(),
+            GeometryType::Mesh(data) => (0..data.triangle_count()).map(|i| data.triangle_area(i) * data.material(i).emitted_luminance()).sum(),
+            _ => self.area() * self.material.emitted_luminance()
+        }
+    }
+
+    // Emissive shapes at or below this one, each wrapped in the instances between here and it, and
+    // whether all of this geometry emits, in which case it is returned as a single light instead
+    fn gather_lights(&self) -> (Vec<Geometry>, bool) {
+        match &self.geometry_type {
+            GeometryType::Sphere(..) | GeometryType::XYRect(..) | GeometryType::XZRect(..) | GeometryType::YZRect(..) | GeometryType::Cuboid(..) | GeometryType::Triangle(..) => {
+                if self.material.is_emissive() && self.area() > 0.0 {
+                    return (vec![self.clone()], true);
+                }
+                (Vec::new(), false)
+            },
+            GeometryType::TranslateInstance(geometry, displacement) => {
+                let (lights, whole) = geometry.gather_lights();
+                if whole {
+                    return (vec![self.clone()], true);
+                }
+                (lights.into_iter().map(|light| Geometry::instance_translation(light, *displacement)).collect(), false)
+            },
+            GeometryType::YRotationInstance(geometry, axis, angle, ..) => {
+                let (lights, whole) = geometry.gather_lights();
+                if whole {
+                    return (vec![self.clone()], true);
+                }
+                (lights.into_iter().map(|light| Geometry::instance_rotation(light, *axis, *angle)).collect(), false)
+            },
+            GeometryType::BVHNode(left, right, _bounding_box, _axis, _area, single) => {
+                let (mut lights, left_whole) = left.gather_lights();
+                if *single {
+                    return if left_whole { (vec![self.clone()], true) } else { (lights, false) };
+                }
+                let (right_lights, right_whole) = right.gather_lights();
+                if left_whole && right_whole {
+                    return (vec![self.clone()], true);
+                }
+                lights.extend(right_lights);
+                (lights, false)
+            },
+            // Meshes that only partly emit hand out copies of their emissive triangles
+            GeometryType::Mesh(data) => {
+                if data.materials.iter().all(|m| m.is_emissive()) && data.area() > 0.0 {
+                    return (vec![self.clone()], true);
+                }
+                let lights = (0..data.triangle_count()).filter(|&i| data.material(i).is_emissive()).map(|i| {
+                    let (p0, p1, p2) = data.corners(i);
+                    Geometry::triangle(p0, p1, p2, data.material(i).clone())
+                }).collect();
+                (lights, false)
+            },
+            GeometryType::ColliderList(colliders, _probabilities) => {
+                let mut lights = Vec::new();
+                let mut whole = !colliders.is_empty();
+                for collider in colliders.iter() {
+                    let (found, collider_whole) = collider.gather_lights();
+                    lights.extend(found);
+                    whole &= collider_whole;
+                }
+                if whole {
+                    return (vec![self.clone()], true);
+                }
+                (lights, false)
+            },
+            _ => (Vec::new(), false)
+        }
+    }
+
+    // Solid angle density of sampling points uniformly over `area` of this shape, seen from `origin`
+    // along `v`
+    fn pdf_value_area(&self, area: f32, origin: Point3, v: Vec3) -> f32 {
+        match self.intersect(Ray::new(origin, v, 0.0), 0.001, f32::MAX) {
+            Some(rec) => {
+                let dist_sqrd = rec.t * rec.t * v.length_squared();
+                let cosine = (v.dot(rec.normal) / v.length()).abs();
+                return dist_sqrd / (cosine * area);
+            },
+            None => 0.0
+        }
+    }
+
+    // Spheres are sampled uniformly over the cone of directions they subtend (or over their surface
+    // from the inside)
+    fn pdf_value_sphere(&self, center: &Point3, radius: f32, origin: Point3, v: Vec3) -> f32 {
+        let dist_sqrd = (*center - origin).length_squared();
+        if dist_sqrd <= radius * radius {
+            return self.pdf_value_area(self.area(), origin, v);
+        }
+        if self.intersect_sphere(center, radius, Ray::new(origin, v, 0.0), 0.001, f32::MAX).is_none() {
+            return 0.0;
+        }
+        let cos_theta_max = (1.0 - radius * radius / dist_sqrd).sqrt();
+        1.0 / (TAU * (1.0 - cos_theta_max))
+    }
+    fn random_sphere(&self, center: &Point3, radius: f32, origin: Point3, sampler: &mut Sampler) -> Vec3 {
+        let direction = *center - origin;
+        let dist_sqrd = direction.length_squared();
+        let (u, v) = sampler.get_2d();
+        let phi = TAU * v;
+        if dist_sqrd <= radius * radius {
+            let z = 1.0 - 2.0 * u;
+            let r = (1.0 - z * z).max(0.0).sqrt();
+            return *center + Vec3::new(r * phi.cos(), r * phi.sin(), z) * radius - origin;
+        }
+        let cos_theta_max = (1.0 - radius * radius / dist_sqrd).sqrt();
+        let z = 1.0 + u * (cos_theta_max - 1.0);
+        let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+        ONB::build_from_w(direction).local(Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z))
+    }
+
+    fn random_triangle(&self, p0: Point3, p1: Point3, p2: Point3, origin: Point3, sampler: &mut Sampler) -> Vec3 {
+        let (u, v) = sampler.get_2d();
+        let su = u.sqrt();
+        let rand_point = p0 * (1.0 - su) + p1 * (v * su) + p2 * ((1.0 - v) * su);
+        rand_point - origin
+    }
+
+    fn pdf_value_cuboid(&self, sides: &SceneColliders, origin: Point3, v: Vec3) -> f32 {
+        let total = self.area();
+        sides.objects.iter().map(|side| side.area() / total * side.pdf_value(origin, v)).sum()
+    }
+    fn random_cuboid(&self, sides: &SceneColliders, origin: Point3, sampler: &mut Sampler) -> Vec3 {
+        let total = self.area();
+        let probabilities: Vec<f32> = sides.objects.iter().map(|side| side.area() / total).collect();
+        sides.objects[pick_index(&probabilities, sampler.random())].random(origin, sampler)
+    }
+
+    fn pdf_value_rot_instance(&self, geometry: &Box<Geometry>, axis: &Axis, sin_theta: f32, cos_theta: f32, origin: Point3, v: Vec3) -> f32 {
+        geometry.pdf_value(rotate_about(origin, axis, sin_theta, cos_theta), rotate_about(v, axis, sin_theta, cos_theta))
+    }
+    fn random_rot_instance(&self, geometry: &Box<Geometry>, axis: &Axis, sin_theta: f32, cos_theta: f32, origin: Point3, sampler: &mut Sampler) -> Vec3 {
+        let dir = geometry.random(rotate_about(origin, axis, sin_theta, cos_theta), sampler);
+        rotate_about(dir, axis, -sin_theta, cos_theta)
+    }
+
+    // Members of a BVH, such as the triangles of a mesh, are sampled by area
+    fn pdf_value_bvh(&self, left: &Box<Geometry>, right: &Box<Geometry>, bounding_box: &Option<AABB>, origin: Point3, v: Vec3) -> f32 {
+        if let Some(bounding_box) = bounding_box {
+            if !bounding_box.intersect(Ray::new(origin, v, 0.0), 0.001, f32::MAX) {
+                return 0.0;
+            }
+        }
+        let (area_left, area_right) = (left.area(), right.area());
+        if area_left + area_right <= 0.0 {
+            return 0.0;
+        }
+        (area_left * left.pdf_value(origin, v) + area_right * right.pdf_value(origin, v)) / (area_left + area_right)
+    }
+    fn random_bvh(&self, left: &Box<Geometry>, right: &Box<Geometry>, origin: Point3, sampler: &mut Sampler) -> Vec3 {
+        let (area_left, area_right) = (left.area(), right.area());
+        if sampler.random() * (area_left + area_right) < area_left { left.random(origin, sampler) } else { right.random(origin, sampler) }
+    }
+
+    fn pdf_value_collider_list(&self, colliders: &Vec<Geometry>, probabilities: &Vec<f32>, origin: Point3, v: Vec3) -> f32 {
+        colliders.iter().zip(probabilities.iter()).map(|(c, p)| if *p > 0.0 { p * c.pdf_value(origin, v) } else { 0.0 }).sum()
+    }
+    fn random_collider_list(&self, colliders: &Vec<Geometry>, probabilities: &Vec<f32>, origin: Point3, sampler: &mut Sampler) -> Vec3 {
+        if colliders.is_empty() {
+            return Vec3::new(1., 0., 0.);
+        }
+        colliders[pick_index(probabilities, sampler.get_1d())].random(origin, sampler)
+    }
+
+    // Solid angle density with which `random` returns the direction `v` from `origin`
     pub fn pdf_value(&self, origin: Point3, v: Vec3) -> f32 {
         match &self.geometry_type {
-            GeometryType::XZRect(x0, x1, z0, z1, k) => self.pdf_value_xzrect(*x0, *x1, *z0, *z1, *k, origin, v),
+            GeometryType::Sphere(center, radius) => self.pdf_value_sphere(center, *radius, origin, v),
+            GeometryType::XYRect(..) | GeometryType::XZRect(..) | GeometryType::YZRect(..) | GeometryType::Triangle(..) => self.pdf_value_area(self.area(), origin, v),
+            GeometryType::Cuboid(_p0, _p1, sides) => self.pdf_value_cuboid(sides, origin, v),
+            GeometryType::TranslateInstance(geometry, displacement) => geometry.pdf_value(origin - *displacement, v),
+            GeometryType::YRotationInstance(geometry, axis, _angle, sin_theta, cos_theta, _has_box, _aabb) => self.pdf_value_rot_instance(geometry, axis, *sin_theta, *cos_theta, origin, v),
+            GeometryType::BVHNode(left, right, bounding_box, _axis, _area, _single) => self.pdf_value_bvh(left, right, bounding_box, origin, v),
+            GeometryType::ColliderList(colliders, probabilities) => self.pdf_value_collider_list(colliders, probabilities, origin, v),
+            GeometryType::Mesh(data) => data.pdf_value(origin, v),
             _ => 0.0
         }
     }
-    pub fn random(&self, origin: Point3) -> Vec3 {
+    // Direction from `origin` towards a random point on the shape; its length is the distance there
+    // for shapes sampled by area
+    pub fn random(&self, origin: Point3, sampler: &mut Sampler) -> Vec3 {
         match &self.geometry_type {
-            GeometryType::XZRect(x0, x1, z0, z1, k) => self.random_xzrect(*x0, *x1, *z0, *z1, *k, origin),
+            GeometryType::Sphere(center, radius) => self.random_sphere(center, *radius, origin, sampler),
+            GeometryType::XYRect(x0, x1, y0, y1, k) => self.random_xyrect(*x0, *x1, *y0, *y1, *k, origin, sampler),
+            GeometryType::XZRect(x0, x1, z0, z1, k) => self.random_xzrect(*x0, *x1, *z0, *z1, *k, origin, sampler),
+            GeometryType::YZRect(z0, z1, y0, y1, k) => self.random_yzrect(*z0, *z1, *y0, *y1, *k, origin, sampler),
+            GeometryType::Cuboid(_p0, _p1, sides) => self.random_cuboid(sides, origin, sampler),
+            GeometryType::TranslateInstance(geometry, displacement) => geometry.random(origin - *displacement, sampler),
+            GeometryType::YRotationInstance(geometry, axis, _angle, sin_theta, cos_theta, _has_box, _aabb) => self.random_rot_instance(geometry, axis, *sin_theta, *cos_theta, origin, sampler),
+            GeometryType::BVHNode(left, right, _bounding_box, _axis, _area, _single) => self.random_bvh(left, right, origin, sampler),
+            GeometryType::ColliderList(colliders, probabilities) => self.random_collider_list(colliders, probabilities, origin, sampler),
+            GeometryType::Triangle(p0, p1, p2, _plane_normal) => self.random_triangle(*p0, *p1, *p2, origin, sampler),
+            GeometryType::Mesh(data) => data.random(origin, sampler),
             _ => Vec3::new(1., 0., 0.)
         }
     }
@@ -732,21 +951,49 @@ impl Geometry {
 pub struct SceneColliders {
     pub objects: Vec<Geometry>,
     pub atlas: Arc<Mutex<ImageTextureAtlas>>,
+    // Every emissive shape in the scene, filled in by `build_lights`
+    pub lights: Arc<Geometry>,
+    pub delta_lights: Vec<DeltaLight>,
 }
 
 impl SceneColliders {
     pub fn new() -> Self {
         Self {
             objects: Vec::new(),
-            atlas: Arc::new(Mutex::new(ImageTextureAtlas::new()))
+            atlas: Arc::new(Mutex::new(ImageTextureAtlas::new())),
+            lights: Arc::new(Geometry::collider_list(Vec::new())),
+            delta_lights: Vec::new(),
         }
     }
     pub fn add(&mut self, object: Geometry) {
         self.objects.push(object);
     }
-    pub fn load_image(&mut self, path: &str) -> Texture {
-        let idx = self.atlas.lock().unwrap().load(path);
-        Texture::image(&self.atlas, idx)
+    pub fn add_light(&mut self, light: DeltaLight) {
+        self.delta_lights.push(light);
+    }
+    // Collects the emissive shapes of every object, including those inside BVHs, instances and meshes,
+    // into the light table. The table picks a light by its emitted power, or by its area if nothing
+    // reports any power.
+    pub fn build_lights(&mut self) {
+        let mut lights = Vec::new();
+        for obj in self.objects.iter() {
+            lights.extend(obj.gather_lights().0);
+        }
+        self.lights = Arc::new(Geometry::collider_list(lights));
+    }
+    pub fn light_count(&self) -> usize {
+        match &self.lights.geometry_type {
+            GeometryType::ColliderList(lights, _probabilities) => lights.len(),
+            _ => 1
+        }
+    }
+    pub fn load_image(&mut self, path: &str) -> Result<Texture, String> {
+        let idx = self.atlas.lock().unwrap().load(path)?;
+        Ok(Texture::image(&self.atlas, idx))
+    }
+    // Whether anything lies along `r` between `t_min` and `t_max`, for shadow rays
+    pub fn occluded(&self, r: Ray, t_min: f32, t_max: f32) -> bool {
+        self.objects.iter().any(|obj| obj.intersect(r, t_min, t_max).is_some())
     }
     pub fn intersect(&self, r: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
         let mut hit_anything = false;
@@ -0,0 +1,321 @@
+#[path = "camera.rs"] mod camera;
+pub use camera::*;
+
+
+pub fn luminance(color: Rgb) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+// Piecewise gaussian with different widths below and above its peak
+fn lobe(x: f32, mu: f32, sigma_below: f32, sigma_above: f32) -> f32 {
+    let t = (x - mu) / if x < mu { sigma_below } else { sigma_above };
+    (-0.5 * t * t).exp()
+}
+
+// CIE 1931 2 degree color matching functions at a wavelength in nanometres, from the multi-lobe fit of
+// Wyman, Sloan and Shirley, "Simple Analytic Approximations to the CIE XYZ Color Matching Functions", 2013
+pub fn cie_xyz(wavelength: f32) -> Vec3 {
+    Vec3::new(
+        1.056 * lobe(wavelength, 599.8, 37.9, 31.0) + 0.362 * lobe(wavelength, 442.0, 16.0, 26.7) - 0.065 * lobe(wavelength, 501.1, 20.4, 26.2),
+        0.821 * lobe(wavelength, 568.8, 46.9, 40.5) + 0.286 * lobe(wavelength, 530.9, 16.3, 31.1),
+        1.217 * lobe(wavelength, 437.0, 11.8, 36.0) + 0.681 * lobe(wavelength, 459.0, 26.0, 13.8),
+    )
+}
+
+// CIE XYZ to linear sRGB (D65 white)
+pub fn xyz_to_rgb(xyz: Vec3) -> Rgb {
+    Rgb::new(
+        3.2404542 * xyz.x - 1.5371385 * xyz.y - 0.4985314 * xyz.z,
+        -0.969266 * xyz.x + 1.8760108 * xyz.y + 0.041556 * xyz.z,
+        0.0556434 * xyz.x - 0.2040259 * xyz.y + 1.0572252 * xyz.z,
+    )
+}
+
+// Linear sRGB color of a black body at a temperature in kelvin, scaled to unit luminance. Colors
+// outside the sRGB gamut (the deep red of very cool bodies) are clipped.
+pub fn blackbody(kelvin: f32) -> Rgb {
+    // Second radiation constant hc/k in nanometre kelvins
+    const C2: f64 = 1.4387769e7;
+    let mut xyz = Vec3::origin();
+    let mut wavelength = 360.0;
+    while wavelength <= 830.0 {
+        let l = wavelength as f64;
+        let planck = 1.0 / (l.powi(5) * ((C2 / (l * kelvin.max(1.0) as f64)).exp() - 1.0));
+        xyz = xyz + cie_xyz(wavelength) * (planck * 1e15) as f32;
+        wavelength += 5.0;
+    }
+    let rgb = xyz_to_rgb(xyz);
+    let rgb = Rgb::new(rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0));
+    let y = luminance(rgb);
+    if y > 0.0 && y.is_finite() { rgb / y } else { Rgb::origin() }
+}
+
+
+// Smits, "An RGB to Spectrum Conversion for Reflectances", 1999: spectra of white and of the primary
+// and secondary colors, at ten wavelengths evenly spaced from 380 to 720 nm
+const SMITS_WHITE: [f32; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f32; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f32; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f32; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f32; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f32; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f32; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// Range of wavelengths sampled in spectral mode, in nanometres, outside of which the eye sees nothing
+pub const LAMBDA_MIN: f32 = 360.0;
+pub const LAMBDA_MAX: f32 = 830.0;
+
+// Integrals of the fitted color matching functions over the sampled range
+const CIE_INTEGRALS: [f32; 3] = [106.76582, 106.92207, 106.875];
+
+fn smits_basis(table: &[f32; 10], wavelength: f32) -> f32 {
+    let x = clamp((wavelength - 380.0) / (720.0 - 380.0) * 9.0, 0.0, 9.0);
+    let i = (x as usize).min(8);
+    table[i] + (table[i + 1] - table[i]) * (x - i as f32)
+}
+
+// Value at a wavelength of a smooth spectrum with the given linear sRGB color (Smits 1999). Whites
+// map to flat spectra and colors inside [0, 1] to reflectances inside it.
+pub fn rgb_to_spectrum(color: Rgb, wavelength: f32) -> f32 {
+    let (r, g, b) = (color.x, color.y, color.z);
+    let basis = |table: &[f32; 10]| smits_basis(table, wavelength);
+    if r <= g && r <= b {
+        r * basis(&SMITS_WHITE) + if g <= b {
+            (g - r) * basis(&SMITS_CYAN) + (b - g) * basis(&SMITS_BLUE)
+        } else {
+            (b - r) * basis(&SMITS_CYAN) + (g - b) * basis(&SMITS_GREEN)
+        }
+    } else if g <= r && g <= b {
+        g * basis(&SMITS_WHITE) + if r <= b {
+            (r - g) * basis(&SMITS_MAGENTA) + (b - r) * basis(&SMITS_BLUE)
+        } else {
+            (b - g) * basis(&SMITS_MAGENTA) + (r - b) * basis(&SMITS_RED)
+        }
+    } else {
+        b * basis(&SMITS_WHITE) + if r <= g {
+            (r - b) * basis(&SMITS_YELLOW) + (g - r) * basis(&SMITS_GREEN)
+        } else {
+            (g - b) * basis(&SMITS_YELLOW) + (r - g) * basis(&SMITS_RED)
+        }
+    }
+}
+
+
+// Wavelengths one path carries in spectral mode: a hero wavelength and two companions shifted by a
+// third and two thirds of the sampled range, wrapping around (Wilkie et al., "Hero Wavelength
+// Spectral Sampling", 2014). Values along the path are kept as a `Vec3` with one component per
+// wavelength, hero first.
+#[derive(Clone, Copy)]
+pub struct Wavelengths {
+    pub lambda: Vec3,
+}
+
+impl Wavelengths {
+    pub fn new(hero: f32) -> Self {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let companion = |shift: f32| LAMBDA_MIN + (hero - LAMBDA_MIN + shift * range) % range;
+        Self {
+            lambda: Vec3::new(hero, companion(1.0 / 3.0), companion(2.0 / 3.0)),
+        }
+    }
+    // Hero wavelength drawn uniformly from a random number in [0, 1)
+    pub fn sample_hero(u: f32) -> f32 {
+        LAMBDA_MIN + u * (LAMBDA_MAX - LAMBDA_MIN)
+    }
+
+    pub fn upsample(&self, color: Rgb) -> Vec3 {
+        Vec3::new(rgb_to_spectrum(color, self.lambda.x), rgb_to_spectrum(color, self.lambda.y), rgb_to_spectrum(color, self.lambda.z))
+    }
+    // Linear sRGB estimate of the radiance a path found at its wavelengths, white balanced so that a
+    // flat spectrum comes out as sRGB white
+    pub fn to_rgb(self, values: Vec3) -> Rgb {
+        let weight = (LAMBDA_MAX - LAMBDA_MIN) / 3.0;
+        let xyz = (cie_xyz(self.lambda.x) * values.x + cie_xyz(self.lambda.y) * values.y + cie_xyz(self.lambda.z) * values.z) * weight;
+        xyz_to_rgb(Vec3::new(xyz.x * 0.95047 / CIE_INTEGRALS[0], xyz.y / CIE_INTEGRALS[1], xyz.z * 1.08883 / CIE_INTEGRALS[2]))
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ToneMapOperator {
+    // Hard clip at 1.0
+    Clamp,
+    // Reinhard on luminance; the parameter is the white point that maps to 1.0 (infinite for the basic operator)
+    Reinhard(f32),
+    // Stephen Hill's fit of the ACES reference rendering and sRGB output transforms
+    AcesFilmic,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TransferFunction {
+    Linear,
+    Gamma(f32),
+    Srgb,
+}
+
+
+// Display transform applied to the linear framebuffer before quantization:
+// exposure (in stops), then tone mapping, then the transfer function of the output.
+#[derive(Clone)]
+pub struct ColorPipeline {
+    pub exposure: f32,
+    pub operator: ToneMapOperator,
+    pub transfer: TransferFunction,
+}
+
+impl Default for ColorPipeline {
+    fn default() -> Self {
+        Self::new(0.0, ToneMapOperator::Clamp, TransferFunction::Srgb)
+    }
+}
+
+impl ColorPipeline {
+    pub fn new(exposure: f32, operator: ToneMapOperator, transfer: TransferFunction) -> Self {
+        Self {
+            exposure: exposure,
+            operator: operator,
+            transfer: transfer,
+        }
+    }
+    pub fn parse_operator(name: &str) -> Option<ToneMapOperator> {
+        match name.to_lowercase().as_str() {
+            "clamp" | "none" => Some(ToneMapOperator::Clamp),
+            "reinhard" => Some(ToneMapOperator::Reinhard(f32::INFINITY)),
+            "aces" => Some(ToneMapOperator::AcesFilmic),
+            _ => {
+                let white = name.strip_prefix("reinhard:")?.parse::<f32>().ok()?;
+                if white > 0.0 { Some(ToneMapOperator::Reinhard(white)) } else { None }
+            }
+        }
+    }
+    pub fn parse_transfer(name: &str) -> Option<TransferFunction> {
+        match name.to_lowercase().as_str() {
+            "linear" => Some(TransferFunction::Linear),
+            "srgb" => Some(TransferFunction::Srgb),
+            _ => {
+                let gamma = name.strip_prefix("gamma")?.trim_start_matches(':').parse::<f32>().ok()?;
+                if gamma > 0.0 { Some(TransferFunction::Gamma(gamma)) } else { None }
+            }
+        }
+    }
+
+    fn tonemap_reinhard(&self, color: Rgb, white: f32) -> Rgb {
+        let l = luminance(color);
+        if l <= 0.0 {
+            return Rgb::origin();
+        }
+        let mapped = l * (1.0 + l / (white * white)) / (1.0 + l);
+        color * (mapped / l)
+    }
+    fn tonemap_aces(&self, color: Rgb) -> Rgb {
+        let v = Rgb::new(
+            0.59719 * color.x + 0.35458 * color.y + 0.04823 * color.z,
+            0.07600 * color.x + 0.90834 * color.y + 0.01566 * color.z,
+            0.02840 * color.x + 0.13383 * color.y + 0.83777 * color.z,
+        );
+        let fit = |x: f32| (x * (x + 0.0245786) - 0.000090537) / (x * (0.983729 * x + 0.432951) + 0.238081);
+        let v = Rgb::new(fit(v.x), fit(v.y), fit(v.z));
+        Rgb::new(
+            1.60475 * v.x - 0.53108 * v.y - 0.07367 * v.z,
+            -0.10208 * v.x + 1.10813 * v.y - 0.00605 * v.z,
+            -0.00327 * v.x - 0.07276 * v.y + 1.07602 * v.z,
+        )
+    }
+
+    fn encode(&self, v: f32) -> f32 {
+        let v = clamp(v, 0.0, 1.0);
+        match self.transfer {
+            TransferFunction::Linear => v,
+            TransferFunction::Gamma(gamma) => v.powf(1.0 / gamma),
+            TransferFunction::Srgb => if v <= 0.0031308 { 12.92 * v } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 },
+        }
+    }
+
+    // Maps linear scene radiance to display encoded values in [0, 1]
+    pub fn apply(&self, color: Rgb) -> Rgb {
+        let mut c = color;
+        if c.x.is_nan() { c.x = 0.0 };
+        if c.y.is_nan() { c.y = 0.0 };
+        if c.z.is_nan() { c.z = 0.0 };
+        c = c * 2f32.powf(self.exposure);
+
+        let mapped = match self.operator {
+            ToneMapOperator::Clamp => c,
+            ToneMapOperator::Reinhard(white) => self.tonemap_reinhard(c, white),
+            ToneMapOperator::AcesFilmic => self.tonemap_aces(c),
+        };
+        Rgb::new(self.encode(mapped.x), self.encode(mapped.y), self.encode(mapped.z))
+    }
+    pub fn quantize(&self, color: Rgb) -> [u8; 3] {
+        let c = self.apply(color);
+        [(c.x * 255.0 + 0.5) as u8, (c.y * 255.0 + 0.5) as u8, (c.z * 255.0 + 0.5) as u8]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Average of `to_rgb` over evenly spread hero wavelengths, as a render with many samples sees it
+    fn average_rgb(spectrum: impl Fn(f32) -> f32) -> Rgb {
+        let n = 3000;
+        let mut sum = Rgb::origin();
+        for i in 0..n {
+            let wavelengths = Wavelengths::new(Wavelengths::sample_hero((i as f32 + 0.5) / n as f32));
+            let values = Vec3::new(spectrum(wavelengths.lambda.x), spectrum(wavelengths.lambda.y), spectrum(wavelengths.lambda.z));
+            sum = sum + wavelengths.to_rgb(values);
+        }
+        sum / n as f32
+    }
+
+    #[test]
+    fn companion_wavelengths_stay_in_range() {
+        for hero in [LAMBDA_MIN, 500.0, 700.0, LAMBDA_MAX - 0.01] {
+            let lambda = Wavelengths::new(hero).lambda;
+            assert_eq!(lambda.x, hero);
+            for l in [lambda.y, lambda.z] {
+                assert!((LAMBDA_MIN..LAMBDA_MAX).contains(&l), "{} from hero {}", l, hero);
+            }
+        }
+    }
+
+    #[test]
+    fn cie_integrals_match_the_fitted_functions() {
+        let steps = 47000;
+        let step = (LAMBDA_MAX - LAMBDA_MIN) / steps as f32;
+        let mut sum = Vec3::origin();
+        for i in 0..steps {
+            sum = sum + cie_xyz(LAMBDA_MIN + (i as f32 + 0.5) * step) * step;
+        }
+        for (computed, table) in [sum.x, sum.y, sum.z].iter().zip(CIE_INTEGRALS) {
+            assert!((computed / table - 1.0).abs() < 1e-3, "{} against {}", computed, table);
+        }
+    }
+
+    #[test]
+    fn flat_spectra_come_out_white() {
+        let white = average_rgb(|_| 1.0);
+        for c in [white.x, white.y, white.z] {
+            assert!((c - 1.0).abs() < 0.01, "white came out as ({}, {}, {})", white.x, white.y, white.z);
+        }
+        let grey = average_rgb(|_| 0.25);
+        assert!((grey.x - 0.25).abs() < 0.005 && (grey.y - 0.25).abs() < 0.005 && (grey.z - 0.25).abs() < 0.005);
+    }
+
+    #[test]
+    fn upsampled_colors_round_trip() {
+        for color in [Rgb::new(1.0, 1.0, 1.0), Rgb::new(0.8, 0.2, 0.1), Rgb::new(0.1, 0.5, 0.9)] {
+            let rgb = average_rgb(|l| rgb_to_spectrum(color, l));
+            assert!((rgb - color).length() < 0.1, "({}, {}, {}) came out as ({}, {}, {})", color.x, color.y, color.z, rgb.x, rgb.y, rgb.z);
+        }
+    }
+
+    #[test]
+    fn white_spectrum_is_flat() {
+        for i in 0..=20 {
+            let l = LAMBDA_MIN + i as f32 * (LAMBDA_MAX - LAMBDA_MIN) / 20.0;
+            assert!((rgb_to_spectrum(Rgb::new(1.0, 1.0, 1.0), l) - 1.0).abs() < 1e-3);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+#[path = "color.rs"] mod color;
+pub use color::*;
+use image::ImageBuffer;
+use std::fs;
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    Spiral,
+    Hilbert,
+}
+
+impl TileOrder {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "scanline" => Some(Self::Scanline),
+            "spiral" => Some(Self::Spiral),
+            "hilbert" => Some(Self::Hilbert),
+            _ => None
+        }
+    }
+}
+
+
+// Rectangle of pixels [x0, x1) x [y0, y1) in image coordinates (y = 0 is the top scanline)
+#[derive(Clone, Copy)]
+pub struct Tile {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Tile {
+    pub fn width(&self) -> u32 {
+        self.x1 - self.x0
+    }
+    pub fn height(&self) -> u32 {
+        self.y1 - self.y0
+    }
+    pub fn pixel_count(&self) -> usize {
+        (self.width() * self.height()) as usize
+    }
+}
+
+
+fn hilbert_d2xy(n: u32, d: u32) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+fn tile_grid_order(nx: u32, ny: u32, order: TileOrder) -> Vec<(u32, u32)> {
+    match order {
+        TileOrder::Scanline => {
+            let mut cells = Vec::new();
+            for ty in 0..ny {
+                for tx in 0..nx {
+                    cells.push((tx, ty));
+                }
+            }
+            cells
+        },
+        TileOrder::Spiral => {
+            // Walk outwards from the center tile: 1 right, 1 down, 2 left, 2 up, 3 right, ...
+            let total = (nx * ny) as usize;
+            let mut cells = Vec::with_capacity(total);
+            let (mut x, mut y) = (((nx - 1) / 2) as i64, ((ny - 1) / 2) as i64);
+            let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+            let mut step_len = 1;
+            let mut dir = 0;
+            cells.push((x as u32, y as u32));
+            while cells.len() < total {
+                for _ in 0..2 {
+                    for _ in 0..step_len {
+                        x += directions[dir].0;
+                        y += directions[dir].1;
+                        if x >= 0 && y >= 0 && x < nx as i64 && y < ny as i64 {
+                            cells.push((x as u32, y as u32));
+                        }
+                    }
+                    dir = (dir + 1) % 4;
+                }
+                step_len += 1;
+            }
+            cells
+        },
+        TileOrder::Hilbert => {
+            let n = nx.max(ny).next_power_of_two();
+            let mut cells = Vec::with_capacity((nx * ny) as usize);
+            for d in 0..n * n {
+                let (x, y) = hilbert_d2xy(n, d);
+                if x < nx && y < ny {
+                    cells.push((x, y));
+                }
+            }
+            cells
+        }
+    }
+}
+
+pub fn generate_tiles(img_width: u32, img_height: u32, tile_size: u32, order: TileOrder) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let nx = img_width.div_ceil(tile_size);
+    let ny = img_height.div_ceil(tile_size);
+    if nx == 0 || ny == 0 {
+        return Vec::new();
+    }
+
+    tile_grid_order(nx, ny, order).iter().map(|(tx, ty)| {
+        Tile {
+            x0: tx * tile_size,
+            y0: ty * tile_size,
+            x1: ((tx + 1) * tile_size).min(img_width),
+            y1: ((ty + 1) * tile_size).min(img_height),
+        }
+    }).collect()
+}
+
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"RTCKPT02";
+
+
+// Running statistics of the samples of one pixel: the radiance sum plus Welford's mean and sum of
+// squared deviations (m2) of the sample luminance, which drive adaptive sampling.
+#[derive(Clone, Copy)]
+pub struct PixelStats {
+    pub sum: Rgb,
+    pub samples: u32,
+    pub mean: f32,
+    pub m2: f32,
+}
+
+impl PixelStats {
+    pub fn new() -> Self {
+        Self {
+            sum: Rgb::origin(),
+            samples: 0,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+    pub fn add_sample(&mut self, color: Rgb) {
+        self.sum = self.sum + color;
+        self.samples += 1;
+        let l = luminance(color);
+        let delta = l - self.mean;
+        self.mean += delta / self.samples as f32;
+        self.m2 += delta * (l - self.mean);
+    }
+    // Chan et al.'s parallel update, combining the statistics of two disjoint sets of samples
+    pub fn merge(&mut self, other: &Self) {
+        if other.samples == 0 {
+            return;
+        }
+        let n_a = self.samples as f32;
+        let n_b = other.samples as f32;
+        let n = n_a + n_b;
+        let delta = other.mean - self.mean;
+
+        self.sum = self.sum + other.sum;
+        self.mean += delta * n_b / n;
+        self.m2 += other.m2 + delta * delta * n_a * n_b / n;
+        self.samples += other.samples;
+    }
+    pub fn color(&self) -> Rgb {
+        if self.samples > 0 { self.sum / self.samples as f32 } else { Rgb::origin() }
+    }
+    pub fn variance(&self) -> f32 {
+        if self.samples > 1 { self.m2 / (self.samples - 1) as f32 } else { f32::INFINITY }
+    }
+    // Standard error of the mean luminance relative to the mean itself. Dark pixels are measured
+    // against a floor so that noise too faint to see does not keep them sampling forever.
+    pub fn relative_error(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+        (self.variance() / self.samples as f32).sqrt() / self.mean.max(0.01)
+    }
+}
+
+
+// Shared accumulation buffer holding the sample statistics of every pixel. The radiance sums are
+// only normalized when the image is read out.
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<PixelStats>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width: width,
+            height: height,
+            pixels: vec![PixelStats::new(); (width * height) as usize],
+        }
+    }
+    // `stats` holds the tile's pixels in scanline order
+    pub fn add_tile(&mut self, tile: &Tile, stats: &[PixelStats]) {
+        let tile_width = tile.width() as usize;
+        for y in tile.y0..tile.y1 {
+            let row = (y - tile.y0) as usize * tile_width;
+            let start = (y * self.width + tile.x0) as usize;
+            for k in 0..tile_width {
+                self.pixels[start + k].merge(&stats[row + k]);
+            }
+        }
+    }
+    pub fn min_samples(&self) -> u32 {
+        self.pixels.iter().map(|p| p.samples).min().unwrap_or(0)
+    }
+    pub fn max_samples(&self) -> u32 {
+        self.pixels.iter().map(|p| p.samples).max().unwrap_or(0)
+    }
+
+    // A pixel keeps sampling until it has `max_samples`; with a positive `threshold` it stops as soon
+    // as it has at least `min_samples` and its relative error dropped below the threshold.
+    pub fn needs_samples(&self, idx: usize, max_samples: u32, min_samples: u32, threshold: f32) -> bool {
+        let p = &self.pixels[idx];
+        if p.samples >= max_samples {
+            return false;
+        }
+        if threshold <= 0.0 || p.samples < min_samples {
+            return true;
+        }
+        p.relative_error() > threshold
+    }
+
+    pub fn to_image(&self) -> ImageBuffer<image::Rgb<f32>, Vec<f32>> {
+        let mut imgbuf = ImageBuffer::new(self.width, self.height);
+        for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
+            let c = self.pixels[(y * self.width + x) as usize].color();
+            *pixel = image::Rgb([c.x, c.y, c.z]);
+        }
+        return imgbuf;
+    }
+    // Debug view of the sample distribution: each pixel's sample count relative to the largest one
+    pub fn sample_count_image(&self) -> ImageBuffer<image::Rgb<f32>, Vec<f32>> {
+        let max = self.max_samples().max(1) as f32;
+        let mut imgbuf = ImageBuffer::new(self.width, self.height);
+        for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
+            let v = self.pixels[(y * self.width + x) as usize].samples as f32 / max;
+            *pixel = image::Rgb([v, v, v]);
+        }
+        return imgbuf;
+    }
+
+    // Checkpoint layout (little endian): magic, width: u32, height: u32, then for every pixel in
+    // scanline order the radiance sum as three f32, the sample count as u32 and the luminance mean
+    // and m2 as f32.
+    pub fn save_checkpoint(&self, path: &str) -> Result<(), String> {
+        let mut data = Vec::with_capacity(16 + self.pixels.len() * 24);
+        data.extend_from_slice(CHECKPOINT_MAGIC);
+        data.extend_from_slice(&self.width.to_le_bytes());
+        data.extend_from_slice(&self.height.to_le_bytes());
+        for p in self.pixels.iter() {
+            data.extend_from_slice(&p.sum.x.to_le_bytes());
+            data.extend_from_slice(&p.sum.y.to_le_bytes());
+            data.extend_from_slice(&p.sum.z.to_le_bytes());
+            data.extend_from_slice(&p.samples.to_le_bytes());
+            data.extend_from_slice(&p.mean.to_le_bytes());
+            data.extend_from_slice(&p.m2.to_le_bytes());
+        }
+
+        // Write next to the target and rename, so a crash mid-write never destroys the previous checkpoint
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, &data).map_err(|e| format!("{}: {}", tmp_path, e))?;
+        fs::rename(&tmp_path, path).map_err(|e| format!("{}: {}", path, e))
+    }
+    pub fn load_checkpoint(path: &str) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        if data.len() < 16 || &data[0..8] != CHECKPOINT_MAGIC {
+            return Err(format!("{}: not a render checkpoint", path));
+        }
+        let read_u32 = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let read_f32 = |offset: usize| f32::from_bits(read_u32(offset));
+
+        let width = read_u32(8);
+        let height = read_u32(12);
+        // A corrupt header can claim more pixels than fit in memory, so the size is checked for overflow
+        let size = (width as usize).checked_mul(height as usize).and_then(|n| n.checked_mul(24)).and_then(|n| n.checked_add(16));
+        if size != Some(data.len()) {
+            return Err(format!("{}: checkpoint is truncated or corrupt", path));
+        }
+
+        let mut film = Self::new(width, height);
+        for (i, p) in film.pixels.iter_mut().enumerate() {
+            let offset = 16 + i * 24;
+            p.sum = Rgb::new(read_f32(offset), read_f32(offset + 4), read_f32(offset + 8));
+            p.samples = read_u32(offset + 12);
+            p.mean = read_f32(offset + 16);
+            p.m2 = read_f32(offset + 20);
+        }
+        Ok(film)
+    }
+}
@@ -0,0 +1,373 @@
+#[path = "stl.rs"] mod stl;
+pub use stl::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use gltf::{buffer, image as gltf_image, camera::Projection, khr_lights_punctual::Kind, mesh::Mode};
+
+
+// Column-major 4x4 matrix, as glTF stores them
+type Mat4 = [[f32; 4]; 4];
+
+const IDENTITY: Mat4 = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
+
+fn mat_mul(a: &Mat4, b: &Mat4) -> Mat4 {
+    let mut c = [[0.0; 4]; 4];
+    for (j, column) in c.iter_mut().enumerate() {
+        for (i, value) in column.iter_mut().enumerate() {
+            *value = (0..4).map(|k| a[k][i] * b[j][k]).sum();
+        }
+    }
+    c
+}
+fn transform_point(m: &Mat4, p: [f32; 3]) -> Point3 {
+    Point3::new(
+        m[0][0] * p[0] + m[1][0] * p[1] + m[2][0] * p[2] + m[3][0],
+        m[0][1] * p[0] + m[1][1] * p[1] + m[2][1] * p[2] + m[3][1],
+        m[0][2] * p[0] + m[1][2] * p[1] + m[2][2] * p[2] + m[3][2],
+    )
+}
+fn transform_vector(m: &Mat4, v: [f32; 3]) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v[0] + m[1][0] * v[1] + m[2][0] * v[2],
+        m[0][1] * v[0] + m[1][1] * v[1] + m[2][1] * v[2],
+        m[0][2] * v[0] + m[1][2] * v[1] + m[2][2] * v[2],
+    )
+}
+// Columns of the cofactor matrix of the upper 3x3 block, which takes normals the way the inverse
+// transpose does up to a positive scale when the block keeps handedness. Also returns its determinant.
+fn normal_matrix(m: &Mat4) -> ([Vec3; 3], f32) {
+    let c = [
+        Vec3::new(m[0][0], m[0][1], m[0][2]),
+        Vec3::new(m[1][0], m[1][1], m[1][2]),
+        Vec3::new(m[2][0], m[2][1], m[2][2]),
+    ];
+    let det = c[0].dot(c[1].cross(c[2]));
+    ([c[1].cross(c[2]), c[2].cross(c[0]), c[0].cross(c[1])], det)
+}
+
+fn decode_base64(data: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in data.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return Err(format!("invalid base64 character '{}'", c as char))
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    // A single character left over holds less than a byte
+    if bits >= 6 {
+        return Err("truncated base64 data".to_string());
+    }
+    Ok(out)
+}
+
+// Contents of a buffer or image URI: either a base64 `data:` URI or a file relative to the glTF file
+fn read_uri(dir: &Path, uri: &str) -> Result<Vec<u8>, String> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        return match data.split_once(";base64,") {
+            Some((_mime_type, payload)) => decode_base64(payload),
+            None => Err("only base64 data URIs are supported".to_string())
+        };
+    }
+    // Undo percent-encoding, which turns spaces in file names into %20
+    let mut bytes = Vec::new();
+    let mut chars = uri.bytes();
+    while let Some(c) = chars.next() {
+        if c == b'%' {
+            let hex: String = chars.by_ref().take(2).map(|c| c as char).collect();
+            bytes.push(u8::from_str_radix(&hex, 16).map_err(|_| format!("invalid URI '{}'", uri))?);
+        } else {
+            bytes.push(c);
+        }
+    }
+    let path = dir.join(String::from_utf8_lossy(&bytes).into_owned());
+    fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+
+// State of one import: the file's buffers, and the textures and materials converted so far, which
+// meshes and materials share
+struct GltfImport<'a> {
+    dir: &'a Path,
+    buffers: Vec<Vec<u8>>,
+    // Keyed by image and the color factor it is multiplied by
+    textures: HashMap<(usize, [u32; 3]), Texture>,
+    materials: HashMap<Option<usize>, Material>,
+}
+
+impl<'a> GltfImport<'a> {
+    // The image `texture` uses, with its colors multiplied by `factor`
+    fn texture(&mut self, scene: &SceneColliders, texture: gltf::Texture, factor: [f32; 3]) -> Result<Texture, String> {
+        let source = texture.source();
+        let key = (source.index(), factor.map(|x| x.to_bits()));
+        if let Some(texture) = self.textures.get(&key) {
+            return Ok(texture.clone());
+        }
+        let bytes = match source.source() {
+            gltf_image::Source::View { view, mime_type: _ } => {
+                let buffer = &self.buffers[view.buffer().index()];
+                match buffer.get(view.offset()..view.offset() + view.length()) {
+                    Some(bytes) => bytes.to_vec(),
+                    None => return Err(format!("image {}: buffer view {} lies outside its buffer", source.index(), view.index()))
+                }
+            },
+            gltf_image::Source::Uri { uri, mime_type: _ } => read_uri(self.dir, uri)?
+        };
+        let mut img = image::load_from_memory(&bytes).map_err(|e| format!("image {}: {}", source.index(), e))?;
+        // Image textures are read as 8 bits per channel, which factors of at most one keep to
+        if factor != [1.0; 3] {
+            let mut tinted = img.to_rgb8();
+            for pixel in tinted.pixels_mut() {
+                for (channel, f) in pixel.0.iter_mut().zip(factor) {
+                    *channel = (*channel as f32 * f).round() as u8;
+                }
+            }
+            img = image::DynamicImage::ImageRgb8(tinted);
+        }
+        let idx = scene.atlas.lock().unwrap().add(img);
+        let texture = Texture::image(&scene.atlas, idx);
+        self.textures.insert(key, texture.clone());
+        Ok(texture)
+    }
+
+    // Metallic-roughness materials become principled ones with the factors as their parameters, and
+    // the base color factor multiplying the base color texture. Materials that emit become lights,
+    // with the emissive factor (and strength) tinting the emissive texture. The metallic-roughness,
+    // normal and occlusion textures aren't used.
+    fn material(&mut self, scene: &SceneColliders, material: gltf::Material) -> Result<Material, String> {
+        if let Some(converted) = self.materials.get(&material.index()) {
+            return Ok(converted.clone());
+        }
+        let emissive = Rgb::new(material.emissive_factor()[0], material.emissive_factor()[1], material.emissive_factor()[2]);
+        let converted = if luminance(emissive) > 0.0 {
+            let texture = match material.emissive_texture() {
+                Some(info) => self.texture(scene, info.texture(), [1.0; 3])?,
+                None => Texture::solid_color(Rgb::new(1.0, 1.0, 1.0))
+            };
+            let mut params = EmissionParams::new();
+            params.intensity = material.emissive_strength().unwrap_or(1.0);
+            params.tint = emissive;
+            params.sides = if material.double_sided() { EmissionSides::Both } else { EmissionSides::Front };
+            Material::emissive_texture(texture, params, &scene.atlas)
+        } else {
+            let pbr = material.pbr_metallic_roughness();
+            let factor = [pbr.base_color_factor()[0], pbr.base_color_factor()[1], pbr.base_color_factor()[2]];
+            let base_color = match pbr.base_color_texture() {
+                Some(info) => self.texture(scene, info.texture(), factor)?,
+                None => Texture::solid_color(Rgb::new(factor[0], factor[1], factor[2]))
+            };
+            let mut params = PrincipledParams::new();
+            params.metallic = pbr.metallic_factor();
+            params.roughness = pbr.roughness_factor();
+            params.transmission = material.transmission().map(|t| t.transmission_factor()).unwrap_or(0.0);
+            params.ior = material.ior().unwrap_or(1.5);
+            Material::principled(base_color, params)
+        };
+        self.materials.insert(material.index(), converted.clone());
+        Ok(converted)
+    }
+
+    // The triangles of every primitive of `mesh`, moved into world space by `transform`, as one mesh
+    fn mesh(&mut self, scene: &SceneColliders, mesh: gltf::Mesh, transform: &Mat4) -> Result<Option<Geometry>, String> {
+        let (normal_columns, det) = normal_matrix(transform);
+        let (mut positions, mut normals, mut uvs) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut triangles, mut materials, mut material_ids) = (Vec::new(), Vec::new(), Vec::new());
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != Mode::Triangles {
+                continue;
+            }
+            let buffers = &self.buffers;
+            let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| data.as_slice()));
+            let primitive_positions: Vec<[f32; 3]> = match reader.read_positions() {
+                Some(iter) => iter.collect(),
+                None => continue
+            };
+            let primitive_normals: Option<Vec<[f32; 3]>> = reader.read_normals().map(|iter| iter.collect());
+            let primitive_uvs: Option<Vec<[f32; 2]>> = reader.read_tex_coords(0).map(|iter| iter.into_f32().collect());
+            // Attributes are read by vertex index, so they must have one entry per position
+            for (name, count) in [("normals", primitive_normals.as_ref().map(|n| n.len())), ("texture coordinates", primitive_uvs.as_ref().map(|uv| uv.len()))] {
+                if let Some(count) = count.filter(|&count| count != primitive_positions.len()) {
+                    return Err(format!("mesh {}: {} {} for {} positions", mesh.index(), count, name, primitive_positions.len()));
+                }
+            }
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(iter) => iter.into_u32().collect(),
+                None => (0..primitive_positions.len() as u32).collect()
+            };
+
+            let base = positions.len() as u32;
+            for (i, p) in primitive_positions.iter().enumerate() {
+                positions.push(transform_point(transform, *p));
+                // Vertices without a normal fall back to the face normal, marked by a zero vector
+                normals.push(match &primitive_normals {
+                    Some(n) => (normal_columns[0] * n[i][0] + normal_columns[1] * n[i][1] + normal_columns[2] * n[i][2]).normalize(),
+                    None => Vec3::origin()
+                });
+                // glTF puts the origin of texture coordinates at the top of the image, the textures here at the bottom
+                uvs.push(primitive_uvs.as_ref().map(|uv| (uv[i][0], 1.0 - uv[i][1])).unwrap_or((0.0, 0.0)));
+            }
+            materials.push(self.material(scene, primitive.material())?);
+            for corners in indices.chunks_exact(3) {
+                if corners.iter().any(|&c| c as usize >= primitive_positions.len()) {
+                    return Err(format!("mesh {}: index out of range", mesh.index()));
+                }
+                // Mirroring transforms turn the winding around
+                triangles.push(if det < 0.0 { [base + corners[0], base + corners[2], base + corners[1]] } else { [base + corners[0], base + corners[1], base + corners[2]] });
+                material_ids.push((materials.len() - 1) as u32);
+            }
+        }
+
+        if normals.iter().all(|n| n.length_squared() <= 0.0) {
+            normals.clear();
+        }
+        if uvs.iter().all(|uv| *uv == (0.0, 0.0)) {
+            uvs.clear();
+        }
+        if triangles.is_empty() {
+            return Ok(None);
+        }
+        let data = MeshData::new(positions, normals, uvs, Vec::new(), triangles, materials, material_ids).map_err(|e| format!("mesh {}: {}", mesh.index(), e))?;
+        if data.triangle_count() == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Geometry::mesh(data)))
+    }
+
+    fn node(&mut self, scene: &mut SceneColliders, cameras: &mut Vec<Camera>, node: gltf::Node, parent: &Mat4) -> Result<(), String> {
+        let transform = mat_mul(parent, &node.transform().matrix());
+        let position = transform_point(&transform, [0.0, 0.0, 0.0]);
+        // Cameras and lights look down their node's -z axis
+        let forward = transform_vector(&transform, [0.0, 0.0, -1.0]).normalize();
+
+        if let Some(mesh) = node.mesh() {
+            if let Some(geometry) = self.mesh(scene, mesh, &transform)? {
+                scene.add(geometry);
+            }
+        }
+        if let Some(camera) = node.camera() {
+            // The renderer has no orthographic projection
+            if let Projection::Perspective(perspective) = camera.projection() {
+                let up = transform_vector(&transform, [0.0, 1.0, 0.0]).normalize();
+                cameras.push(Camera::new(
+                    position, position + forward, up,
+                    to_degrees(perspective.yfov()),
+                    0.0, 1.0,
+                    perspective.aspect_ratio().unwrap_or(1.0),
+                    0.0, 1.0
+                ));
+            }
+        }
+        if let Some(light) = node.light() {
+            let color = Rgb::new(light.color()[0], light.color()[1], light.color()[2]) * (light.intensity() / LUMENS_PER_WATT);
+            scene.add_light(match light.kind() {
+                Kind::Point => DeltaLight::point(position, color, 2.0),
+                Kind::Spot { inner_cone_angle, outer_cone_angle } => {
+                    let (inner, outer) = (to_degrees(inner_cone_angle), to_degrees(outer_cone_angle));
+                    DeltaLight::spot(position, position + forward, color, 2.0 * outer, outer - inner, 2.0)
+                },
+                Kind::Directional => DeltaLight::directional(forward, color),
+            });
+        }
+        for child in node.children() {
+            self.node(scene, cameras, child, &transform)?;
+        }
+        Ok(())
+    }
+}
+
+impl SceneColliders {
+    // Adds the meshes and punctual lights of a glTF 2.0 file (`.gltf` or `.glb`) to the scene, scaled
+    // about the origin, and returns its perspective cameras in the order they are found. Only the
+    // default scene (or the first one) is loaded. Meshes are placed in world space; a mesh used by
+    // several nodes is copied for each of them. Light intensities in candela and lux are converted at
+    // 683 lumens per watt.
+    pub fn load_gltf(&mut self, path: &str, scale: f32) -> Result<Vec<Camera>, String> {
+        let gltf = gltf::Gltf::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+        let mut buffers = Vec::new();
+        for buffer in gltf.buffers() {
+            let data = match buffer.source() {
+                buffer::Source::Bin => gltf.blob.clone().ok_or(format!("{}: missing binary chunk", path))?,
+                buffer::Source::Uri(uri) => read_uri(dir, uri).map_err(|e| format!("{}: {}", path, e))?
+            };
+            if data.len() < buffer.length() {
+                return Err(format!("{}: buffer {} is shorter than declared", path, buffer.index()));
+            }
+            buffers.push(data);
+        }
+
+        let mut import = GltfImport {
+            dir: dir,
+            buffers: buffers,
+            textures: HashMap::new(),
+            materials: HashMap::new(),
+        };
+        let scene = match gltf.default_scene().or(gltf.scenes().next()) {
+            Some(scene) => scene,
+            None => return Err(format!("{}: no scenes", path))
+        };
+        let mut root = IDENTITY;
+        for (i, column) in root.iter_mut().take(3).enumerate() {
+            column[i] = scale;
+        }
+        let mut cameras = Vec::new();
+        for node in scene.nodes() {
+            import.node(self, &mut cameras, node, &root).map_err(|e| format!("{}: {}", path, e))?;
+        }
+        Ok(cameras)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_base64_with_and_without_padding() {
+        assert_eq!(decode_base64("TWFu").unwrap(), b"Man");
+        assert_eq!(decode_base64("TWE=").unwrap(), b"Ma");
+        assert_eq!(decode_base64("TQ==").unwrap(), b"M");
+        assert_eq!(decode_base64("TQ").unwrap(), b"M");
+        assert_eq!(decode_base64("").unwrap(), b"");
+    }
+
+    #[test]
+    fn decodes_base64_across_whitespace_and_url_safe_alphabet() {
+        assert_eq!(decode_base64("TW\nFu IA==").unwrap(), b"Man ");
+        assert_eq!(decode_base64("+/8=").unwrap(), decode_base64("-_8=").unwrap());
+        assert_eq!(decode_base64("+/8=").unwrap(), [0xfb, 0xff]);
+    }
+
+    #[test]
+    fn rejects_invalid_base64() {
+        assert!(decode_base64("TW!u").is_err());
+        assert!(decode_base64("TWFuT").is_err());
+    }
+
+    #[test]
+    fn reads_data_uris_and_percent_encoded_files() {
+        assert_eq!(read_uri(Path::new(""), "data:application/octet-stream;base64,AAEC").unwrap(), [0, 1, 2]);
+        assert!(read_uri(Path::new(""), "data:text/plain,hello").is_err());
+
+        let dir = std::env::temp_dir().join(format!("gltf_read_uri_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a b.bin"), [7u8, 8]).unwrap();
+        assert_eq!(read_uri(&dir, "a%20b.bin").unwrap(), [7, 8]);
+        assert!(read_uri(&dir, "missing.bin").is_err());
+        assert!(read_uri(&dir, "a%zzb.bin").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
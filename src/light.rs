@@ -0,0 +1,74 @@
+#[path = "aabb.rs"] mod aabb;
+pub use aabb::*;
+
+
+#[derive(Clone)]
+pub enum DeltaLightType {
+    // Position and the exponent of the distance falloff (2 is physical)
+    Point(Point3, f32),
+    // Position, unit direction of the cone axis, cosines of the angles at which the falloff towards the
+    // edge of the cone starts and ends, and the exponent of the distance falloff
+    Spot(Point3, Vec3, f32, f32, f32),
+    // Unit direction the light travels in
+    Directional(Vec3),
+}
+
+// Lights that occupy no area and can't be hit by rays, only reached by shadow rays: point and spot
+// lights with an intensity (radiant power per solid angle) and directional lights with the
+// irradiance they deliver to a surface facing them
+#[derive(Clone)]
+pub struct DeltaLight {
+    pub light_type: DeltaLightType,
+    pub color: Rgb,
+}
+
+impl DeltaLight {
+    pub fn point(position: Point3, intensity: Rgb, decay: f32) -> Self {
+        Self {
+            light_type: DeltaLightType::Point(position, decay),
+            color: intensity,
+        }
+    }
+    // Spot light at `position` aimed at `target`, lighting a cone `cone` degrees wide that fades out
+    // over the outermost `falloff` degrees
+    pub fn spot(position: Point3, target: Point3, intensity: Rgb, cone: f32, falloff: f32, decay: f32) -> Self {
+        let half_angle = clamp(cone / 2.0, 0.0, 180.0);
+        let cos_outer = to_radians(half_angle).cos();
+        let cos_inner = to_radians((half_angle - falloff).max(0.0)).cos();
+        Self {
+            light_type: DeltaLightType::Spot(position, (target - position).normalize(), cos_inner, cos_outer, decay),
+            color: intensity,
+        }
+    }
+    pub fn directional(direction: Vec3, irradiance: Rgb) -> Self {
+        Self {
+            light_type: DeltaLightType::Directional(direction.normalize()),
+            color: irradiance,
+        }
+    }
+
+    fn illuminate_point(&self, position: &Point3, decay: f32, point: Point3) -> (Vec3, f32, Rgb) {
+        let to_light = *position - point;
+        let dist = to_light.length();
+        (to_light / dist, dist, self.color / dist.powf(decay))
+    }
+    fn illuminate_spot(&self, position: &Point3, axis: &Vec3, cos_inner: f32, cos_outer: f32, decay: f32, point: Point3) -> (Vec3, f32, Rgb) {
+        let (wi, dist, radiance) = self.illuminate_point(position, decay, point);
+        let cos_theta = -wi.dot(*axis);
+        let t = if cos_theta >= cos_inner { 1.0 } else if cos_inner > cos_outer { clamp((cos_theta - cos_outer) / (cos_inner - cos_outer), 0.0, 1.0) } else { 0.0 };
+        (wi, dist, radiance * (t * t * (3.0 - 2.0 * t)))
+    }
+    fn illuminate_directional(&self, direction: &Vec3) -> (Vec3, f32, Rgb) {
+        (-*direction, f32::MAX, self.color)
+    }
+
+    // Unit direction from `point` towards the light, the distance to it and the light arriving at
+    // `point` from it, ignoring occlusion
+    pub fn illuminate(&self, point: Point3) -> (Vec3, f32, Rgb) {
+        match &self.light_type {
+            DeltaLightType::Point(position, decay) => self.illuminate_point(position, *decay, point),
+            DeltaLightType::Spot(position, axis, cos_inner, cos_outer, decay) => self.illuminate_spot(position, axis, *cos_inner, *cos_outer, *decay, point),
+            DeltaLightType::Directional(direction) => self.illuminate_directional(direction),
+        }
+    }
+}
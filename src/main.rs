@@ -1,18 +1,18 @@
-#[path = "render.rs"] mod render;
-use render::*;
+#[path = "scene.rs"] mod scene;
+use scene::*;
 use macroquad::prelude::{next_frame, clear_background, BLACK, Image, Texture2D, draw_texture_ex, WHITE, DrawTextureParams, Vec2, screen_width, screen_height, Color};
 
 const FILENAME: &str = "out.png";
 
 
-fn default_scene() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize, usize) {
+fn default_scene() -> (Camera, SceneColliders, Background, f32, u32, u32, usize, usize) {
     // Image
     let aspect_ratio = 16.0 / 9.0;
     let image_width = 800;
     let image_height = (image_width as f32 / aspect_ratio) as u32;
     let samples_per_pixel = 500;
     let max_depth = 200;
-    let background = Rgb::new(0.7, 0.8, 1.0);
+    let background = Background::constant(Rgb::new(0.7, 0.8, 1.0));
 
     // Camera
     let look_from = Point3::new(3.0, 3.0, 2.0);
@@ -44,17 +44,17 @@ fn default_scene() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usi
     scene.add(Geometry::sphere(Point3::new(-1.0, 0.0, -1.0), -0.4, mat_left_in));
     scene.add(Geometry::sphere(Point3::new(1.0, 0.0, -1.0), 0.5, mat_right));
 
-    return (cam, scene, Geometry::collider_list(Vec::new()), background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
+    return (cam, scene, background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
 }
 
-fn random_spheres() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize, usize) {
+fn random_spheres() -> (Camera, SceneColliders, Background, f32, u32, u32, usize, usize) {
     // Image
     let aspect_ratio = 3.0 / 2.0;
     let image_width = 600;
     let image_height = (image_width as f32 / aspect_ratio) as u32;
     let samples_per_pixel = 250;
     let max_depth = 50;
-    let background = Rgb::new(0.7, 0.8, 1.0);
+    let background = Background::constant(Rgb::new(0.7, 0.8, 1.0));
 
     // Camera
     let look_from = Point3::new(13.0, 2.0, 3.0);
@@ -111,17 +111,17 @@ fn random_spheres() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, us
 
     scene.add(Geometry::bvh_node(&spheres, 0.0, 1.0, 0, spheres.len()));
     
-    return (cam, scene, Geometry::collider_list(Vec::new()), background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
+    return (cam, scene, background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
 }
 
-fn random_moving_spheres() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize, usize) {
+fn random_moving_spheres() -> (Camera, SceneColliders, Background, f32, u32, u32, usize, usize) {
     // Image
     let aspect_ratio = 3.0 / 2.0;
     let image_width = 400;
     let image_height = (image_width as f32 / aspect_ratio) as u32;
     let samples_per_pixel = 100;
     let max_depth = 50;
-    let background = Rgb::new(0.7, 0.8, 1.0);
+    let background = Background::constant(Rgb::new(0.7, 0.8, 1.0));
 
     // Camera
     let look_from = Point3::new(13.0, 2.0, 3.0);
@@ -178,17 +178,17 @@ fn random_moving_spheres() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32,
     let mat3 = Material::glossy(Rgb::new(0.7, 0.6, 0.5), 0.1);
     scene.add(Geometry::sphere(Point3::new(4.0, 1.0, 0.0), 1.0, mat3));
     
-    return (cam, scene, Geometry::collider_list(Vec::new()), background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
+    return (cam, scene, background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
 }
 
-fn two_spheres() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize, usize) {
+fn two_spheres() -> (Camera, SceneColliders, Background, f32, u32, u32, usize, usize) {
     // Image
     let aspect_ratio = 16.0 / 9.0;
     let image_width = 800;
     let image_height = (image_width as f32 / aspect_ratio) as u32;
     let samples_per_pixel = 100;
     let max_depth = 50;
-    let background = Rgb::new(0.7, 0.8, 1.0);
+    let background = Background::constant(Rgb::new(0.7, 0.8, 1.0));
 
     // Camera
     let look_from = Point3::new(13.0, 2.0, 3.0);
@@ -212,17 +212,17 @@ fn two_spheres() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize
     scene.add(Geometry::sphere(Point3::new(0.0, -8.0, 0.0), 8.0, checker.clone()));
     scene.add(Geometry::sphere(Point3::new(0.0, 8.0, 0.0), 8.0, checker.clone()));
 
-    return (cam, scene, Geometry::collider_list(Vec::new()), background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
+    return (cam, scene, background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
 }
 
-fn two_perlin_spheres() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize, usize) {
+fn two_perlin_spheres() -> (Camera, SceneColliders, Background, f32, u32, u32, usize, usize) {
     // Image
     let aspect_ratio = 16.0 / 9.0;
     let image_width = 600;
     let image_height = (image_width as f32 / aspect_ratio) as u32;
     let samples_per_pixel = 100;
     let max_depth = 50;
-    let background = Rgb::new(0.7, 0.8, 1.0);
+    let background = Background::constant(Rgb::new(0.7, 0.8, 1.0));
 
     // Camera
     let look_from = Point3::new(13.0, 2.0, 3.0);
@@ -246,17 +246,17 @@ fn two_perlin_spheres() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32
     scene.add(Geometry::sphere(Point3::new(0.0, -1000.0, 0.0), 1000.0, pertext.clone()));
     scene.add(Geometry::sphere(Point3::new(0.0, 2.0, 0.0), 2.0, pertext.clone()));
 
-    return (cam, scene, Geometry::collider_list(Vec::new()), background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
+    return (cam, scene, background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
 }
 
-fn earth() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize, usize) {
+fn earth() -> (Camera, SceneColliders, Background, f32, u32, u32, usize, usize) {
     // Image
     let aspect_ratio = 16.0 / 9.0;
     let image_width = 800;
     let image_height = (image_width as f32 / aspect_ratio) as u32;
     let samples_per_pixel = 100;
     let max_depth = 50;
-    let background = Rgb::new(0.7, 0.8, 1.0);
+    let background = Background::constant(Rgb::new(0.7, 0.8, 1.0));
 
     // Camera
     let look_from = Point3::new(13.0, 2.0, 3.0);
@@ -276,20 +276,20 @@ fn earth() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize, usiz
     // Scene
     let mut scene = SceneColliders::new();
 
-    let earth_surface = Material::lambertian(scene.load_image("assets/earthmap.jpeg"));
+    let earth_surface = Material::lambertian(scene.load_image("assets/earthmap.jpeg").unwrap());
     scene.add(Geometry::sphere(Point3::new(0.0, 0.0, 0.0), 2.0, earth_surface));
 
-    return (cam, scene, Geometry::collider_list(Vec::new()), background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
+    return (cam, scene, background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
 }
 
-fn rect_light() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize, usize) {
+fn rect_light() -> (Camera, SceneColliders, Background, f32, u32, u32, usize, usize) {
     // Image
     let aspect_ratio = 16.0 / 9.0;
     let image_width = 800;
     let image_height = (image_width as f32 / aspect_ratio) as u32;
     let samples_per_pixel = 800;
     let max_depth = 50;
-    let background = Rgb::new(0.0, 0.0, 0.0);
+    let background = Background::constant(Rgb::new(0.0, 0.0, 0.0));
 
     // Camera
     let look_from = Point3::new(26.0, 3.0, 6.0);
@@ -314,22 +314,21 @@ fn rect_light() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize,
     scene.add(Geometry::sphere(Point3::new(0.0, -1000.0, 0.0), 1000.0, checker));
     scene.add(Geometry::sphere(Point3::new(0.0, 2.0, 0.0), 2.0, texture));
 
-    let mut lights = Vec::new();
     let difflight = Material::emissive(Rgb::new(5.0, 2.0, 2.0));
-    lights.push(Geometry::xyrect(3.0, 5.0, 1.0, 3.0, -2.0, difflight.clone()));
-    lights.push(Geometry::sphere(Point3::new(0.0, 7.0, 0.0), 1.5, difflight));
+    scene.add(Geometry::xyrect(3.0, 5.0, 1.0, 3.0, -2.0, difflight.clone()));
+    scene.add(Geometry::sphere(Point3::new(0.0, 7.0, 0.0), 1.5, difflight));
     
-    return (cam, scene, Geometry::collider_list(lights), background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
+    return (cam, scene, background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
 }
 
-fn cornell_box() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize, usize) {
+fn cornell_box() -> (Camera, SceneColliders, Background, f32, u32, u32, usize, usize) {
     // Image
     let aspect_ratio = 1.0;
     let image_width = 600;
     let image_height = (image_width as f32 / aspect_ratio) as u32;
     let samples_per_pixel = 5000;
     let max_depth = 200;
-    let background = Rgb::new(0.0, 0.0, 0.0);
+    let background = Background::constant(Rgb::new(0.0, 0.0, 0.0));
 
     // Camera
     let look_from = Point3::new(278.0, 278.0, -800.0);
@@ -356,7 +355,7 @@ fn cornell_box() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize
 
     scene.add(Geometry::yzrect(0.0, 555.0, 0.0, 555.0, 555.0, green));
     scene.add(Geometry::yzrect(0.0, 555.0, 0.0, 555.0, 0.0, red));
-    scene.add(Geometry::xzrect(213.0, 343.0, 227.0, 332.0, 554.0, light.clone()));
+    scene.add(Geometry::xzrect(213.0, 343.0, 227.0, 332.0, 554.0, light));
     scene.add(Geometry::xzrect(0.0, 555.0, 0.0, 555.0, 0.0, white.clone()));
     scene.add(Geometry::xzrect(0.0, 555.0, 0.0, 555.0, 555.0, white.clone()));
     scene.add(Geometry::xyrect(0.0, 555.0, 0.0, 555.0, 555.0, white.clone()));
@@ -364,17 +363,17 @@ fn cornell_box() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize
     scene.add(Geometry::instance_translation(Geometry::instance_rotation(Geometry::cuboid(Point3::new(0.0, 0.0, 0.0), Point3::new(165.0, 330.0, 165.0), white.clone()), Axis::Y, 15.0), Vec3::new(265.0, 0.0, 295.0)));
     scene.add(Geometry::instance_translation(Geometry::instance_rotation(Geometry::cuboid(Point3::new(0.0, 0.0, 0.0), Point3::new(165.0, 165.0, 165.0), white.clone()), Axis::Y, -18.0), Vec3::new(130.0, 0.0, 65.0)));
 
-    return (cam, scene, Geometry::xzrect(213.0, 343.0, 227.0, 332.0, 554.0, white), background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
+    return (cam, scene, background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
 }
 
-fn cornell_smoke() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize, usize) {
+fn cornell_smoke() -> (Camera, SceneColliders, Background, f32, u32, u32, usize, usize) {
     // Image
     let aspect_ratio = 1.0;
     let image_width = 600;
     let image_height = (image_width as f32 / aspect_ratio) as u32;
     let samples_per_pixel = 200;
     let max_depth = 200;
-    let background = Rgb::new(0.0, 0.0, 0.0);
+    let background = Background::constant(Rgb::new(0.0, 0.0, 0.0));
 
     // Camera
     let look_from = Point3::new(278.0, 278.0, -800.0);
@@ -393,7 +392,6 @@ fn cornell_smoke() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usi
 
     // Scene
     let mut scene = SceneColliders::new();
-    let mut lights = Vec::new();
 
     let red = Material::lambertian(Texture::solid_color(Rgb::new(0.65, 0.05, 0.05)));
     let white = Material::lambertian(Texture::solid_color(Rgb::new(0.73, 0.73, 0.73)));
@@ -402,7 +400,7 @@ fn cornell_smoke() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usi
 
     scene.add(Geometry::yzrect(0.0, 555.0, 0.0, 555.0, 555.0, green));
     scene.add(Geometry::yzrect(0.0, 555.0, 0.0, 555.0, 0.0, red));
-    lights.push(Geometry::xzrect(113.0, 443.0, 127.0, 432.0, 554.0, light));
+    scene.add(Geometry::xzrect(113.0, 443.0, 127.0, 432.0, 554.0, light));
     scene.add(Geometry::xzrect(0.0, 555.0, 0.0, 555.0, 0.0, white.clone()));
     scene.add(Geometry::xzrect(0.0, 555.0, 0.0, 555.0, 555.0, white.clone()));
     scene.add(Geometry::xyrect(0.0, 555.0, 0.0, 555.0, 555.0, white.clone()));
@@ -410,17 +408,17 @@ fn cornell_smoke() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usi
     scene.add(Geometry::constant_medium(Geometry::instance_translation(Geometry::instance_rotation(Geometry::cuboid(Point3::new(0.0, 0.0, 0.0), Point3::new(165.0, 330.0, 165.0), white.clone()), Axis::Y, 15.0), Vec3::new(265.0, 0.0, 295.0)), 0.01, Rgb::new(0.0, 0.0, 0.0)));
     scene.add(Geometry::constant_medium(Geometry::instance_translation(Geometry::instance_rotation(Geometry::cuboid(Point3::new(0.0, 0.0, 0.0), Point3::new(165.0, 165.0, 165.0), white.clone()), Axis::Y, -18.0), Vec3::new(130.0, 0.0, 65.0)), 0.01, Rgb::new(1.0, 1.0, 1.0)));
 
-    return (cam, scene, Geometry::collider_list(lights), background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
+    return (cam, scene, background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
 }
 
-fn cornell_pedestal() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize, usize) {
+fn cornell_pedestal() -> (Camera, SceneColliders, Background, f32, u32, u32, usize, usize) {
     // Image
     let aspect_ratio = 1.0;
     let image_width = 600;
     let image_height = (image_width as f32 / aspect_ratio) as u32;
     let samples_per_pixel = 200;
     let max_depth = 100;
-    let background = Rgb::new(0.0, 0.0, 0.0);
+    let background = Background::constant(Rgb::new(0.0, 0.0, 0.0));
 
     // Camera
     let look_from = Point3::new(278.0, 278.0, -800.0);
@@ -439,7 +437,6 @@ fn cornell_pedestal() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32,
 
     // Scene
     let mut scene = SceneColliders::new();
-    let mut lights = Vec::new();
 
     let red = Material::lambertian(Texture::solid_color(Rgb::new(0.65, 0.05, 0.05)));
     let white = Material::lambertian(Texture::solid_color(Rgb::new(0.73, 0.73, 0.73)));
@@ -448,7 +445,7 @@ fn cornell_pedestal() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32,
 
     scene.add(Geometry::yzrect(0.0, 555.0, 0.0, 555.0, 555.0, green));
     scene.add(Geometry::yzrect(0.0, 555.0, 0.0, 555.0, 0.0, red));
-    lights.push(Geometry::xzrect(113.0, 443.0, 127.0, 432.0, 554.0, light.clone()));
+    scene.add(Geometry::xzrect(113.0, 443.0, 127.0, 432.0, 554.0, light.clone()));
     scene.add(Geometry::xzrect(0.0, 555.0, 0.0, 555.0, 0.0, white.clone()));
     scene.add(Geometry::xzrect(0.0, 555.0, 0.0, 555.0, 555.0, white.clone()));
     scene.add(Geometry::xyrect(0.0, 555.0, 0.0, 555.0, 555.0, white.clone()));
@@ -458,19 +455,19 @@ fn cornell_pedestal() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32,
     scene.add(Geometry::instance_translation(Geometry::instance_rotation(Geometry::cuboid(Point3::new(0., 0., 0.), Point3::new(125., 125., 125.), white.clone()), Axis::Y, 45.0), Vec3::new(188., 0., 178.)));
     
     // scene.add(Geometry::triangle(Point3::new(200., 100., 400.), Point3::new(100., 100., 200.), Point3::new(100., 200., 400.), white.clone()));
-    scene.add(Geometry::instance_translation(Geometry::instance_rotation(Geometry::instance_rotation(Geometry::load_obj("assets/objs/suzanne.obj", 80.0, white.clone()), Axis::Y, 145.), Axis::Z, -30.), Vec3::new(270., 200., 178.)));
+    scene.add(Geometry::instance_translation(Geometry::instance_rotation(Geometry::instance_rotation(Geometry::load_obj("assets/objs/suzanne.obj", 80.0, Some(white.clone()), &[], &scene.atlas).unwrap_or_else(|e| fail(e)), Axis::Y, 145.), Axis::Z, -30.), Vec3::new(270., 200., 178.)));
 
-    return (cam, scene, Geometry::collider_list(lights), background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
+    return (cam, scene, background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
 }
 
-fn final_scene() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize, usize) {
+fn final_scene() -> (Camera, SceneColliders, Background, f32, u32, u32, usize, usize) {
     // Image
     let aspect_ratio = 1.0;
     let image_width = 800;
     let image_height = (image_width as f32 / aspect_ratio) as u32;
     let samples_per_pixel = 50;
     let max_depth = 50;
-    let background = Rgb::new(0.0, 0.0, 0.0);
+    let background = Background::constant(Rgb::new(0.0, 0.0, 0.0));
 
     // Camera
     let look_from = Point3::new(478.0, 278.0, -600.0);
@@ -489,7 +486,6 @@ fn final_scene() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize
 
     // Scene
     let mut scene = SceneColliders::new();
-    let mut lights = Vec::new();
 
     let ground = Material::lambertian(Texture::solid_color(Rgb::new(0.48, 0.83, 0.53)));
     let boxes_per_side = 20;
@@ -511,7 +507,7 @@ fn final_scene() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize
     scene.add(Geometry::bvh_node(&boxes, 0.0, 1.0, 0, boxes.len()));
     
     let light = Material::emissive(Rgb::new(7.0, 7.0, 7.0));
-    lights.push(Geometry::xzrect(123.0, 423.0, 147.0, 412.0, 554.0, light.clone()));
+    scene.add(Geometry::xzrect(123.0, 423.0, 147.0, 412.0, 554.0, light.clone()));
 
     scene.add(Geometry::sphere(Point3::new(260.0, 150.0, 45.0), 50.0, Material::dielectric(1.5)));
     scene.add(Geometry::sphere(Point3::new(0.0, 150.0, 145.0), 50.0, Material::glossy(Rgb::new(0.8, 0.8, 0.9), 1.0)));
@@ -522,7 +518,7 @@ fn final_scene() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize
     let boundary = Geometry::sphere(Point3::origin(), 5000.0, Material::dielectric(1.5));
     scene.add(Geometry::constant_medium(boundary, 0.0001, Rgb::new(1.0, 1.0, 1.0)));
 
-    let emat = Material::lambertian(scene.load_image("assets/earthmap.jpeg"));
+    let emat = Material::lambertian(scene.load_image("assets/earthmap.jpeg").unwrap());
     scene.add(Geometry::sphere(Point3::new(400.0, 200.0, 400.0), 100.0, emat));
     // let emat = Material::glossy(Rgb::new(0.8, 0.8, 0.8), 0.0);
     // scene.add(Geometry::sphere(Point3::new(400.0, 200.0, 400.0), 100.0, emat));
@@ -537,25 +533,361 @@ fn final_scene() -> (Camera, SceneColliders, Geometry, Rgb, f32, u32, u32, usize
     }
     scene.add(Geometry::instance_translation(Geometry::instance_rotation(Geometry::bvh_node(&spheres, 0.0, 1.0, 0, spheres.len()), Axis::Y, 15.0), Vec3::new(-100.0, 270.0, 395.0)));
 
-    return (cam, scene, Geometry::collider_list(lights), background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
+    return (cam, scene, background, aspect_ratio, image_width, image_height, samples_per_pixel, max_depth);
 }
 
 
 
 
+// Function setting up one of the scenes that can be rendered by name
+type SceneBuilder = fn() -> Scene;
+
+const BUILTIN_SCENES: [(&str, SceneBuilder); 11] = [
+    ("default", default_scene),
+    ("random_spheres", random_spheres),
+    ("random_moving_spheres", random_moving_spheres),
+    ("two_spheres", two_spheres),
+    ("two_perlin_spheres", two_perlin_spheres),
+    ("earth", earth),
+    ("rect_light", rect_light),
+    ("cornell_box", cornell_box),
+    ("cornell_smoke", cornell_smoke),
+    ("cornell_pedestal", cornell_pedestal),
+    ("final_scene", final_scene),
+];
+
+const USAGE: &str = "Usage: epic_raytracer [OPTIONS] [SCENE]
+
+SCENE is either the name of a built-in scene or the path to a scene description file (default: cornell_box).
+
+Options:
+  -o, --output <PATH>    Output image path, may be given several times (default: output/out.png)
+                         .exr, .hdr and .pfm files store linear radiance, other formats go through the color pipeline
+  -e, --exposure <STOPS> Exposure adjustment applied before tone mapping (default: 0)
+      --tonemap <OP>     Tone mapping operator: clamp, reinhard, reinhard:<white>, aces (default: clamp)
+      --transfer <FN>    Output transfer function: srgb, gamma<g>, linear (default: srgb)
+  -W, --width <PX>       Image width; keeps the scene's aspect ratio unless --height is also given
+  -H, --height <PX>      Image height; keeps the scene's aspect ratio unless --width is also given
+  -s, --samples <N>      Samples per pixel; the per pixel maximum when sampling adaptively
+  -d, --depth <N>        Maximum ray bounce depth
+  -t, --threads <N>      Number of render threads (default: number of CPUs)
+      --tile-size <PX>   Edge length of the square tiles handed to the render threads (default: 32)
+      --tile-order <O>   Order in which tiles are rendered: scanline, spiral, hilbert (default: scanline)
+      --pass-samples <N> Samples per pixel rendered in each progressive pass, 0 for a single pass (default: 16)
+      --checkpoint <PATH>
+                         Periodically save the accumulated radiance and sample counts to PATH
+      --checkpoint-interval <SECS>
+                         Minimum time between checkpoints (default: 300)
+      --resume <PATH>    Continue a render from a checkpoint up to the requested samples per pixel;
+                         checkpoints are written back to PATH unless --checkpoint is given
+      --adaptive <ERR>   Stop sampling pixels once the standard error of their luminance relative to
+                         its mean drops below ERR, e.g. 0.01 (default: 0, sample every pixel fully)
+      --min-samples <N>  Samples every pixel takes before it may stop adaptively (default: 16)
+      --sample-map <PATH>
+                         Also write an image of the per pixel sample counts, scaled to the maximum
+      --environment <PATH>
+                         Light the scene with an equirectangular environment map (e.g. an .hdr sky)
+                         instead of its background color
+      --environment-intensity <K>
+                         Scale of the environment map's radiance (default: 1)
+      --environment-rotate <DEG>
+                         Turn the environment map about the vertical axis (default: 0)
+      --sky <ELEV>[,<AZIM>[,<TURBIDITY>]]
+                         Light the scene with a procedural daylight sky and sun at ELEV degrees above
+                         the horizon and azimuth AZIM (0 along +z, 90 along +x; default: 0) with the
+                         given turbidity (default: 3). Radiance is in kcd/m^2, try --exposure -5
+      --spectral         Trace light at sampled wavelengths instead of in RGB, so dispersive glass splits
+                         white light into colors (RGB colors are converted to smooth spectra)
+      --sampler <S>      Sample generator: independent, stratified, halton, sobol (default: independent)
+      --seed <N>         Seed for the random number generator; renders with the same seed are identical (default: 0)
+  -l, --list             List the built-in scenes
+  -h, --help             Print this message";
+
+
+struct CliArgs {
+    scene: String,
+    outputs: Vec<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    samples: Option<usize>,
+    depth: Option<usize>,
+    threads: Option<usize>,
+    tile_size: Option<u32>,
+    tile_order: Option<TileOrder>,
+    pass_samples: Option<usize>,
+    checkpoint: Option<String>,
+    checkpoint_interval: Option<u64>,
+    resume: Option<String>,
+    adaptive_threshold: Option<f32>,
+    min_samples: Option<usize>,
+    sample_map: Option<String>,
+    seed: Option<u64>,
+    sampler: Option<SamplerType>,
+    environment: Option<String>,
+    environment_intensity: f32,
+    environment_rotation: f32,
+    sky: Option<(f32, f32, f32)>,
+    spectral: bool,
+    color: ColorPipeline,
+}
+
+impl CliArgs {
+    fn parse(args: Vec<String>) -> Result<Self, String> {
+        let mut cli = Self {
+            scene: "cornell_box".to_string(),
+            outputs: Vec::new(),
+            width: None,
+            height: None,
+            samples: None,
+            depth: None,
+            threads: None,
+            tile_size: None,
+            tile_order: None,
+            pass_samples: None,
+            checkpoint: None,
+            checkpoint_interval: None,
+            resume: None,
+            adaptive_threshold: None,
+            min_samples: None,
+            sample_map: None,
+            seed: None,
+            sampler: None,
+            environment: None,
+            environment_intensity: 1.0,
+            environment_rotation: 0.0,
+            sky: None,
+            spectral: false,
+            color: ColorPipeline::default(),
+        };
+        let mut scene_given = false;
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                },
+                "-l" | "--list" => {
+                    for (name, _) in BUILTIN_SCENES.iter() {
+                        println!("{}", name);
+                    }
+                    std::process::exit(0);
+                },
+                "-o" | "--output" => cli.outputs.push(Self::value(&arg, args.next())?),
+                "-W" | "--width" => cli.width = Some(Self::number(&arg, args.next())?),
+                "-H" | "--height" => cli.height = Some(Self::number(&arg, args.next())?),
+                "-s" | "--samples" => cli.samples = Some(Self::number(&arg, args.next())?),
+                "-d" | "--depth" => cli.depth = Some(Self::number(&arg, args.next())?),
+                "-t" | "--threads" => cli.threads = Some(Self::number(&arg, args.next())?),
+                "--tile-size" => cli.tile_size = Some(Self::number(&arg, args.next())?),
+                "--tile-order" => {
+                    let value = Self::value(&arg, args.next())?;
+                    cli.tile_order = Some(TileOrder::parse(&value).ok_or(format!("unknown tile order '{}'", value))?);
+                },
+                "--pass-samples" => cli.pass_samples = Some(Self::number(&arg, args.next())?),
+                "--checkpoint" => cli.checkpoint = Some(Self::value(&arg, args.next())?),
+                "--checkpoint-interval" => cli.checkpoint_interval = Some(Self::number(&arg, args.next())?),
+                "--resume" => cli.resume = Some(Self::value(&arg, args.next())?),
+                "--adaptive" => cli.adaptive_threshold = Some(Self::number(&arg, args.next())?),
+                "--min-samples" => cli.min_samples = Some(Self::number(&arg, args.next())?),
+                "--sample-map" => cli.sample_map = Some(Self::value(&arg, args.next())?),
+                "--sampler" => {
+                    let value = Self::value(&arg, args.next())?;
+                    cli.sampler = Some(SamplerType::parse(&value).ok_or(format!("unknown sampler '{}'", value))?);
+                },
+                "--seed" => cli.seed = Some(Self::number(&arg, args.next())?),
+                "--environment" => cli.environment = Some(Self::value(&arg, args.next())?),
+                "--environment-intensity" => cli.environment_intensity = Self::number(&arg, args.next())?,
+                "--environment-rotate" => cli.environment_rotation = Self::number(&arg, args.next())?,
+                "--sky" => {
+                    let value = Self::value(&arg, args.next())?;
+                    let parts: Vec<&str> = value.split(',').collect();
+                    let numbers: Vec<f32> = parts.iter().filter_map(|p| p.trim().parse().ok()).collect();
+                    if parts.len() > 3 || numbers.len() != parts.len() {
+                        return Err(format!("invalid value '{}' for '{}'", value, arg));
+                    }
+                    cli.sky = Some((numbers[0], *numbers.get(1).unwrap_or(&0.0), *numbers.get(2).unwrap_or(&3.0)));
+                },
+                "--spectral" => cli.spectral = true,
+                "-e" | "--exposure" => cli.color.exposure = Self::number(&arg, args.next())?,
+                "--tonemap" => {
+                    let value = Self::value(&arg, args.next())?;
+                    cli.color.operator = ColorPipeline::parse_operator(&value).ok_or(format!("unknown tone mapping operator '{}'", value))?;
+                },
+                "--transfer" => {
+                    let value = Self::value(&arg, args.next())?;
+                    cli.color.transfer = ColorPipeline::parse_transfer(&value).ok_or(format!("unknown transfer function '{}'", value))?;
+                },
+                _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+                _ => {
+                    if scene_given {
+                        return Err(format!("unexpected argument '{}'", arg));
+                    }
+                    cli.scene = arg;
+                    scene_given = true;
+                }
+            }
+        }
+        if cli.environment.is_some() && cli.sky.is_some() {
+            return Err("'--environment' and '--sky' cannot be used together".to_string());
+        }
+        if let Some((_, _, turbidity)) = cli.sky {
+            if turbidity < 1.7 {
+                return Err(format!("sky turbidity must be at least 1.7, found {}", turbidity));
+            }
+        }
+        if cli.outputs.is_empty() {
+            cli.outputs.push(format!("output/{}", FILENAME));
+        }
+        for output in cli.outputs.iter().chain(cli.sample_map.iter()) {
+            OutputFormat::from_path(output)?;
+        }
+        Ok(cli)
+    }
+
+    fn value(flag: &str, value: Option<String>) -> Result<String, String> {
+        value.ok_or(format!("missing value for '{}'", flag))
+    }
+    fn number<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+        let value = Self::value(flag, value)?;
+        match value.parse::<T>() {
+            Ok(n) => Ok(n),
+            Err(_) => Err(format!("invalid value '{}' for '{}'", value, flag))
+        }
+    }
+}
+
+
+fn fail(msg: String) -> ! {
+    eprintln!("error: {}", msg);
+    std::process::exit(1);
+}
+
 fn main() {
-    let (cam, scene, lights, background, _aspect_ratio, img_width, img_height, samples_per_pixel, max_depth) = cornell_box();
-    let imgbuf = render_multi(
+    let cli = CliArgs::parse(std::env::args().skip(1).collect()).unwrap_or_else(|e| fail(format!("{}\n\n{}", e, USAGE)));
+
+    let seed = cli.seed.unwrap_or(0);
+    seed_random(seed);
+
+    let (mut cam, mut scene, mut background, aspect_ratio, mut img_width, mut img_height, mut samples_per_pixel, mut max_depth) = match BUILTIN_SCENES.iter().find(|(name, _)| *name == cli.scene) {
+        Some((_, build)) => build(),
+        None => load_scene(&cli.scene).unwrap_or_else(|e| fail(format!("failed to load scene: {}", e)))
+    };
+    scene.build_lights();
+    if let Some(path) = &cli.environment {
+        if !std::path::Path::new(path).is_file() {
+            fail(format!("environment map '{}' does not exist", path));
+        }
+        background = Background::environment_map(&scene.atlas, path, cli.environment_intensity, &[(Axis::Y, cli.environment_rotation)]).unwrap_or_else(|e| fail(format!("failed to load environment map: {}", e)));
+    }
+    if let Some((elevation, azimuth, turbidity)) = cli.sky {
+        background = Background::sky(elevation, azimuth, turbidity, 1.0, 0.53);
+    }
+
+    match (cli.width, cli.height) {
+        (Some(w), Some(h)) => {
+            img_width = w;
+            img_height = h;
+            cam = cam.with_aspect_ratio(w as f32 / h as f32);
+        },
+        (Some(w), None) => {
+            img_width = w;
+            img_height = (w as f32 / aspect_ratio) as u32;
+        },
+        (None, Some(h)) => {
+            img_height = h;
+            img_width = (h as f32 * aspect_ratio) as u32;
+        },
+        (None, None) => ()
+    }
+    if img_width == 0 || img_height == 0 {
+        fail(format!("invalid image size {}x{}", img_width, img_height));
+    }
+    if let Some(samples) = cli.samples { samples_per_pixel = samples };
+    if let Some(depth) = cli.depth { max_depth = depth };
+
+    let mut settings = RenderSettings::new(img_width, img_height, samples_per_pixel, max_depth);
+    if let Some(threads) = cli.threads { settings.n_threads = threads.max(1) };
+    if let Some(tile_size) = cli.tile_size { settings.tile_size = tile_size.max(1) };
+    if let Some(tile_order) = cli.tile_order { settings.tile_order = tile_order };
+    if let Some(pass_samples) = cli.pass_samples { settings.pass_samples = pass_samples };
+    if let Some(interval) = cli.checkpoint_interval { settings.checkpoint_interval = interval };
+    if let Some(threshold) = cli.adaptive_threshold { settings.adaptive_threshold = threshold.max(0.0) };
+    if let Some(min_samples) = cli.min_samples { settings.min_samples = min_samples };
+    settings.checkpoint = cli.checkpoint.clone().or(cli.resume.clone());
+    settings.seed = seed;
+    if let Some(sampler) = cli.sampler { settings.sampler = sampler };
+    settings.color = cli.color.clone();
+    settings.spectral = cli.spectral;
+
+    let film = match &cli.resume {
+        Some(path) => {
+            let film = Film::load_checkpoint(path).unwrap_or_else(|e| fail(e));
+            if film.width != settings.img_width || film.height != settings.img_height {
+                fail(format!("{}: checkpoint is {}x{} but the render is {}x{}", path, film.width, film.height, settings.img_width, settings.img_height));
+            }
+            film
+        },
+        None => Film::new(settings.img_width, settings.img_height)
+    };
+
+    println!("{}:", cli.scene);
+    println!("image size: {}x{}", settings.img_width, settings.img_height);
+    println!("samples per pixel: {}", settings.samples_per_pixel);
+    if settings.adaptive_threshold > 0.0 {
+        println!("adaptive: relative error {} (at least {} samples per pixel)", settings.adaptive_threshold, settings.min_samples);
+    }
+    println!("max depth: {}", settings.max_depth);
+    println!("lights: {}", scene.light_count());
+    if !scene.delta_lights.is_empty() {
+        println!("point, spot and directional lights: {}", scene.delta_lights.len());
+    }
+    if let Some(path) = &cli.environment {
+        println!("environment: {} (intensity {})", path, cli.environment_intensity);
+    }
+    if let Some((elevation, azimuth, turbidity)) = cli.sky {
+        println!("sky: sun at {} degrees elevation, {} degrees azimuth, turbidity {}", elevation, azimuth, turbidity);
+    }
+    if settings.spectral {
+        println!("spectral: {} to {} nm", LAMBDA_MIN, LAMBDA_MAX);
+    }
+    println!("threads: {}", settings.n_threads);
+    if let Some(path) = &cli.resume {
+        println!("resuming: {} ({} samples per pixel done)", path, film.min_samples());
+    }
+    if let Some(path) = &settings.checkpoint {
+        println!("checkpoint: {} (every {}s)", path, settings.checkpoint_interval);
+    }
+    println!("sampler: {}", settings.sampler.name());
+    println!("seed: {}", settings.seed);
+    for output in cli.outputs.iter() {
+        let kind = if OutputFormat::from_path(output).unwrap().is_hdr() { "linear" } else { "display encoded" };
+        println!("output: {} ({})", output, kind);
+    }
+
+    let film = render_multi(
         scene,
-        lights,
         cam,
         background,
-        max_depth,
-        samples_per_pixel,
-        img_width, 
-        img_height,
+        &settings,
+        film,
     );
+    let imgbuf = film.to_image();
+    let mut images: Vec<_> = cli.outputs.iter().map(|output| (output, imgbuf.clone(), settings.color.clone())).collect();
+    if let Some(path) = &cli.sample_map {
+        let average = film.pixels.iter().map(|p| p.samples as f64).sum::<f64>() / film.pixels.len() as f64;
+        println!("average samples per pixel: {:.1} (max {})", average, film.max_samples());
+        // Sample counts are data rather than radiance, so they are written without a display transform
+        images.push((path, film.sample_count_image(), ColorPipeline::new(0.0, ToneMapOperator::Clamp, TransferFunction::Linear)));
+    }
 
-    imgbuf.save(&format!("output/{}", FILENAME)).unwrap();
+    for (output, imgbuf, pipeline) in images.iter() {
+        if let Some(parent) = std::path::Path::new(output).parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent).unwrap_or_else(|e| fail(format!("{}: {}", parent.display(), e)));
+            }
+        }
+        save_image(imgbuf, output, pipeline).unwrap_or_else(|e| fail(e));
+    }
 }
-
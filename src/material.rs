@@ -1,5 +1,5 @@
-#[path = "texture.rs"] mod texture;
-pub use texture::*;
+#[path = "microfacet.rs"] mod microfacet;
+pub use microfacet::*;
 use std::{sync::{Arc, Mutex}, fs::DirEntry};
 
 
@@ -12,6 +12,10 @@ pub struct HitRecord {
     pub t: f32,
     pub u: f32,
     pub v: f32,
+    // Barycentric coordinates of the hit with respect to the second and third corners, for triangles
+    pub barycentric: (f32, f32),
+    // Vertex color interpolated at the hit, for meshes that have them
+    pub color: Rgb,
     pub front_face: bool
 }
 
@@ -24,6 +28,8 @@ impl HitRecord {
             t: t,
             u: 0.0,
             v: 0.0,
+            barycentric: (0.0, 0.0),
+            color: Rgb::new(1.0, 1.0, 1.0),
             front_face: false
         }
     }
@@ -31,6 +37,16 @@ impl HitRecord {
         self.front_face = r.direction.dot(out_normal) < 0.0;
         self.normal = if self.front_face { out_normal } else { -out_normal };
     }
+    // Replaces the normal with an interpolated one, turned to the side `set_face_normal` picked. Vertex
+    // normals that point against the winding of their triangle are flipped to agree with it.
+    pub fn set_shading_normal(&mut self, shading_normal: Vec3, face_normal: Vec3) {
+        if shading_normal.length_squared() <= 0.0 {
+            return;
+        }
+        let n = shading_normal.normalize();
+        let n = if n.dot(face_normal) < 0.0 { -n } else { n };
+        self.normal = if self.front_face { n } else { -n };
+    }
     pub fn set_uv(&mut self, uv: (f32, f32)) {
         self.u = uv.0;
         self.v = uv.1;
@@ -39,13 +55,164 @@ impl HitRecord {
 
 
 
+// Scalar parameters of the principled BSDF (after Burley, "Physically Based Shading at Disney",
+// 2012, and its 2015 extension to transmission). All of them range over [0, 1] except `ior`.
+#[derive(Clone, Copy)]
+pub struct PrincipledParams {
+    pub metallic: f32,
+    pub roughness: f32,
+    pub specular: f32,
+    pub specular_tint: f32,
+    pub sheen: f32,
+    pub sheen_tint: f32,
+    pub clearcoat: f32,
+    pub clearcoat_gloss: f32,
+    pub transmission: f32,
+    pub ior: f32,
+}
+
+impl PrincipledParams {
+    pub fn new() -> Self {
+        Self {
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            transmission: 0.0,
+            ior: 1.5,
+        }
+    }
+
+    // Weights of the diffuse, specular, transmission and clearcoat lobes in the mix. They depend only
+    // on the scalar parameters, so they double as the lobe selection probabilities when sampling.
+    fn lobe_weights(&self) -> [f32; 4] {
+        let dielectric = 1.0 - self.metallic;
+        [
+            dielectric * (1.0 - self.transmission),
+            1.0 - self.transmission * dielectric,
+            self.transmission * dielectric,
+            0.25 * self.clearcoat,
+        ]
+    }
+}
+
+
+// Index of refraction of a dielectric as a function of wavelength, with the coefficients in terms of
+// the wavelength in micrometres as they are usually tabulated
+#[derive(Clone, Copy)]
+pub enum Dispersion {
+    // n = a + b / lambda^2
+    Cauchy(f32, f32),
+    // n^2 = 1 + sum of b_i lambda^2 / (lambda^2 - c_i)
+    Sellmeier([f32; 3], [f32; 3]),
+}
+
+impl Dispersion {
+    // Sellmeier coefficients of some common optical materials, written as published even where f32
+    // can't hold every digit
+    #[allow(clippy::excessive_precision)]
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "bk7" => Some(Dispersion::Sellmeier([1.03961212, 0.231792344, 1.01046945], [0.00600069867, 0.0200179144, 103.560653])),
+            "fused_silica" => Some(Dispersion::Sellmeier([0.6961663, 0.4079426, 0.8974794], [0.00467914826, 0.0135120631, 97.9340025])),
+            "flint" => Some(Dispersion::Sellmeier([1.73759695, 0.313747346, 1.89878101], [0.013188707, 0.0623068142, 155.23629])),
+            "diamond" => Some(Dispersion::Sellmeier([0.3306, 4.3356, 0.0], [0.030625, 0.011236, 0.0])),
+            _ => None
+        }
+    }
+
+    pub fn ior(&self, wavelength: f32) -> f32 {
+        let l2 = (wavelength * 1e-3) * (wavelength * 1e-3);
+        match self {
+            Dispersion::Cauchy(a, b) => a + b / l2,
+            Dispersion::Sellmeier(b, c) => (1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f32>()).sqrt(),
+        }
+    }
+}
+
+// Wavelength at which dispersive materials are evaluated when rendering in RGB: the sodium D line the
+// refractive indices of glasses are quoted at
+const RGB_WAVELENGTH: f32 = 589.3;
+
+
+// Faces of a surface that emit light, relative to its outward normal (the positive side of rectangles)
+#[derive(Clone, Copy, PartialEq)]
+pub enum EmissionSides {
+    Front,
+    Back,
+    Both,
+}
+
+// Luminous efficacy used to turn lumens into watts: that of monochromatic light at 555 nm
+pub const LUMENS_PER_WATT: f32 = 683.0;
+
+// Scaling of an emissive texture. A `power` in watts stands for the total the object carrying the
+// material should emit, and is turned into `intensity` once the object's area is known.
+#[derive(Clone, Copy)]
+pub struct EmissionParams {
+    pub intensity: f32,
+    pub tint: Rgb,
+    pub power: Option<f32>,
+    pub sides: EmissionSides,
+}
+
+impl EmissionParams {
+    pub fn new() -> Self {
+        Self {
+            intensity: 1.0,
+            tint: Rgb::new(1.0, 1.0, 1.0),
+            power: None,
+            sides: EmissionSides::Both,
+        }
+    }
+
+    fn side_count(&self) -> f32 {
+        if self.sides == EmissionSides::Both { 2.0 } else { 1.0 }
+    }
+}
+
+
+// A direction sampled by a material, with its weight (BSDF * cos / pdf) and the density it was drawn
+// with. Specular records carry only their weight: their pdf is meaningless.
+#[derive(Clone)]
+pub struct ScatterRecord {
+    pub scattered: Ray,
+    pub attenuation: Rgb,
+    pub pdf: f32,
+    pub is_specular: bool,
+}
+
+impl ScatterRecord {
+    pub fn new(is_specular: bool) -> Self {
+        Self {
+            scattered: Ray::new(Vec3::origin(), Vec3::origin(), 0.0),
+            attenuation: Rgb::origin(),
+            pdf: 0.0,
+            is_specular: is_specular,
+        }
+    }
+}
+
+
 #[derive(Clone)]
 pub enum MaterialType {
     Lambertian(Texture),
     Glossy(Rgb, f32),
     Dielectric(f32),
     Isotropic(Texture),
-    Emissive(Texture)
+    // Texture, its scaling and the average luminance of the texture
+    Emissive(Texture, EmissionParams, f32),
+    Principled(Texture, PrincipledParams),
+    // GGX microfacet metal with a complex index of refraction (eta, k) and a roughness
+    Conductor(Rgb, Rgb, f32),
+    // GGX microfacet dielectric with an index of refraction and a roughness
+    RoughDielectric(f32, f32),
+    // Smooth dielectric whose index of refraction depends on the wavelength
+    DispersiveDielectric(Dispersion),
 }
 
 
@@ -75,18 +242,69 @@ impl Material {
             mat_type: MaterialType::Isotropic(Texture::solid_color(color)),
         }
     }
+    pub fn principled(base_color: Texture, params: PrincipledParams) -> Self {
+        Self {
+            mat_type: MaterialType::Principled(base_color, params),
+        }
+    }
+    pub fn conductor(eta: Rgb, k: Rgb, roughness: f32) -> Self {
+        Self {
+            mat_type: MaterialType::Conductor(eta, k, roughness),
+        }
+    }
+    // Conductor with the measured complex index of refraction of a common metal, sampled at the
+    // wavelengths of the red, green and blue primaries
+    pub fn conductor_preset(name: &str, roughness: f32) -> Option<Self> {
+        let (eta, k) = match name {
+            "gold" => (Rgb::new(0.143119, 0.374957, 1.44248), Rgb::new(3.98316, 2.38572, 1.60322)),
+            "copper" => (Rgb::new(0.200438, 0.924033, 1.10221), Rgb::new(3.91295, 2.45285, 2.14219)),
+            "aluminium" | "aluminum" => (Rgb::new(1.65746, 0.880369, 0.521229), Rgb::new(9.22387, 6.26952, 4.837)),
+            "silver" => (Rgb::new(0.155265, 0.116723, 0.138342), Rgb::new(4.82835, 3.12225, 2.14696)),
+            _ => return None
+        };
+        Some(Self::conductor(eta, k, roughness))
+    }
+    pub fn rough_dielectric(refraction_index: f32, roughness: f32) -> Self {
+        Self {
+            mat_type: MaterialType::RoughDielectric(refraction_index, roughness),
+        }
+    }
+    pub fn dispersive_dielectric(dispersion: Dispersion) -> Self {
+        Self {
+            mat_type: MaterialType::DispersiveDielectric(dispersion),
+        }
+    }
     pub fn emissive(color: Rgb) -> Self {
         Self {
-            mat_type: MaterialType::Emissive(Texture::solid_color(color)),
+            mat_type: MaterialType::Emissive(Texture::solid_color(color), EmissionParams::new(), 0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z),
+        }
+    }
+    pub fn emissive_texture(texture: Texture, params: EmissionParams, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Self {
+        let average = texture.average_color(atlas);
+        Self {
+            mat_type: MaterialType::Emissive(texture, params, 0.2126 * average.x + 0.7152 * average.y + 0.0722 * average.z),
+        }
+    }
+    // Sets the intensity of an emissive material given a power so that an object of this total area
+    // emits that power. A Lambertian emitter of radiance L sends out pi * L watts per square metre
+    // from each face.
+    pub fn resolve_emission_power(&mut self, area: f32) {
+        if let MaterialType::Emissive(_texture, params, average) = &mut self.mat_type {
+            if let Some(power) = params.power {
+                let tint = 0.2126 * params.tint.x + 0.7152 * params.tint.y + 0.0722 * params.tint.z;
+                let radiance = PI * area * params.side_count() * *average * tint;
+                params.intensity = if radiance > 0.0 { power / radiance } else { 0.0 };
+                params.power = None;
+            }
         }
     }
 
     
-    fn scatter_lambertian(&self, albedo: &Texture, r_in: Ray, attenuation: &mut Rgb, rec: HitRecord, scattered: &mut Ray, pdf: &mut f32, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> bool {
+    fn scatter_lambertian(&self, albedo: &Texture, r_in: Ray, attenuation: &mut Rgb, rec: HitRecord, scattered: &mut Ray, pdf: &mut f32, atlas: &Arc<Mutex<ImageTextureAtlas>>, sampler: &mut Sampler) -> bool {
         let uvw = ONB::build_from_w(rec.normal);
-        let dir = uvw.local(random_cosin_direction());
+        let dir = uvw.local(random_cosin_direction(sampler));
         scattered.reset(rec.point, dir.normalize(), r_in.time);
-        attenuation.set_to(albedo.get_color(rec.u, rec.v, rec.point, atlas));
+        attenuation.set_to(albedo.get_color(rec.u, rec.v, rec.point, rec.color, atlas));
         *pdf = uvw.w.dot(scattered.direction) / PI;
         return true;
     }
@@ -118,14 +336,14 @@ impl Material {
         r0 *= r0;
         return r0 + (1.0 - r0) * (1.0 - cosin).powi(5);
     }
-    fn scatter_glossy(&self, color: &Rgb, fuzz: &f32, r_in: Ray, attenuation: &mut Rgb, rec: HitRecord, scattered: &mut Ray) -> bool {
+    fn scatter_glossy(&self, color: &Rgb, fuzz: &f32, r_in: Ray, attenuation: &mut Rgb, rec: HitRecord, scattered: &mut Ray, sampler: &mut Sampler) -> bool {
         let reflected = reflect(r_in.direction.normalize(), rec.normal);
-        scattered.reset(rec.point, reflected + random_in_unit_sphere() * *fuzz, r_in.time);
+        scattered.reset(rec.point, reflected + random_in_unit_sphere(sampler) * *fuzz, r_in.time);
         attenuation.set_to(*color);
         return scattered.direction.dot(rec.normal) > 0.0;
     }
     
-    fn scatter_dielectric(&self, refraction_index: &f32, r_in: Ray, attenuation: &mut Rgb, rec: HitRecord, scattered: &mut Ray) -> bool {
+    fn scatter_dielectric(&self, refraction_index: &f32, r_in: Ray, attenuation: &mut Rgb, rec: HitRecord, scattered: &mut Ray, sampler: &mut Sampler) -> bool {
         attenuation.set_to(Vec3::new(1.0, 1.0, 1.0));
         let refraction_ratio = if rec.front_face { 1.0 / *refraction_index } else { *refraction_index };
 
@@ -136,7 +354,7 @@ impl Material {
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
         let dir;
 
-        if cannot_refract || (self.reflectance(cos_theta, refraction_ratio) > random()) {
+        if cannot_refract || (self.reflectance(cos_theta, refraction_ratio) > sampler.get_1d()) {
             dir = reflect(unit_dir, rec.normal);
         }
         else {
@@ -147,9 +365,10 @@ impl Material {
         return true;
     }
 
-    fn scatter_isotropic(&self, albedo: &Texture, r_in: Ray, attenuation: &mut Rgb, rec: HitRecord, scattered: &mut Ray, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> bool {
-        scattered.reset(rec.point, random_in_unit_sphere(), r_in.time);
-        attenuation.set_to(albedo.get_color(rec.u, rec.v, rec.point, atlas));
+    fn scatter_isotropic(&self, albedo: &Texture, r_in: Ray, attenuation: &mut Rgb, rec: HitRecord, scattered: &mut Ray, pdf: &mut f32, atlas: &Arc<Mutex<ImageTextureAtlas>>, sampler: &mut Sampler) -> bool {
+        scattered.reset(rec.point, random_in_unit_sphere(sampler), r_in.time);
+        attenuation.set_to(albedo.get_color(rec.u, rec.v, rec.point, rec.color, atlas));
+        *pdf = 1.0 / (4.0 * PI);
         return true;
     }
 
@@ -157,25 +376,272 @@ impl Material {
         return false;
     }
 
+    // Microfacet materials are evaluated and sampled in the frame of the shading normal, which always
+    // faces the incoming ray; `wo` points back along that ray.
+    fn shading_frame(&self, r_in: Ray, rec: &HitRecord) -> (ONB, Vec3) {
+        let uvw = ONB::build_from_w(rec.normal);
+        let wo = uvw.to_local(-r_in.direction.normalize());
+        (uvw, wo)
+    }
+    // Relative index of refraction across the surface as seen from the incoming ray
+    fn relative_eta(&self, ior: f32, rec: &HitRecord) -> f32 {
+        if rec.front_face { ior } else { 1.0 / ior }
+    }
+    // Scatters along the material's own sampling distribution, weighting by BSDF * cos / pdf
+    fn scatter_sampled(&self, r_in: Ray, attenuation: &mut Rgb, rec: HitRecord, scattered: &mut Ray, pdf: &mut f32, atlas: &Arc<Mutex<ImageTextureAtlas>>, sampler: &mut Sampler) -> bool {
+        let dir = self.sample_direction(r_in, &rec, sampler);
+        scattered.reset(rec.point, dir, r_in.time);
+        *pdf = self.scattering_pdf(r_in, rec.clone(), scattered);
+        attenuation.set_to(if *pdf > 0.0 { self.eval(r_in, &rec, scattered, atlas) / *pdf } else { Rgb::origin() });
+        return true;
+    }
 
-    pub fn scatter(&self, r_in: Ray, attenuation: &mut Rgb, rec: HitRecord, scattered: &mut Ray, pdf: &mut f32, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> bool {
-        match &self.mat_type {
-            MaterialType::Lambertian(albedo) => self.scatter_lambertian(albedo, r_in, attenuation, rec, scattered, pdf, atlas),
-            MaterialType::Glossy(color, fuzz) => self.scatter_glossy(color, fuzz, r_in, attenuation, rec, scattered),
-            MaterialType::Dielectric(refraction_index) => self.scatter_dielectric(refraction_index, r_in, attenuation, rec, scattered),
-            MaterialType::Isotropic(albedo) => self.scatter_isotropic(albedo, r_in, attenuation, rec, scattered, atlas),
-            MaterialType::Emissive(_albedo) => self.scatter_emissive(r_in, attenuation, rec, scattered)
+    fn eval_conductor(&self, eta: &Rgb, k: &Rgb, roughness: f32, r_in: Ray, rec: &HitRecord, direction: Vec3) -> Rgb {
+        let (uvw, wo) = self.shading_frame(r_in, rec);
+        let wi = uvw.to_local(direction.normalize());
+        if wo.z <= 0.0 || !same_hemisphere(wo, wi) {
+            return Rgb::origin();
         }
+        let alpha = roughness_to_alpha(roughness);
+        let wm = (wo + wi).normalize();
+        let spec = ggx_d(wm, alpha) * ggx_g(wo, wi, alpha) / (4.0 * wo.z);
+        return fresnel_complex(wo.dot(wm), *eta, *k) * spec;
+    }
+    fn sample_conductor(&self, roughness: f32, r_in: Ray, rec: &HitRecord, sampler: &mut Sampler) -> Vec3 {
+        let (uvw, wo) = self.shading_frame(r_in, rec);
+        let wm = ggx_sample_visible(wo, roughness_to_alpha(roughness), sampler.get_2d());
+        uvw.local(wm * (2.0 * wo.dot(wm)) - wo)
+    }
+    fn scattering_pdf_conductor(&self, roughness: f32, r_in: Ray, rec: &HitRecord, direction: Vec3) -> f32 {
+        let (uvw, wo) = self.shading_frame(r_in, rec);
+        let wi = uvw.to_local(direction.normalize());
+        if wo.z <= 0.0 || (wo + wi).length_squared() == 0.0 {
+            return 0.0;
+        }
+        let wm = (wo + wi).normalize();
+        if wm.z <= 0.0 || wo.dot(wm) <= 0.0 {
+            return 0.0;
+        }
+        ggx_visible_pdf(wo, wm, roughness_to_alpha(roughness)) / (4.0 * wo.dot(wm))
+    }
+
+    fn eval_rough_dielectric(&self, ior: f32, roughness: f32, r_in: Ray, rec: &HitRecord, direction: Vec3) -> Rgb {
+        let (uvw, wo) = self.shading_frame(r_in, rec);
+        let wi = uvw.to_local(direction.normalize());
+        let f = rough_dielectric_f(wo, wi, self.relative_eta(ior, rec), roughness_to_alpha(roughness));
+        Rgb::new(1.0, 1.0, 1.0) * (f * wi.z.abs())
+    }
+    fn sample_rough_dielectric(&self, ior: f32, roughness: f32, r_in: Ray, rec: &HitRecord, sampler: &mut Sampler) -> Vec3 {
+        let (uvw, wo) = self.shading_frame(r_in, rec);
+        let u = sampler.get_2d();
+        let wi = rough_dielectric_sample(wo, self.relative_eta(ior, rec), roughness_to_alpha(roughness), u, sampler.get_1d());
+        uvw.local(wi)
+    }
+    fn scattering_pdf_rough_dielectric(&self, ior: f32, roughness: f32, r_in: Ray, rec: &HitRecord, direction: Vec3) -> f32 {
+        let (uvw, wo) = self.shading_frame(r_in, rec);
+        let wi = uvw.to_local(direction.normalize());
+        rough_dielectric_pdf(wo, wi, self.relative_eta(ior, rec), roughness_to_alpha(roughness))
+    }
+    fn eval_principled(&self, base_color: &Texture, params: &PrincipledParams, r_in: Ray, rec: &HitRecord, direction: Vec3, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Rgb {
+        let (uvw, wo) = self.shading_frame(r_in, rec);
+        let wi = uvw.to_local(direction.normalize());
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return Rgb::origin();
+        }
+
+        let base = base_color.get_color(rec.u, rec.v, rec.point, rec.color, atlas);
+        let weights = params.lobe_weights();
+        let alpha = roughness_to_alpha(params.roughness);
+        let one = Rgb::new(1.0, 1.0, 1.0);
+        let mut f = Rgb::origin();
+
+        if same_hemisphere(wo, wi) {
+            let wh = (wo + wi).normalize();
+            let cos_d = wi.dot(wh);
+            let lum = 0.2126 * base.x + 0.7152 * base.y + 0.0722 * base.z;
+            let tint = if lum > 0.0 { base / lum } else { one };
+
+            // Diffuse with Burley's grazing retro-reflection, plus sheen
+            if weights[0] > 0.0 {
+                let fd90 = 0.5 + 2.0 * params.roughness * cos_d * cos_d;
+                let fd = (1.0 + (fd90 - 1.0) * schlick_weight(wi.z)) * (1.0 + (fd90 - 1.0) * schlick_weight(wo.z));
+                let sheen = (one + (tint - one) * params.sheen_tint) * (params.sheen * schlick_weight(cos_d));
+                f = f + (base * (fd / PI) + sheen) * weights[0];
+            }
+            // GGX specular reflection, tinted towards the base color as the surface becomes metallic
+            if weights[1] > 0.0 {
+                let spec0 = (one + (tint - one) * params.specular_tint) * (0.08 * params.specular);
+                let f0 = spec0 + (base - spec0) * params.metallic;
+                let spec = ggx_d(wh, alpha) * ggx_g(wo, wi, alpha) / (4.0 * wi.z * wo.z);
+                f = f + schlick_fresnel(f0, cos_d) * (spec * weights[1]);
+            }
+            // Achromatic clearcoat with its own, fixed index of refraction of 1.5
+            if weights[3] > 0.0 {
+                let alpha_c = 0.1 + (0.001 - 0.1) * params.clearcoat_gloss;
+                let fresnel = 0.04 + 0.96 * schlick_weight(cos_d);
+                let coat = gtr1_d(wh, alpha_c) * fresnel * ggx_g(wo, wi, 0.25) / (4.0 * wi.z * wo.z);
+                f = f + one * (coat * weights[3]);
+            }
+        }
+        // Rough dielectric transmission; only the refracted part takes on the base color
+        if weights[2] > 0.0 {
+            let ft = rough_dielectric_f(wo, wi, self.relative_eta(params.ior, rec), alpha) * weights[2];
+            f = f + if same_hemisphere(wo, wi) { one * ft } else { base * ft };
+        }
+        return f * wi.z.abs();
+    }
+    fn sample_principled(&self, params: &PrincipledParams, r_in: Ray, rec: &HitRecord, sampler: &mut Sampler) -> Vec3 {
+        let (uvw, wo) = self.shading_frame(r_in, rec);
+        let weights = params.lobe_weights();
+        let total: f32 = weights.iter().sum();
+        let alpha = roughness_to_alpha(params.roughness);
+
+        // Lobe picked in proportion to its weight, with `uc` rescaled to the offset within it
+        let uc = sampler.get_1d() * total;
+        let (mut lobe, mut start) = (0, 0.0);
+        while lobe < weights.len() - 1 && uc >= start + weights[lobe] {
+            start += weights[lobe];
+            lobe += 1;
+        }
+        let uc = uc - start;
+
+        let u = sampler.get_2d();
+        let wi = match lobe {
+            0 => {
+                let (x, y) = ((TAU * u.0).cos() * u.1.sqrt(), (TAU * u.0).sin() * u.1.sqrt());
+                Vec3::new(x, y, (1.0 - u.1).max(0.0).sqrt())
+            },
+            1 => {
+                let wm = ggx_sample_visible(wo, alpha, u);
+                wm * (2.0 * wo.dot(wm)) - wo
+            },
+            2 => rough_dielectric_sample(wo, self.relative_eta(params.ior, rec), alpha, u, uc / weights[2]),
+            _ => {
+                let alpha_c = 0.1 + (0.001 - 0.1) * params.clearcoat_gloss;
+                let wh = gtr1_sample(alpha_c, u);
+                wh * (2.0 * wo.dot(wh)) - wo
+            }
+        };
+        uvw.local(wi)
+    }
+    fn scattering_pdf_principled(&self, params: &PrincipledParams, r_in: Ray, rec: &HitRecord, direction: Vec3) -> f32 {
+        let (uvw, wo) = self.shading_frame(r_in, rec);
+        let wi = uvw.to_local(direction.normalize());
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        let weights = params.lobe_weights();
+        let total: f32 = weights.iter().sum();
+        let alpha = roughness_to_alpha(params.roughness);
+
+        let mut pdf = 0.0;
+        if same_hemisphere(wo, wi) {
+            pdf += weights[0] * wi.z / PI;
+        }
+        if (wo + wi).length_squared() > 0.0 {
+            let wh = (wo + wi).normalize();
+            if wh.z > 0.0 && wo.dot(wh) > 0.0 {
+                pdf += weights[1] * ggx_visible_pdf(wo, wh, alpha) / (4.0 * wo.dot(wh));
+            }
+            // Both signs of the half vector reflect `wo` into `wi`, and GTR1 is symmetric in them
+            let alpha_c = 0.1 + (0.001 - 0.1) * params.clearcoat_gloss;
+            pdf += weights[3] * gtr1_d(wh, alpha_c) * wh.z.abs() / (4.0 * wo.dot(wh).abs());
+        }
+        pdf += weights[2] * rough_dielectric_pdf(wo, wi, self.relative_eta(params.ior, rec), alpha);
+        return pdf / total;
+    }
+
+
+    // Mirrors, smooth glass and fuzzed metal scatter along directions no other sampling strategy can
+    // find, and have no density to weigh one against
+    pub fn is_specular(&self) -> bool {
+        matches!(&self.mat_type, MaterialType::Glossy(..) | MaterialType::Dielectric(..) | MaterialType::DispersiveDielectric(..))
+    }
+    // Whether the material sends different wavelengths in different directions, so a spectral path
+    // can only follow one of them past it
+    pub fn is_dispersive(&self) -> bool {
+        matches!(&self.mat_type, MaterialType::DispersiveDielectric(..))
+    }
+    pub fn is_emissive(&self) -> bool {
+        matches!(&self.mat_type, MaterialType::Emissive(..))
+    }
+    pub fn scatter(&self, r_in: Ray, rec: &HitRecord, atlas: &Arc<Mutex<ImageTextureAtlas>>, sampler: &mut Sampler) -> Option<ScatterRecord> {
+        let mut srec = ScatterRecord::new(self.is_specular());
+        let (attenuation, scattered, pdf) = (&mut srec.attenuation, &mut srec.scattered, &mut srec.pdf);
+        let rec = rec.clone();
+        let did_scatter = match &self.mat_type {
+            MaterialType::Lambertian(albedo) => self.scatter_lambertian(albedo, r_in, attenuation, rec, scattered, pdf, atlas, sampler),
+            MaterialType::Glossy(color, fuzz) => self.scatter_glossy(color, fuzz, r_in, attenuation, rec, scattered, sampler),
+            MaterialType::Dielectric(refraction_index) => self.scatter_dielectric(refraction_index, r_in, attenuation, rec, scattered, sampler),
+            MaterialType::DispersiveDielectric(dispersion) => {
+                let wavelength = if r_in.wavelength > 0.0 { r_in.wavelength } else { RGB_WAVELENGTH };
+                self.scatter_dielectric(&dispersion.ior(wavelength), r_in, attenuation, rec, scattered, sampler)
+            },
+            MaterialType::Isotropic(albedo) => self.scatter_isotropic(albedo, r_in, attenuation, rec, scattered, pdf, atlas, sampler),
+            MaterialType::Emissive(..) => self.scatter_emissive(r_in, attenuation, rec, scattered),
+            MaterialType::Principled(..) | MaterialType::Conductor(..) | MaterialType::RoughDielectric(..) => self.scatter_sampled(r_in, attenuation, rec, scattered, pdf, atlas, sampler),
+        };
+        if did_scatter { Some(srec) } else { None }
     }
     pub fn scattering_pdf(&self, r_in: Ray, rec: HitRecord, scattered: &mut Ray) -> f32 {
         match &self.mat_type {
             MaterialType::Lambertian(albedo) => self.scattering_pdf_lambertian(r_in, rec, scattered),
+            MaterialType::Isotropic(_albedo) => 1.0 / (4.0 * PI),
+            MaterialType::Principled(_base_color, params) => self.scattering_pdf_principled(params, r_in, &rec, scattered.direction),
+            MaterialType::Conductor(_eta, _k, roughness) => self.scattering_pdf_conductor(*roughness, r_in, &rec, scattered.direction),
+            MaterialType::RoughDielectric(ior, roughness) => self.scattering_pdf_rough_dielectric(*ior, *roughness, r_in, &rec, scattered.direction),
+            _ => 0.0
+        }
+    }
+    // BSDF times the cosine of the scattered direction. Zero for specular materials.
+    pub fn eval(&self, r_in: Ray, rec: &HitRecord, scattered: &Ray, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Rgb {
+        match &self.mat_type {
+            MaterialType::Lambertian(albedo) => {
+                let cosin = rec.normal.dot(scattered.direction.normalize());
+                albedo.get_color(rec.u, rec.v, rec.point, rec.color, atlas) * if cosin < 0.0 { 0.0 } else { cosin / PI }
+            },
+            // Phase functions carry no cosine
+            MaterialType::Isotropic(albedo) => albedo.get_color(rec.u, rec.v, rec.point, rec.color, atlas) / (4.0 * PI),
+            MaterialType::Principled(base_color, params) => self.eval_principled(base_color, params, r_in, rec, scattered.direction, atlas),
+            MaterialType::Conductor(eta, k, roughness) => self.eval_conductor(eta, k, *roughness, r_in, rec, scattered.direction),
+            MaterialType::RoughDielectric(ior, roughness) => self.eval_rough_dielectric(*ior, *roughness, r_in, rec, scattered.direction),
+            _ => Rgb::origin()
+        }
+    }
+    // Direction drawn from the material's own importance sampling distribution
+    pub fn sample_direction(&self, r_in: Ray, rec: &HitRecord, sampler: &mut Sampler) -> Vec3 {
+        match &self.mat_type {
+            MaterialType::Principled(_base_color, params) => self.sample_principled(params, r_in, rec, sampler),
+            MaterialType::Conductor(_eta, _k, roughness) => self.sample_conductor(*roughness, r_in, rec, sampler),
+            MaterialType::RoughDielectric(ior, roughness) => self.sample_rough_dielectric(*ior, *roughness, r_in, rec, sampler),
+            _ => ONB::build_from_w(rec.normal).local(random_cosin_direction(sampler))
+        }
+    }
+    // Rough luminance of the light the material emits, for spreading light samples by power. Two-sided
+    // emitters count twice.
+    pub fn emitted_luminance(&self) -> f32 {
+        match &self.mat_type {
+            MaterialType::Emissive(_texture, params, average) => {
+                let tint = 0.2126 * params.tint.x + 0.7152 * params.tint.y + 0.0722 * params.tint.z;
+                *average * params.intensity * tint * params.side_count()
+            },
             _ => 0.0
         }
     }
-    pub fn emitted(&self, u: f32, v: f32, point: Point3, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Rgb {
+    // Radiance leaving the surface at `rec` towards the ray that hit it
+    pub fn emitted(&self, rec: &HitRecord, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Rgb {
         match &self.mat_type {
-            MaterialType::Emissive(albedo) => albedo.get_color(u, v, point, atlas),
+            MaterialType::Emissive(texture, params, _average) => {
+                let lit = match params.sides {
+                    EmissionSides::Front => rec.front_face,
+                    EmissionSides::Back => !rec.front_face,
+                    EmissionSides::Both => true,
+                };
+                if !lit {
+                    return Rgb::origin();
+                }
+                texture.get_color(rec.u, rec.v, rec.point, rec.color, atlas) * params.tint * params.intensity
+            },
             _ => Rgb::origin()
         }
     }
@@ -0,0 +1,414 @@
+#[path = "light.rs"] mod light;
+pub use light::*;
+
+
+// Triangles per leaf of a mesh's BVH
+const MESH_LEAF_SIZE: usize = 4;
+
+// Distance along `r` at which it crosses the triangle, if that lies between `t_min` and `t_max`, and
+// the barycentric coordinates of the crossing with respect to `p1` and `p2` (Möller and Trumbore,
+// "Fast, Minimum Storage Ray/Triangle Intersection", 1997)
+pub fn hit_triangle(p0: Point3, p1: Point3, p2: Point3, r: Ray, t_min: f32, t_max: f32) -> Option<(f32, f32, f32)> {
+    let edge1 = p1 - p0;
+    let edge2 = p2 - p0;
+    let p = r.direction.cross(edge2);
+    let det = edge1.dot(p);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let s = r.origin - p0;
+    let b1 = s.dot(p) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let q = s.cross(edge1);
+    let b2 = r.direction.dot(q) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+    let dist = edge2.dot(q) * inv_det;
+    if dist < t_min || dist > t_max {
+        return None;
+    }
+    Some((dist, b1, b2))
+}
+
+// Slab test of `r` against a box, with the inverse of the ray direction computed once per traversal
+fn hit_bounds(bounds: &AABB, r: &Ray, inv_dir: Vec3, t_min: f32, t_max: f32) -> bool {
+    let (mut t_min, mut t_max) = (t_min, t_max);
+    for a in 0..3 {
+        let t0 = (bounds.minimum[a] - r.origin[a]) * inv_dir[a];
+        let t1 = (bounds.maximum[a] - r.origin[a]) * inv_dir[a];
+        let (t0, t1) = if inv_dir[a] < 0.0 { (t1, t0) } else { (t0, t1) };
+        t_min = t_min.max(t0);
+        t_max = t_max.min(t1);
+        if t_max < t_min {
+            return false;
+        }
+    }
+    true
+}
+
+
+// Node of a mesh's BVH, stored flat: leaves cover `count` triangles from `start`, inner nodes have
+// a count of zero and their two children at `start` and `start + 1`
+#[derive(Clone)]
+struct MeshNode {
+    bounds: AABB,
+    start: u32,
+    count: u32,
+}
+
+// Triangle mesh with shared vertex buffers: positions, and optionally normals and texture
+// coordinates, one per vertex, indexed by the corners of the triangles. The triangles are kept in
+// the order of the leaves of their BVH. Geometry holding a mesh shares it through an `Arc`, so
+// instancing it copies nothing.
+#[derive(Clone)]
+pub struct MeshData {
+    pub positions: Vec<Point3>,
+    // Empty when the mesh has no normals, texture coordinates or colors
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<(f32, f32)>,
+    pub colors: Vec<Rgb>,
+    pub triangles: Vec<[u32; 3]>,
+    pub materials: Vec<Material>,
+    // Index into `materials` of every triangle; empty when there is only one material
+    pub material_ids: Vec<u32>,
+    nodes: Vec<MeshNode>,
+    // Running total of the triangle areas, for picking triangles by area
+    cumulative_area: Vec<f32>,
+}
+
+impl MeshData {
+    // Builds the BVH over the triangles. Degenerate triangles, which can't be hit, are dropped. Fails
+    // when there is no material, when a triangle refers to a vertex or material that doesn't exist,
+    // or when a vertex attribute buffer is neither empty nor one entry per position.
+    pub fn new(positions: Vec<Point3>, normals: Vec<Vec3>, uvs: Vec<(f32, f32)>, colors: Vec<Rgb>, triangles: Vec<[u32; 3]>, materials: Vec<Material>, material_ids: Vec<u32>) -> Result<Self, String> {
+        if materials.is_empty() {
+            return Err("mesh has no materials".to_string());
+        }
+        for (name, count) in [("normals", normals.len()), ("texture coordinates", uvs.len()), ("colors", colors.len())] {
+            if count != 0 && count != positions.len() {
+                return Err(format!("{} {} for {} vertices", count, name, positions.len()));
+            }
+        }
+        if let Some(corner) = triangles.iter().flatten().find(|&&v| v as usize >= positions.len()) {
+            return Err(format!("triangle refers to vertex {}, but there are {}", corner, positions.len()));
+        }
+        if materials.len() > 1 {
+            if material_ids.len() != triangles.len() {
+                return Err(format!("{} material indices for {} triangles", material_ids.len(), triangles.len()));
+            }
+            if let Some(id) = material_ids.iter().find(|&&id| id as usize >= materials.len()) {
+                return Err(format!("triangle refers to material {}, but there are {}", id, materials.len()));
+            }
+        }
+
+        let keep: Vec<usize> = (0..triangles.len()).filter(|&i| {
+            let [a, b, c] = triangles[i].map(|v| positions[v as usize]);
+            (b - a).cross(c - a).length() > 0.0
+        }).collect();
+        let mut mesh = Self {
+            positions: positions,
+            normals: normals,
+            uvs: uvs,
+            colors: colors,
+            triangles: keep.iter().map(|&i| triangles[i]).collect(),
+            material_ids: if materials.len() > 1 { keep.iter().map(|&i| material_ids[i]).collect() } else { Vec::new() },
+            materials: materials,
+            nodes: Vec::new(),
+            cumulative_area: Vec::new(),
+        };
+        mesh.build_bvh();
+        let mut total = 0.0;
+        mesh.cumulative_area = (0..mesh.triangles.len()).map(|i| { total += mesh.triangle_area(i); total }).collect();
+        Ok(mesh)
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+    pub fn corners(&self, triangle: usize) -> (Point3, Point3, Point3) {
+        let [a, b, c] = self.triangles[triangle];
+        (self.positions[a as usize], self.positions[b as usize], self.positions[c as usize])
+    }
+    pub fn material(&self, triangle: usize) -> &Material {
+        if self.material_ids.is_empty() { &self.materials[0] } else { &self.materials[self.material_ids[triangle] as usize] }
+    }
+    pub fn triangle_area(&self, triangle: usize) -> f32 {
+        let (p0, p1, p2) = self.corners(triangle);
+        0.5 * (p1 - p0).cross(p2 - p0).length()
+    }
+    pub fn area(&self) -> f32 {
+        *self.cumulative_area.last().unwrap_or(&0.0)
+    }
+    pub fn bounds(&self) -> Option<AABB> {
+        self.nodes.first().map(|node| node.bounds.clone())
+    }
+
+    fn triangle_bounds(&self, triangle: usize) -> AABB {
+        let (p0, p1, p2) = self.corners(triangle);
+        // Padded like the rectangles, so triangles lying in an axis plane don't get a flat box
+        let pad = Vec3::new(0.0001, 0.0001, 0.0001);
+        AABB::new(
+            Point3::new(p0.x.min(p1.x.min(p2.x)), p0.y.min(p1.y.min(p2.y)), p0.z.min(p1.z.min(p2.z))) - pad,
+            Point3::new(p0.x.max(p1.x.max(p2.x)), p0.y.max(p1.y.max(p2.y)), p0.z.max(p1.z.max(p2.z))) + pad,
+        )
+    }
+
+    // Median split along the axis over which the triangle centroids are spread the widest, then
+    // reorders the triangles to match the leaves
+    fn build_bvh(&mut self) {
+        let n = self.triangles.len();
+        if n == 0 {
+            return;
+        }
+        let boxes: Vec<AABB> = (0..n).map(|i| self.triangle_bounds(i)).collect();
+        let centroids: Vec<Point3> = boxes.iter().map(|b| (b.minimum + b.maximum) * 0.5).collect();
+        let mut order: Vec<u32> = (0..n as u32).collect();
+        let mut nodes = vec![MeshNode { bounds: AABB::empty(), start: 0, count: 0 }];
+        // Nodes still to be filled in with the range of `order` they cover
+        let mut pending = vec![(0usize, 0usize, n)];
+
+        while let Some((node, start, end)) = pending.pop() {
+            let mut bounds = boxes[order[start] as usize].clone();
+            let mut lo = centroids[order[start] as usize];
+            let mut hi = lo;
+            for &i in order[start..end].iter() {
+                bounds = AABB::surrounding_box(bounds, boxes[i as usize].clone());
+                for a in 0..3 {
+                    lo[a] = lo[a].min(centroids[i as usize][a]);
+                    hi[a] = hi[a].max(centroids[i as usize][a]);
+                }
+            }
+            let extent = hi - lo;
+            let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+            nodes[node].bounds = bounds;
+
+            if end - start <= MESH_LEAF_SIZE || extent[axis] <= 0.0 {
+                nodes[node].start = start as u32;
+                nodes[node].count = (end - start) as u32;
+                continue;
+            }
+            let mid = (start + end) / 2;
+            order[start..end].select_nth_unstable_by(mid - start, |&a, &b| {
+                centroids[a as usize][axis].partial_cmp(&centroids[b as usize][axis]).unwrap_or(std::cmp::Ordering::Equal)
+            });
+            let left = nodes.len();
+            nodes[node].start = left as u32;
+            nodes.push(MeshNode { bounds: AABB::empty(), start: 0, count: 0 });
+            nodes.push(MeshNode { bounds: AABB::empty(), start: 0, count: 0 });
+            pending.push((left, start, mid));
+            pending.push((left + 1, mid, end));
+        }
+
+        self.triangles = order.iter().map(|&i| self.triangles[i as usize]).collect();
+        if !self.material_ids.is_empty() {
+            self.material_ids = order.iter().map(|&i| self.material_ids[i as usize]).collect();
+        }
+        self.nodes = nodes;
+    }
+
+    // Calls `hit` with every triangle in a leaf whose box `r` crosses before `t_max`. `hit` returns
+    // the new bound on the distance, so closest hit queries can skip what lies behind.
+    fn traverse(&self, r: &Ray, t_min: f32, mut t_max: f32, mut hit: impl FnMut(usize, f32) -> f32) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let inv_dir = Vec3::new(1.0 / r.direction.x, 1.0 / r.direction.y, 1.0 / r.direction.z);
+        let mut stack = vec![0u32];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node as usize];
+            if !hit_bounds(&node.bounds, r, inv_dir, t_min, t_max) {
+                continue;
+            }
+            if node.count > 0 {
+                for triangle in node.start..node.start + node.count {
+                    t_max = hit(triangle as usize, t_max);
+                }
+            } else {
+                stack.push(node.start + 1);
+                stack.push(node.start);
+            }
+        }
+    }
+
+    // Normals, texture coordinates and colors are interpolated from the corners. Corners without a normal
+    // give the triangle its flat normal, and triangles without texture coordinates use their
+    // barycentric coordinates instead.
+    pub fn intersect(&self, r: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut closest: Option<(usize, f32, f32, f32)> = None;
+        self.traverse(&r, t_min, t_max, |triangle, t_max| {
+            let (p0, p1, p2) = self.corners(triangle);
+            match hit_triangle(p0, p1, p2, r, t_min, t_max) {
+                Some((t, b1, b2)) => {
+                    closest = Some((triangle, t, b1, b2));
+                    t
+                },
+                None => t_max
+            }
+        });
+        let (triangle, t, b1, b2) = closest?;
+        let (p0, p1, p2) = self.corners(triangle);
+        let [i0, i1, i2] = self.triangles[triangle].map(|i| i as usize);
+        let b0 = 1.0 - b1 - b2;
+        let face_normal = (p1 - p0).cross(p2 - p0).normalize();
+
+        let mut rec = HitRecord::new(r.at(t), t);
+        rec.set_face_normal(r, face_normal);
+        rec.barycentric = (b1, b2);
+        if !self.normals.is_empty() {
+            let (n0, n1, n2) = (self.normals[i0], self.normals[i1], self.normals[i2]);
+            if n0.length_squared() > 0.0 && n1.length_squared() > 0.0 && n2.length_squared() > 0.0 {
+                rec.set_shading_normal(n0 * b0 + n1 * b1 + n2 * b2, face_normal);
+            }
+        }
+        if self.uvs.is_empty() {
+            rec.set_uv((b1, b2));
+        } else {
+            let (uv0, uv1, uv2) = (self.uvs[i0], self.uvs[i1], self.uvs[i2]);
+            rec.set_uv((uv0.0 * b0 + uv1.0 * b1 + uv2.0 * b2, uv0.1 * b0 + uv1.1 * b1 + uv2.1 * b2));
+        }
+        if !self.colors.is_empty() {
+            rec.color = self.colors[i0] * b0 + self.colors[i1] * b1 + self.colors[i2] * b2;
+        }
+        rec.material = self.material(triangle).clone();
+        Some(rec)
+    }
+
+    // Solid angle density of sampling points uniformly over the whole surface, seen from `origin`
+    // along `v`. Every triangle `v` passes through could have produced it.
+    pub fn pdf_value(&self, origin: Point3, v: Vec3) -> f32 {
+        let area = self.area();
+        if area <= 0.0 {
+            return 0.0;
+        }
+        let r = Ray::new(origin, v, 0.0);
+        let mut pdf = 0.0;
+        self.traverse(&r, 0.001, f32::MAX, |triangle, t_max| {
+            let (p0, p1, p2) = self.corners(triangle);
+            if let Some((t, _b1, _b2)) = hit_triangle(p0, p1, p2, r, 0.001, f32::MAX) {
+                let normal = (p1 - p0).cross(p2 - p0).normalize();
+                let dist_sqrd = t * t * v.length_squared();
+                let cosine = (v.dot(normal) / v.length()).abs();
+                pdf += dist_sqrd / (cosine * area);
+            }
+            t_max
+        });
+        pdf
+    }
+    // Direction from `origin` towards a point drawn uniformly over the surface
+    pub fn random(&self, origin: Point3, sampler: &mut Sampler) -> Vec3 {
+        let target = sampler.random() * self.area();
+        let triangle = self.cumulative_area.partition_point(|&a| a <= target).min(self.triangles.len().saturating_sub(1));
+        let (p0, p1, p2) = self.corners(triangle);
+        let (u, v) = sampler.get_2d();
+        let su = u.sqrt();
+        p0 * (1.0 - su) + p1 * (v * su) + p2 * ((1.0 - v) * su) - origin
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material() -> Material {
+        Material::lambertian(Texture::solid_color(Rgb::new(0.5, 0.5, 0.5)))
+    }
+    // Unit square in the z = 0 plane, as two triangles
+    fn square_positions() -> Vec<Point3> {
+        vec![Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(1.0, 1.0, 0.0), Point3::new(0.0, 1.0, 0.0)]
+    }
+    fn square_triangles() -> Vec<[u32; 3]> {
+        vec![[0, 1, 2], [0, 2, 3]]
+    }
+
+    #[test]
+    fn rejects_meshes_without_materials() {
+        assert_eq!(MeshData::new(square_positions(), vec![], vec![], vec![], square_triangles(), vec![], vec![]).err().unwrap(), "mesh has no materials");
+    }
+
+    #[test]
+    fn rejects_out_of_range_vertices() {
+        let result = MeshData::new(square_positions(), vec![], vec![], vec![], vec![[0, 1, 4]], vec![material()], vec![]);
+        assert_eq!(result.err().unwrap(), "triangle refers to vertex 4, but there are 4");
+    }
+
+    #[test]
+    fn rejects_attribute_buffers_of_the_wrong_length() {
+        let normals = vec![Vec3::new(0.0, 0.0, 1.0); 3];
+        let result = MeshData::new(square_positions(), normals, vec![], vec![], square_triangles(), vec![material()], vec![]);
+        assert_eq!(result.err().unwrap(), "3 normals for 4 vertices");
+        let result = MeshData::new(square_positions(), vec![], vec![(0.0, 0.0); 5], vec![], square_triangles(), vec![material()], vec![]);
+        assert_eq!(result.err().unwrap(), "5 texture coordinates for 4 vertices");
+    }
+
+    #[test]
+    fn rejects_bad_material_indices() {
+        let materials = vec![material(), material()];
+        let result = MeshData::new(square_positions(), vec![], vec![], vec![], square_triangles(), materials.clone(), vec![0]);
+        assert_eq!(result.err().unwrap(), "1 material indices for 2 triangles");
+        let result = MeshData::new(square_positions(), vec![], vec![], vec![], square_triangles(), materials, vec![0, 2]);
+        assert_eq!(result.err().unwrap(), "triangle refers to material 2, but there are 2");
+    }
+
+    #[test]
+    fn drops_degenerate_triangles() {
+        let mesh = MeshData::new(square_positions(), vec![], vec![], vec![], vec![[0, 1, 2], [0, 0, 3], [0, 1, 1]], vec![material()], vec![]).unwrap();
+        assert_eq!(mesh.triangle_count(), 1);
+    }
+
+    #[test]
+    fn interpolates_vertex_attributes() {
+        let normals = vec![Vec3::new(0.0, 0.0, 1.0); 4];
+        let uvs = vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+        let colors = vec![Rgb::new(1.0, 0.0, 0.0), Rgb::new(0.0, 1.0, 0.0), Rgb::new(0.0, 0.0, 1.0), Rgb::new(0.0, 0.0, 0.0)];
+        let mesh = MeshData::new(square_positions(), normals, uvs, colors, square_triangles(), vec![material()], vec![]).unwrap();
+
+        let r = Ray::new(Point3::new(0.75, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let rec = mesh.intersect(r, 0.001, f32::MAX).unwrap();
+        assert!((rec.t - 1.0).abs() < 1e-6);
+        assert!((rec.u - 0.75).abs() < 1e-6 && (rec.v - 0.25).abs() < 1e-6);
+        assert!((rec.normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-6);
+        assert!((rec.color - Rgb::new(0.25, 0.5, 0.25)).length() < 1e-6);
+        assert!(mesh.intersect(Ray::new(Point3::new(1.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0), 0.001, f32::MAX).is_none());
+    }
+
+    #[test]
+    fn hit_triangle_reports_barycentrics_of_the_second_and_third_corners() {
+        let (p0, p1, p2) = (Point3::new(0.0, 0.0, 0.0), Point3::new(2.0, 0.0, 0.0), Point3::new(0.0, 4.0, 0.0));
+        for (b1, b2) in [(0.25, 0.25), (0.1, 0.7), (0.6, 0.3), (0.0, 0.0), (1.0, 0.0), (0.0, 1.0)] {
+            let target = p0 * (1.0 - b1 - b2) + p1 * b1 + p2 * b2;
+            let r = Ray::new(target + Vec3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.5), 0.0);
+            let (t, hit_b1, hit_b2) = hit_triangle(p0, p1, p2, r, 0.001, f32::MAX).unwrap();
+            assert!((t - 2.0).abs() < 1e-5);
+            assert!((hit_b1 - b1).abs() < 1e-5 && (hit_b2 - b2).abs() < 1e-5, "({}, {}) came back as ({}, {})", b1, b2, hit_b1, hit_b2);
+            assert!((r.at(t) - target).length() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn hit_triangle_hits_both_sides() {
+        let (p0, p1, p2) = (Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0));
+        let below = Ray::new(Point3::new(0.2, 0.2, -1.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let (t, b1, b2) = hit_triangle(p0, p1, p2, below, 0.001, f32::MAX).unwrap();
+        assert!((t - 1.0).abs() < 1e-6 && (b1 - 0.2).abs() < 1e-6 && (b2 - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn hit_triangle_misses_outside_and_beyond_bounds() {
+        let (p0, p1, p2) = (Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0));
+        let down = |x: f32, y: f32| Ray::new(Point3::new(x, y, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(hit_triangle(p0, p1, p2, down(0.6, 0.6), 0.001, f32::MAX).is_none());
+        assert!(hit_triangle(p0, p1, p2, down(-0.1, 0.5), 0.001, f32::MAX).is_none());
+        assert!(hit_triangle(p0, p1, p2, down(0.5, -0.1), 0.001, f32::MAX).is_none());
+        assert!(hit_triangle(p0, p1, p2, down(0.2, 0.2), 0.001, 0.5).is_none());
+        assert!(hit_triangle(p0, p1, p2, down(0.2, 0.2), 1.5, f32::MAX).is_none());
+        let away = Ray::new(Point3::new(0.2, 0.2, 1.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        assert!(hit_triangle(p0, p1, p2, away, 0.001, f32::MAX).is_none());
+        let parallel = Ray::new(Point3::new(-1.0, 0.2, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        assert!(hit_triangle(p0, p1, p2, parallel, 0.001, f32::MAX).is_none());
+    }
+}
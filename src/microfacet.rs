@@ -0,0 +1,215 @@
+#[path = "texture.rs"] mod texture;
+pub use texture::*;
+
+
+// Microfacet helpers shared by the rough materials. Directions are expressed in the local shading
+// frame, where the surface normal is +z.
+
+pub fn same_hemisphere(a: Vec3, b: Vec3) -> bool {
+    a.z * b.z > 0.0
+}
+
+// Perceptually linear roughness to the GGX width, clamped so that smooth surfaces stay numerically stable
+pub fn roughness_to_alpha(roughness: f32) -> f32 {
+    (roughness * roughness).max(1e-3)
+}
+
+pub fn schlick_weight(cosin: f32) -> f32 {
+    let m = clamp(1.0 - cosin, 0.0, 1.0);
+    (m * m) * (m * m) * m
+}
+
+pub fn schlick_fresnel(f0: Rgb, cosin: f32) -> Rgb {
+    f0 + (Rgb::new(1.0, 1.0, 1.0) - f0) * schlick_weight(cosin)
+}
+
+// Unpolarized Fresnel reflectance of a dielectric interface. `eta` is the relative index of
+// refraction across the interface as seen from the side `cos_theta_i` is measured on.
+pub fn fresnel_dielectric(cos_theta_i: f32, eta: f32) -> f32 {
+    let cos_theta_i = clamp(cos_theta_i, -1.0, 1.0);
+    let (cos_theta_i, eta) = if cos_theta_i < 0.0 { (-cos_theta_i, 1.0 / eta) } else { (cos_theta_i, eta) };
+
+    let sin2_theta_t = (1.0 - cos_theta_i * cos_theta_i) / (eta * eta);
+    if sin2_theta_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_theta_t = (1.0 - sin2_theta_t).max(0.0).sqrt();
+    let r_parl = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let r_perp = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+    (r_parl * r_parl + r_perp * r_perp) / 2.0
+}
+
+// Fresnel reflectance of a conductor with complex index of refraction `eta + i k`, per channel
+pub fn fresnel_complex(cos_theta_i: f32, eta: Rgb, k: Rgb) -> Rgb {
+    let cos_theta_i = clamp(cos_theta_i, 0.0, 1.0);
+    let channel = |eta: f32, k: f32| -> f32 {
+        let cos2 = cos_theta_i * cos_theta_i;
+        let sin2 = 1.0 - cos2;
+        let eta2 = eta * eta;
+        let k2 = k * k;
+
+        let t0 = eta2 - k2 - sin2;
+        let a2b2 = (t0 * t0 + 4.0 * eta2 * k2).max(0.0).sqrt();
+        let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+        let t1 = a2b2 + cos2;
+        let t2 = 2.0 * a * cos_theta_i;
+        let r_perp = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let r_parl = r_perp * (t3 - t4) / (t3 + t4);
+        0.5 * (r_parl + r_perp)
+    };
+    Rgb::new(channel(eta.x, k.x), channel(eta.y, k.y), channel(eta.z, k.z))
+}
+
+// Refracts `wi` about the normal `n` (on the same side as `wi`) with relative index `eta`,
+// or returns None on total internal reflection
+pub fn refract_dir(wi: Vec3, n: Vec3, eta: f32) -> Option<Vec3> {
+    let cos_theta_i = n.dot(wi);
+    let sin2_theta_i = (1.0 - cos_theta_i * cos_theta_i).max(0.0);
+    let sin2_theta_t = sin2_theta_i / (eta * eta);
+    if sin2_theta_t >= 1.0 {
+        return None;
+    }
+    let cos_theta_t = (1.0 - sin2_theta_t).sqrt();
+    Some(-wi / eta + n * (cos_theta_i / eta - cos_theta_t))
+}
+
+
+// Trowbridge-Reitz (GGX) distribution with Smith masking, isotropic width `alpha`
+pub fn ggx_d(wm: Vec3, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let c2 = wm.z * wm.z;
+    let d = c2 * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+pub fn ggx_lambda(w: Vec3, alpha: f32) -> f32 {
+    let c2 = w.z * w.z;
+    if c2 <= 0.0 {
+        return 0.0;
+    }
+    let tan2 = (1.0 - c2).max(0.0) / c2;
+    ((1.0 + alpha * alpha * tan2).sqrt() - 1.0) / 2.0
+}
+
+pub fn ggx_g1(w: Vec3, alpha: f32) -> f32 {
+    1.0 / (1.0 + ggx_lambda(w, alpha))
+}
+
+pub fn ggx_g(wo: Vec3, wi: Vec3, alpha: f32) -> f32 {
+    1.0 / (1.0 + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha))
+}
+
+// Density of the normals visible from `wo` (Heitz 2018), with respect to solid angle around `wm`
+pub fn ggx_visible_pdf(wo: Vec3, wm: Vec3, alpha: f32) -> f32 {
+    if wo.z == 0.0 {
+        return 0.0;
+    }
+    ggx_g1(wo, alpha) / wo.z.abs() * ggx_d(wm, alpha) * wo.dot(wm).abs()
+}
+
+// Samples a normal visible from `wo` (Heitz, "Sampling the GGX Distribution of Visible Normals", 2018)
+pub fn ggx_sample_visible(wo: Vec3, alpha: f32, u: (f32, f32)) -> Vec3 {
+    let flip = wo.z < 0.0;
+    let wo = if flip { -wo } else { wo };
+
+    // Stretch to the hemisphere configuration
+    let vh = Vec3::new(alpha * wo.x, alpha * wo.y, wo.z).normalize();
+    let lensq = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if lensq > 0.0 { Vec3::new(-vh.y, vh.x, 0.0) / lensq.sqrt() } else { Vec3::new(1.0, 0.0, 0.0) };
+    let t2 = vh.cross(t1);
+
+    // Uniform point on the projected disk, warped towards the visible half
+    let r = u.0.sqrt();
+    let phi = TAU * u.1;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+    let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+
+    // Unstretch
+    let wm = Vec3::new(alpha * nh.x, alpha * nh.y, nh.z.max(1e-6)).normalize();
+    if flip { -wm } else { wm }
+}
+
+
+// Berry's (GTR1) distribution, used by the clearcoat lobe
+pub fn gtr1_d(wm: Vec3, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let c2 = wm.z * wm.z;
+    (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * c2))
+}
+
+pub fn gtr1_sample(alpha: f32, u: (f32, f32)) -> Vec3 {
+    let a2 = alpha * alpha;
+    let cos_theta = ((1.0 - a2.powf(1.0 - u.0)) / (1.0 - a2)).max(0.0).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = TAU * u.1;
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+
+// Rough dielectric interface (Walter et al., "Microfacet Models for Refraction through Rough
+// Surfaces", 2007) with relative index of refraction `eta`, sampled through the visible normals.
+// Returns the generalized half vector of the pair and the relative index along the path, or None if
+// the configuration cannot occur.
+fn rough_dielectric_half(wo: Vec3, wi: Vec3, eta: f32) -> Option<(Vec3, f32)> {
+    if wo.z == 0.0 || wi.z == 0.0 {
+        return None;
+    }
+    let etap = if same_hemisphere(wo, wi) { 1.0 } else if wo.z > 0.0 { eta } else { 1.0 / eta };
+    let wm = wi * etap + wo;
+    if wm.length_squared() == 0.0 {
+        return None;
+    }
+    let wm = wm.normalize();
+    let wm = if wm.z < 0.0 { -wm } else { wm };
+
+    // Discard back facing microfacets
+    if wm.dot(wi) * wi.z < 0.0 || wm.dot(wo) * wo.z < 0.0 {
+        return None;
+    }
+    Some((wm, etap))
+}
+
+pub fn rough_dielectric_f(wo: Vec3, wi: Vec3, eta: f32, alpha: f32) -> f32 {
+    let (wm, etap) = match rough_dielectric_half(wo, wi, eta) {
+        Some(half) => half,
+        None => return 0.0
+    };
+    let fresnel = fresnel_dielectric(wo.dot(wm), eta);
+    if same_hemisphere(wo, wi) {
+        return ggx_d(wm, alpha) * ggx_g(wo, wi, alpha) * fresnel / (4.0 * wi.z * wo.z).abs();
+    }
+    let denom = (wi.dot(wm) + wo.dot(wm) / etap).powi(2) * wi.z * wo.z;
+    // Radiance is compressed by the squared relative index when it crosses into a denser medium
+    ggx_d(wm, alpha) * ggx_g(wo, wi, alpha) * (1.0 - fresnel) * (wi.dot(wm) * wo.dot(wm) / denom).abs() / (etap * etap)
+}
+
+pub fn rough_dielectric_pdf(wo: Vec3, wi: Vec3, eta: f32, alpha: f32) -> f32 {
+    let (wm, etap) = match rough_dielectric_half(wo, wi, eta) {
+        Some(half) => half,
+        None => return 0.0
+    };
+    let fresnel = fresnel_dielectric(wo.dot(wm), eta);
+    if same_hemisphere(wo, wi) {
+        return ggx_visible_pdf(wo, wm, alpha) / (4.0 * wo.dot(wm).abs()) * fresnel;
+    }
+    let denom = (wi.dot(wm) + wo.dot(wm) / etap).powi(2);
+    ggx_visible_pdf(wo, wm, alpha) * wi.dot(wm).abs() / denom * (1.0 - fresnel)
+}
+
+// Picks reflection or refraction about a visible normal with probability given by the Fresnel term
+pub fn rough_dielectric_sample(wo: Vec3, eta: f32, alpha: f32, u: (f32, f32), uc: f32) -> Vec3 {
+    let wm = ggx_sample_visible(wo, alpha, u);
+    let reflected = wm * (2.0 * wo.dot(wm)) - wo;
+    if uc < fresnel_dielectric(wo.dot(wm), eta) {
+        return reflected;
+    }
+    let (n, eta) = if wo.dot(wm) < 0.0 { (-wm, 1.0 / eta) } else { (wm, eta) };
+    match refract_dir(wo, n, eta) {
+        Some(wi) => wi,
+        None => reflected
+    }
+}
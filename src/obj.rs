@@ -0,0 +1,367 @@
+#[path = "output.rs"] mod output;
+pub use output::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use wavefront_obj::obj::{self, Primitive};
+
+
+// Rewrites an OBJ file into the subset `wavefront_obj` reads: all vertex data first (faces may use
+// vertices from any earlier object, but the parser only looks within the current one), relative
+// indices made absolute (the parser gets negative ones wrong), object names added to the groups of
+// their faces and everything else the parser doesn't know, such as free-form geometry or stray text,
+// dropped. Returns the rewritten file and the material libraries it names.
+fn normalize_obj(source: &str) -> Result<(String, Vec<String>), String> {
+    let mut vertex_data = String::new();
+    let mut faces = String::new();
+    let mut libraries = Vec::new();
+    // Positions, texture coordinates and normals read so far
+    let mut counts = [0isize; 3];
+    let mut object: Option<&str> = None;
+
+    for (number, line) in source.lines().enumerate() {
+        let content = line.split('#').next().unwrap_or("").trim();
+        let mut words = content.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue
+        };
+        match keyword {
+            "v" | "vt" | "vn" => {
+                counts[match keyword { "v" => 0, "vt" => 1, _ => 2 }] += 1;
+                vertex_data.push_str(content);
+                vertex_data.push('\n');
+            },
+            "f" | "l" => {
+                faces.push_str(keyword);
+                for corner in words {
+                    let mut indices = Vec::new();
+                    for (i, index) in corner.split('/').enumerate() {
+                        if index.is_empty() || i > 2 {
+                            indices.push(index.to_string());
+                            continue;
+                        }
+                        let n: isize = index.parse().map_err(|_| format!("line {}: invalid index '{}'", number + 1, index))?;
+                        indices.push(if n < 0 { (counts[i] + 1 + n).to_string() } else { n.to_string() });
+                    }
+                    faces.push(' ');
+                    faces.push_str(&indices.join("/"));
+                }
+                faces.push('\n');
+            },
+            "o" => {
+                object = words.next();
+                faces.push_str(&format!("g {}\n", object.unwrap_or("")));
+            },
+            "g" => {
+                let groups: Vec<&str> = object.into_iter().chain(words).collect();
+                faces.push_str(&format!("g {}\n", groups.join(" ")));
+            },
+            "s" | "usemtl" => {
+                faces.push_str(content);
+                faces.push('\n');
+            },
+            "mtllib" => libraries.extend(words.map(|name| name.to_string())),
+            _ => ()
+        }
+    }
+    vertex_data.push_str(&faces);
+    Ok((vertex_data, libraries))
+}
+
+// Reads the materials of an MTL file. Only the statements that map onto this renderer's materials
+// are looked at: colors Kd, Ks and Ke, specular exponent Ns, index of refraction Ni, dissolve d (or
+// its complement Tr), illumination model illum and diffuse texture map_Kd.
+fn load_mtl(path: &str, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Result<HashMap<String, Material>, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlParams)> = None;
+
+    for (number, line) in source.lines().enumerate() {
+        let error = |message: String| format!("{}:{}: {}", path, number + 1, message);
+        let content = line.split('#').next().unwrap_or("").trim();
+        let words: Vec<&str> = content.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let number_at = |i: usize| -> Result<f32, String> {
+            let word = words.get(i).ok_or(error(format!("'{}' is missing a value", words[0])))?;
+            word.parse().map_err(|_| error(format!("expected a number, found '{}'", word)))
+        };
+        let color = || -> Result<Rgb, String> {
+            let r = number_at(1)?;
+            // A single value stands for a gray
+            if words.len() < 4 { Ok(Rgb::new(r, r, r)) } else { Ok(Rgb::new(r, number_at(2)?, number_at(3)?)) }
+        };
+
+        if words[0] == "newmtl" {
+            if let Some((name, params)) = current.take() {
+                materials.insert(name, params.to_material(atlas)?);
+            }
+            current = Some((words[1..].join(" "), MtlParams::new()));
+            continue;
+        }
+        let params = match &mut current {
+            Some((_name, params)) => params,
+            None => continue
+        };
+        match words[0] {
+            "Kd" => params.diffuse = color()?,
+            "Ks" => params.specular = color()?,
+            "Ke" => params.emission = color()?,
+            "Ns" => params.exponent = number_at(1)?,
+            "Ni" => params.ior = Some(number_at(1)?),
+            "d" => params.dissolve = number_at(1)?,
+            "Tr" => params.dissolve = 1.0 - number_at(1)?,
+            "illum" => params.illum = number_at(1)? as usize,
+            "map_Kd" => {
+                // Options such as -s or -o come before the file name
+                let file = dir.join(words[words.len() - 1]).to_string_lossy().into_owned();
+                if !Path::new(&file).is_file() {
+                    return Err(error(format!("texture '{}' does not exist", file)));
+                }
+                params.diffuse_map = Some(file);
+            },
+            _ => ()
+        }
+    }
+    if let Some((name, params)) = current.take() {
+        materials.insert(name, params.to_material(atlas)?);
+    }
+    Ok(materials)
+}
+
+struct MtlParams {
+    diffuse: Rgb,
+    specular: Rgb,
+    emission: Rgb,
+    exponent: f32,
+    ior: Option<f32>,
+    dissolve: f32,
+    illum: usize,
+    diffuse_map: Option<String>,
+}
+
+impl MtlParams {
+    fn new() -> Self {
+        Self {
+            diffuse: Rgb::new(0.8, 0.8, 0.8),
+            specular: Rgb::origin(),
+            emission: Rgb::origin(),
+            exponent: 0.0,
+            ior: None,
+            dissolve: 1.0,
+            illum: 2,
+            diffuse_map: None,
+        }
+    }
+
+    // Emitting materials become lights and see-through ones (partly dissolved, or using one of the
+    // refracting illumination models) smooth glass. Everything else is a principled material whose
+    // specular level follows Ks and whose roughness follows the Phong exponent through the usual
+    // Beckmann equivalence, alpha = sqrt(2 / (Ns + 2)).
+    fn to_material(&self, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Result<Material, String> {
+        if luminance(self.emission) > 0.0 {
+            return Ok(Material::emissive(self.emission));
+        }
+        if self.dissolve < 1.0 || [4, 6, 7, 9].contains(&self.illum) {
+            return Ok(Material::dielectric(self.ior.unwrap_or(1.5)));
+        }
+        let base_color = match &self.diffuse_map {
+            Some(path) => {
+                let idx = atlas.lock().unwrap().load(path)?;
+                Texture::image(atlas, idx)
+            },
+            None => Texture::solid_color(self.diffuse)
+        };
+        let mut params = PrincipledParams::new();
+        params.roughness = (2.0 / (self.exponent.max(0.0) + 2.0)).sqrt().sqrt();
+        params.specular = clamp(luminance(self.specular), 0.0, 1.0);
+        params.ior = self.ior.unwrap_or(1.5);
+        Ok(Material::principled(base_color, params))
+    }
+}
+
+impl Geometry {
+    // Loads the triangles of a Wavefront OBJ file, scaled about the origin, into a mesh. Polygons are
+    // split into fans of triangles, and corners that share a position, texture coordinate and normal
+    // share a vertex. Faces take their materials from the file's material libraries unless `material`
+    // is given, with a light gray for faces that have none. When `select` names objects or groups,
+    // only faces in one of them are loaded.
+    pub fn load_obj(path: &str, scale: f32, material: Option<Material>, select: &[String], atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Result<Self, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let (source, libraries) = normalize_obj(&source).map_err(|e| format!("{}: {}", path, e))?;
+        let objset = obj::parse(source).map_err(|e| format!("{}: {}", path, e.message))?;
+
+        let mut library = HashMap::new();
+        if material.is_none() {
+            let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+            for name in libraries.iter() {
+                library.extend(load_mtl(&dir.join(name).to_string_lossy(), atlas)?);
+            }
+        }
+        let fallback = material.unwrap_or(Material::lambertian(Texture::solid_color(Rgb::new(0.8, 0.8, 0.8))));
+
+        let (mut positions, mut normals, mut uvs) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut has_normals, mut has_uvs) = (false, false);
+        let (mut triangles, mut material_ids) = (Vec::new(), Vec::new());
+        let mut materials: Vec<Material> = Vec::new();
+        // Index in `materials` of every material name used so far, `None` standing for the fallback
+        let mut material_index: HashMap<Option<String>, u32> = HashMap::new();
+        for object in objset.objects.iter() {
+            let mut vertex_index: HashMap<(usize, Option<usize>, Option<usize>), u32> = HashMap::new();
+            for geometry in object.geometry.iter() {
+                let name = geometry.material_name.clone().filter(|name| library.contains_key(name));
+                let material_id = *material_index.entry(name.clone()).or_insert_with(|| {
+                    materials.push(name.as_ref().map(|name| library[name].clone()).unwrap_or(fallback.clone()));
+                    (materials.len() - 1) as u32
+                });
+                for shape in geometry.shapes.iter() {
+                    if !select.is_empty() && !shape.groups.iter().any(|group| select.contains(group)) {
+                        continue;
+                    }
+                    let corners = match shape.primitive {
+                        Primitive::Triangle(a, b, c) => [a, b, c],
+                        _ => continue
+                    };
+                    triangles.push(corners.map(|corner| *vertex_index.entry(corner).or_insert_with(|| {
+                        let v = object.vertices[corner.0];
+                        positions.push(Point3::new(v.x as f32, v.y as f32, v.z as f32) * scale);
+                        // Corners without a normal fall back to the face normal, marked by a zero vector
+                        normals.push(corner.2.map(|i| {
+                            let n = object.normals[i];
+                            has_normals = true;
+                            Vec3::new(n.x as f32, n.y as f32, n.z as f32).normalize()
+                        }).unwrap_or(Vec3::origin()));
+                        uvs.push(corner.1.map(|i| {
+                            let t = object.tex_vertices[i];
+                            has_uvs = true;
+                            (t.u as f32, t.v as f32)
+                        }).unwrap_or((0.0, 0.0)));
+                        (positions.len() - 1) as u32
+                    })));
+                    material_ids.push(material_id);
+                }
+            }
+        }
+        if !has_normals {
+            normals.clear();
+        }
+        if !has_uvs {
+            uvs.clear();
+        }
+
+        let no_triangles = || if select.is_empty() {
+            format!("{}: no triangles", path)
+        } else {
+            format!("{}: no triangles in the objects or groups {}", path, select.join(", "))
+        };
+        if triangles.is_empty() {
+            return Err(no_triangles());
+        }
+        let mesh = MeshData::new(positions, normals, uvs, Vec::new(), triangles, materials, material_ids).map_err(|e| format!("{}: {}", path, e))?;
+        if mesh.triangle_count() == 0 {
+            return Err(no_triangles());
+        }
+        Ok(Geometry::mesh(mesh))
+    }
+}
+
+
+// Writes the files into a fresh temporary directory named after the first, loads that one with
+// `load` and returns the mesh it made. Shared by the tests of the mesh loaders.
+#[cfg(test)]
+pub fn load_test_mesh(files: &[(&str, &[u8])], load: impl FnOnce(&str) -> Result<Geometry, String>) -> Result<Arc<MeshData>, String> {
+    let dir = std::env::temp_dir().join(format!("mesh_test_{}_{}", std::process::id(), files[0].0));
+    fs::create_dir_all(&dir).unwrap();
+    for (name, contents) in files {
+        fs::write(dir.join(name), contents).unwrap();
+    }
+    let geometry = load(&dir.join(files[0].0).to_string_lossy());
+    fs::remove_dir_all(&dir).unwrap();
+    match geometry?.geometry_type {
+        GeometryType::Mesh(mesh) => Ok(mesh),
+        _ => panic!("{} did not load as a mesh", files[0].0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(files: &[(&str, &str)], select: &[&str]) -> Result<Arc<MeshData>, String> {
+        let files: Vec<(&str, &[u8])> = files.iter().map(|(name, contents)| (*name, contents.as_bytes())).collect();
+        let select: Vec<String> = select.iter().map(|s| s.to_string()).collect();
+        let atlas = Arc::new(Mutex::new(ImageTextureAtlas::new()));
+        load_test_mesh(&files, |path| Geometry::load_obj(path, 1.0, None, &select, &atlas))
+    }
+    const SQUARE: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n";
+
+    #[test]
+    fn makes_relative_indices_absolute() {
+        let (source, libraries) = normalize_obj("mtllib a.mtl b.mtl\nv 0 0 0\nv 1 0 0\nvt 0 0\nv 0 1 0\nf -3/-1 -2/1 -1//\n").unwrap();
+        assert_eq!(source, "v 0 0 0\nv 1 0 0\nvt 0 0\nv 0 1 0\nf 1/1 2/1 3//\n");
+        assert_eq!(libraries, ["a.mtl", "b.mtl"]);
+    }
+
+    #[test]
+    fn moves_vertex_data_first_and_names_groups_after_objects() {
+        let (source, _libraries) = normalize_obj("o box # comment\nv 0 0 0\ng lid\nf 1 1 1\ncurv 0 1 1 2\nv 1 1 1\n").unwrap();
+        assert_eq!(source, "v 0 0 0\nv 1 1 1\ng box\ng box lid\nf 1 1 1\n");
+    }
+
+    #[test]
+    fn rejects_invalid_indices() {
+        assert_eq!(normalize_obj("v 0 0 0\n\nf 1 x 1\n").err().unwrap(), "line 3: invalid index 'x'");
+    }
+
+    #[test]
+    fn splits_polygons_and_shares_identical_corners() {
+        // The quad's corners share a texture coordinate; the triangle reuses its positions with another
+        let source = format!("{}vt 0 0\nvt 1 1\nvn 0 0 1\nf 1/1/1 2/1/1 3/1/1 4/1/1\nf 1/2/1 2/2/1 3/2/1\n", SQUARE);
+        let mesh = load(&[("corners.obj", &source)], &[]).unwrap();
+        assert_eq!(mesh.triangle_count(), 3);
+        assert_eq!(mesh.positions.len(), 7);
+        assert_eq!((mesh.normals.len(), mesh.uvs.len()), (7, 7));
+    }
+
+    #[test]
+    fn selects_objects_and_groups() {
+        let source = format!("{}o first\nf 1 2 3\no second\ng top\nf 1 3 4\n", SQUARE);
+        let triangles = |file: &str, select: &[&str]| load(&[(file, &source)], select).map(|mesh| mesh.triangle_count());
+        assert_eq!(triangles("select_object.obj", &["first"]), Ok(1));
+        assert_eq!(triangles("select_group.obj", &["top"]), Ok(1));
+        assert_eq!(triangles("select_both.obj", &["first", "second"]), Ok(2));
+        assert!(triangles("select_none.obj", &["third"]).err().unwrap().ends_with("no triangles in the objects or groups third"));
+    }
+
+    #[test]
+    fn reads_materials_from_libraries() {
+        let source = format!("mtllib colors.mtl\n{}usemtl red\nf 1 2 3\nusemtl unknown\nf 1 3 4\n", SQUARE);
+        let mesh = load(&[("colors.obj", &source), ("colors.mtl", "newmtl red\nKd 1 0 0\nNs 10\n")], &[]).unwrap();
+        assert_eq!(mesh.materials.len(), 2);
+        assert_eq!(mesh.material_ids.len(), 2);
+        assert_ne!(mesh.material_ids[0], mesh.material_ids[1]);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(load(&[("empty.obj", SQUARE)], &[]).err().unwrap().ends_with("no triangles"));
+        assert!(load(&[("bad_index.obj", "v 0 0 0\nf 1 -x 1\n")], &[]).err().unwrap().ends_with("line 2: invalid index '-x'"));
+        let error = load(&[("beyond.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n")], &[]).err().unwrap();
+        assert!(error.ends_with("Expected index in the range [1, 4), but got 4."), "{}", error);
+        assert!(load(&[("missing_mtl.obj", &format!("mtllib nowhere.mtl\n{}f 1 2 3\n", SQUARE))], &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_material_libraries() {
+        let source = format!("mtllib lib.mtl\n{}f 1 2 3\n", SQUARE);
+        let error = load(&[("bad_number.obj", &source), ("lib.mtl", "newmtl red\nKd 1 zero 0\n")], &[]).err().unwrap();
+        assert!(error.ends_with("lib.mtl:2: expected a number, found 'zero'"), "{}", error);
+        let error = load(&[("missing_map.obj", &source), ("lib.mtl", "newmtl red\nmap_Kd nowhere.png\n")], &[]).err().unwrap();
+        assert!(error.contains("nowhere.png' does not exist"), "{}", error);
+    }
+}
@@ -0,0 +1,81 @@
+#[path = "render.rs"] mod render;
+pub use render::*;
+use image::{ImageBuffer, ImageFormat, codecs::hdr::HdrEncoder};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    // 8 bit formats supported by the `image` crate (png, jpeg, bmp, ...), passed through the color pipeline
+    LowDynamicRange(ImageFormat),
+    // Linear radiance, written without any display transform
+    OpenExr,
+    RadianceHdr,
+    Pfm,
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        let ext = match Path::new(path).extension() {
+            Some(ext) => ext.to_string_lossy().to_lowercase(),
+            None => return Err(format!("{}: missing file extension", path))
+        };
+        match ext.as_str() {
+            "exr" => Ok(Self::OpenExr),
+            "hdr" => Ok(Self::RadianceHdr),
+            "pfm" => Ok(Self::Pfm),
+            _ => match ImageFormat::from_extension(&ext) {
+                Some(format) => Ok(Self::LowDynamicRange(format)),
+                None => Err(format!("{}: unsupported output format '{}'", path, ext))
+            }
+        }
+    }
+    pub fn is_hdr(&self) -> bool {
+        !matches!(self, Self::LowDynamicRange(_))
+    }
+}
+
+
+pub fn to_ldr(imgbuf: &ImageBuffer<image::Rgb<f32>, Vec<f32>>, pipeline: &ColorPipeline) -> ImageBuffer<image::Rgb<u8>, Vec<u8>> {
+    let mut ldrbuf = ImageBuffer::new(imgbuf.width(), imgbuf.height());
+    for (x, y, pixel) in ldrbuf.enumerate_pixels_mut() {
+        let pix = imgbuf.get_pixel(x, y);
+        *pixel = image::Rgb(pipeline.quantize(Rgb::new(pix.0[0], pix.0[1], pix.0[2])));
+    }
+    return ldrbuf;
+}
+
+fn write_hdr(imgbuf: &ImageBuffer<image::Rgb<f32>, Vec<f32>>, path: &str) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    let pixels: Vec<image::Rgb<f32>> = imgbuf.pixels().cloned().collect();
+    HdrEncoder::new(BufWriter::new(file))
+        .encode(&pixels, imgbuf.width() as usize, imgbuf.height() as usize)
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+fn write_pfm(imgbuf: &ImageBuffer<image::Rgb<f32>, Vec<f32>>, path: &str) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut writer = BufWriter::new(file);
+
+    // A negative scale marks the data as little endian. Scanlines are stored bottom to top.
+    let mut data = format!("PF\n{} {}\n-1.0\n", imgbuf.width(), imgbuf.height()).into_bytes();
+    for y in (0..imgbuf.height()).rev() {
+        for x in 0..imgbuf.width() {
+            for c in imgbuf.get_pixel(x, y).0.iter() {
+                data.extend_from_slice(&c.to_le_bytes());
+            }
+        }
+    }
+    writer.write_all(&data).and_then(|_| writer.flush()).map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn save_image(imgbuf: &ImageBuffer<image::Rgb<f32>, Vec<f32>>, path: &str, pipeline: &ColorPipeline) -> Result<(), String> {
+    match OutputFormat::from_path(path)? {
+        OutputFormat::LowDynamicRange(format) => to_ldr(imgbuf, pipeline).save_with_format(path, format).map_err(|e| format!("{}: {}", path, e)),
+        OutputFormat::OpenExr => imgbuf.save_with_format(path, ImageFormat::OpenExr).map_err(|e| format!("{}: {}", path, e)),
+        OutputFormat::RadianceHdr => write_hdr(imgbuf, path),
+        OutputFormat::Pfm => write_pfm(imgbuf, path),
+    }
+}
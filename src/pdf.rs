@@ -1,11 +1,13 @@
-#[path = "collider.rs"] mod collider;
-pub use collider::*;
+#[path = "background.rs"] mod background;
+pub use background::*;
+use std::sync::Arc;
 
 
 pub enum PDFType {
-    CosinePDF(ONB),
-    CollidablePDF(Geometry, Point3),
-    MixturePDF(Box<PDF>, Box<PDF>)
+    CollidablePDF(Arc<Geometry>, Point3),
+    BackgroundPDF(Background),
+    // Draws from the first distribution with the given probability and from the second otherwise
+    MixturePDF(Box<PDF>, Box<PDF>, f32),
 }
 
 
@@ -14,58 +16,60 @@ pub struct PDF {
 }
 
 impl PDF {
-    pub fn cosine_pdf(w: Vec3) -> Self {
+    pub fn collidable_pdf(geometry: Arc<Geometry>, origin: Point3) -> Self {
         Self {
-            pdf_type: PDFType::CosinePDF(ONB::build_from_w(w))
+            pdf_type: PDFType::CollidablePDF(geometry, origin)
         }
     }
-    pub fn collidable_pdf(geometry: Geometry, origin: Point3) -> Self {
+
+    pub fn background_pdf(background: Background) -> Self {
         Self {
-            pdf_type: PDFType::CollidablePDF(geometry, origin)
+            pdf_type: PDFType::BackgroundPDF(background)
         }
     }
-    pub fn mixture_pdf(p0: PDF, p1: PDF) -> Self {
+    pub fn mixture_pdf(first: PDF, second: PDF, weight: f32) -> Self {
         Self {
-            pdf_type: PDFType::MixturePDF(Box::new(p0), Box::new(p1))
+            pdf_type: PDFType::MixturePDF(Box::new(first), Box::new(second), weight)
         }
     }
 
-    pub fn value_cosine(&self, direction: Vec3, uvw: &ONB) -> f32 {
-        let cosin = direction.normalize().dot(uvw.w);
-        if cosin <= 0.0 { 0.0 } else { cosin / PI }
+    pub fn value_collidable(&self, geometry: &Geometry, origin: Point3, direction: Vec3) -> f32 {
+        geometry.pdf_value(origin, direction)
     }
-    pub fn generate_cosine(&self, uvw: &ONB) -> Vec3 {
-        uvw.local(random_cosin_direction())
+    pub fn generate_collidable(&self, geometry: &Geometry, origin: Point3, sampler: &mut Sampler) -> Vec3 {
+        geometry.random(origin, sampler)
     }
 
-    pub fn value_collidable(&self, geometry: &Geometry, origin: Point3, direction: Vec3) -> f32 {
-        geometry.pdf_value(origin, direction)
+    pub fn value_background(&self, background: &Background, direction: Vec3) -> f32 {
+        background.pdf_value(direction)
     }
-    pub fn generate_collidable(&self, geometry: &Geometry, origin: Point3) -> Vec3 {
-        geometry.random(origin)
+    pub fn generate_background(&self, background: &Background, sampler: &mut Sampler) -> Vec3 {
+        background.random(sampler)
     }
 
-    pub fn value_mixture(&self, p0: &Box<PDF>, p1: &Box<PDF>, direction: Vec3) -> f32 {
-        return 0.5 * p0.value(direction) + 0.5 * p1.value(direction);
+    pub fn value_mixture(&self, first: &PDF, second: &PDF, weight: f32, direction: Vec3) -> f32 {
+        weight * first.value(direction) + (1.0 - weight) * second.value(direction)
     }
-    pub fn generate_mixture(&self, p0: &Box<PDF>, p1: &Box<PDF>) -> Vec3 {
-        return if random() < 0.5 { p0.generate() } else { p1.generate() };
+    pub fn generate_mixture(&self, first: &PDF, second: &PDF, weight: f32, sampler: &mut Sampler) -> Vec3 {
+        if sampler.get_1d() < weight {
+            first.generate(sampler)
+        } else {
+            second.generate(sampler)
+        }
     }
 
     pub fn value(&self, direction: Vec3) -> f32 {
         match &self.pdf_type {
-            PDFType::CosinePDF(uvw) => self.value_cosine(direction, uvw),
             PDFType::CollidablePDF(geometry, origin) => self.value_collidable(geometry, *origin, direction),
-            PDFType::MixturePDF(p0, p1) => self.value_mixture(p0, p1, direction),
-            _ => 0.0,
+            PDFType::BackgroundPDF(background) => self.value_background(background, direction),
+            PDFType::MixturePDF(first, second, weight) => self.value_mixture(first, second, *weight, direction),
         }
     }
-    pub fn generate(&self) -> Vec3 {
+    pub fn generate(&self, sampler: &mut Sampler) -> Vec3 {
         match &self.pdf_type {
-            PDFType::CosinePDF(uvw) => self.generate_cosine(uvw),
-            PDFType::CollidablePDF(geometry, origin) => self.generate_collidable(geometry, *origin),
-            PDFType::MixturePDF(p0, p1) => self.generate_mixture(p0, p1),
-            _ => Vec3::origin(),
+            PDFType::CollidablePDF(geometry, origin) => self.generate_collidable(geometry, *origin, sampler),
+            PDFType::BackgroundPDF(background) => self.generate_background(background, sampler),
+            PDFType::MixturePDF(first, second, weight) => self.generate_mixture(first, second, *weight, sampler),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,344 @@
+#[path = "obj.rs"] mod obj;
+pub use obj::*;
+use std::fs;
+
+
+#[derive(Clone, Copy)]
+enum PlyType {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "char" | "int8" => Some(PlyType::Int8),
+            "uchar" | "uint8" => Some(PlyType::UInt8),
+            "short" | "int16" => Some(PlyType::Int16),
+            "ushort" | "uint16" => Some(PlyType::UInt16),
+            "int" | "int32" => Some(PlyType::Int32),
+            "uint" | "uint32" => Some(PlyType::UInt32),
+            "float" | "float32" => Some(PlyType::Float32),
+            "double" | "float64" => Some(PlyType::Float64),
+            _ => None
+        }
+    }
+    fn size(&self) -> usize {
+        match self {
+            PlyType::Int8 | PlyType::UInt8 => 1,
+            PlyType::Int16 | PlyType::UInt16 => 2,
+            PlyType::Int32 | PlyType::UInt32 | PlyType::Float32 => 4,
+            PlyType::Float64 => 8,
+        }
+    }
+    // Largest value of unsigned integer types, which colors stored in them are scaled by
+    fn color_scale(&self) -> f32 {
+        match self {
+            PlyType::UInt8 => 255.0,
+            PlyType::UInt16 => 65535.0,
+            _ => 1.0
+        }
+    }
+}
+
+#[derive(Clone)]
+enum PlyProperty {
+    Scalar(PlyType, String),
+    // Types of the item count and of the items
+    List(PlyType, PlyType, String),
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+// Body of a PLY file: whitespace separated numbers, or packed binary values and whether they are
+// big-endian
+enum PlyBody<'a> {
+    Ascii(std::str::SplitWhitespace<'a>),
+    Binary(&'a [u8], bool),
+}
+
+impl<'a> PlyBody<'a> {
+    fn read(&mut self, value_type: PlyType) -> Result<f64, String> {
+        match self {
+            PlyBody::Ascii(words) => {
+                let word = words.next().ok_or("unexpected end of file".to_string())?;
+                word.parse::<f64>().map_err(|_| format!("expected a number, found '{}'", word))
+            },
+            PlyBody::Binary(data, big_endian) => {
+                let size = value_type.size();
+                if data.len() < size {
+                    return Err("unexpected end of file".to_string());
+                }
+                let mut bytes = [0u8; 8];
+                bytes[..size].copy_from_slice(&data[..size]);
+                if *big_endian {
+                    bytes[..size].reverse();
+                }
+                *data = &data[size..];
+                Ok(match value_type {
+                    PlyType::Int8 => bytes[0] as i8 as f64,
+                    PlyType::UInt8 => bytes[0] as f64,
+                    PlyType::Int16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+                    PlyType::UInt16 => u16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+                    PlyType::Int32 => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+                    PlyType::UInt32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+                    PlyType::Float32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+                    PlyType::Float64 => f64::from_le_bytes(bytes),
+                })
+            }
+        }
+    }
+}
+
+// Reads the header, returning the elements it declares, whether the body is binary and big-endian,
+// and where the body starts
+fn parse_ply_header(data: &[u8]) -> Result<(Vec<PlyElement>, Option<bool>, usize), String> {
+    let end = data.windows(10).position(|w| w == b"end_header").ok_or("missing end_header".to_string())?;
+    let body_start = match data[end..].iter().position(|&c| c == b'\n') {
+        Some(newline) => end + newline + 1,
+        None => data.len()
+    };
+    let header = String::from_utf8_lossy(&data[..end]);
+    let mut lines = header.lines();
+    if lines.next().map(|line| line.trim()) != Some("ply") {
+        return Err("not a PLY file".to_string());
+    }
+
+    let mut elements: Vec<PlyElement> = Vec::new();
+    let mut binary = None;
+    let mut format_found = false;
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["format", format, _version] => {
+                binary = match *format {
+                    "ascii" => None,
+                    "binary_little_endian" => Some(false),
+                    "binary_big_endian" => Some(true),
+                    _ => return Err(format!("unknown format '{}'", format))
+                };
+                format_found = true;
+            },
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: count.parse().map_err(|_| format!("invalid element count '{}'", count))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count_type, item_type, name] => {
+                let element = elements.last_mut().ok_or("property before any element".to_string())?;
+                let count_type = PlyType::parse(count_type).ok_or(format!("unknown type '{}'", count_type))?;
+                let item_type = PlyType::parse(item_type).ok_or(format!("unknown type '{}'", item_type))?;
+                element.properties.push(PlyProperty::List(count_type, item_type, name.to_string()));
+            },
+            ["property", value_type, name] => {
+                let element = elements.last_mut().ok_or("property before any element".to_string())?;
+                let value_type = PlyType::parse(value_type).ok_or(format!("unknown type '{}'", value_type))?;
+                element.properties.push(PlyProperty::Scalar(value_type, name.to_string()));
+            },
+            ["comment", ..] | ["obj_info", ..] | [] => (),
+            _ => return Err(format!("invalid header line '{}'", line.trim()))
+        }
+    }
+    if !format_found {
+        return Err("missing format line".to_string());
+    }
+    Ok((elements, binary, body_start))
+}
+
+impl Geometry {
+    // Loads a PLY file, ASCII or binary, scaled about the origin, into a mesh. Vertices give their
+    // position and may give a normal, texture coordinates and a color (integer colors are scaled to
+    // [0, 1]); faces are split into fans of triangles. Without a material the mesh is lambertian,
+    // colored by its vertex colors if it has them and light gray otherwise. Other elements, such as
+    // edges, are skipped.
+    pub fn load_ply(path: &str, scale: f32, material: Option<Material>) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let (elements, binary, body_start) = parse_ply_header(&data).map_err(|e| format!("{}: {}", path, e))?;
+        let text;
+        let mut body = match binary {
+            Some(big_endian) => PlyBody::Binary(&data[body_start..], big_endian),
+            None => {
+                text = String::from_utf8_lossy(&data[body_start..]);
+                PlyBody::Ascii(text.split_whitespace())
+            }
+        };
+
+        let (mut positions, mut normals, mut uvs, mut colors) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        let mut triangles = Vec::new();
+        for element in elements.iter() {
+            let (mut has_normal, mut has_uv, mut has_color) = (false, false, false);
+            for _ in 0..element.count {
+                let (mut position, mut normal, mut uv, mut color) = ([0.0f32; 3], [0.0f32; 3], [0.0f32; 2], [1.0f32; 3]);
+                for property in element.properties.iter() {
+                    match property {
+                        PlyProperty::Scalar(value_type, name) => {
+                            let value = body.read(*value_type).map_err(|e| format!("{}: {}", path, e))? as f32;
+                            if element.name != "vertex" {
+                                continue;
+                            }
+                            match name.as_str() {
+                                "x" => position[0] = value,
+                                "y" => position[1] = value,
+                                "z" => position[2] = value,
+                                "nx" | "ny" | "nz" => {
+                                    normal[match name.as_str() { "nx" => 0, "ny" => 1, _ => 2 }] = value;
+                                    has_normal = true;
+                                },
+                                "u" | "s" | "texture_u" | "texture_s" => { uv[0] = value; has_uv = true; },
+                                "v" | "t" | "texture_v" | "texture_t" => { uv[1] = value; has_uv = true; },
+                                "red" | "diffuse_red" => { color[0] = value / value_type.color_scale(); has_color = true; },
+                                "green" | "diffuse_green" => { color[1] = value / value_type.color_scale(); has_color = true; },
+                                "blue" | "diffuse_blue" => { color[2] = value / value_type.color_scale(); has_color = true; },
+                                _ => ()
+                            }
+                        },
+                        PlyProperty::List(count_type, item_type, name) => {
+                            // The count comes from the file, so nothing is reserved for it up front;
+                            // reading stops at the end of the body instead
+                            let count = body.read(*count_type).map_err(|e| format!("{}: {}", path, e))? as usize;
+                            let mut items = Vec::new();
+                            for _ in 0..count {
+                                items.push(body.read(*item_type).map_err(|e| format!("{}: {}", path, e))?);
+                            }
+                            if element.name != "face" || (name != "vertex_indices" && name != "vertex_index") {
+                                continue;
+                            }
+                            if let Some(&bad) = items.iter().find(|&&i| i < 0.0 || i as usize >= positions.len()) {
+                                return Err(format!("{}: face refers to vertex {}, but there are {}", path, bad, positions.len()));
+                            }
+                            for i in 2..items.len() {
+                                triangles.push([items[0] as u32, items[i - 1] as u32, items[i] as u32]);
+                            }
+                        }
+                    }
+                }
+                if element.name == "vertex" {
+                    positions.push(Point3::new(position[0], position[1], position[2]) * scale);
+                    // Zero normals count as missing, as in the other loaders
+                    let normal = Vec3::new(normal[0], normal[1], normal[2]);
+                    normals.push(if normal.length_squared() > 0.0 { normal.normalize() } else { normal });
+                    uvs.push((uv[0], uv[1]));
+                    colors.push(Rgb::new(color[0], color[1], color[2]));
+                }
+            }
+            if element.name == "vertex" {
+                if !has_normal { normals.clear(); }
+                if !has_uv { uvs.clear(); }
+                if !has_color { colors.clear(); }
+            }
+        }
+
+        let material = material.unwrap_or(if colors.is_empty() {
+            Material::lambertian(Texture::solid_color(Rgb::new(0.8, 0.8, 0.8)))
+        } else {
+            Material::lambertian(Texture::vertex_color())
+        });
+        let mesh = MeshData::new(positions, normals, uvs, colors, triangles, vec![material], Vec::new()).map_err(|e| format!("{}: {}", path, e))?;
+        if mesh.triangle_count() == 0 {
+            return Err(format!("{}: no triangles", path));
+        }
+        Ok(Geometry::mesh(mesh))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, data: &[u8]) -> Result<std::sync::Arc<MeshData>, String> {
+        load_test_mesh(&[(&format!("{}.ply", name), data)], |path| Geometry::load_ply(path, 1.0, None))
+    }
+    fn header(format: &str, vertices: usize, faces: usize) -> String {
+        format!("ply\nformat {} 1.0\nelement vertex {}\nproperty float x\nproperty float y\nproperty float z\nelement face {}\nproperty list uchar int vertex_indices\nend_header\n", format, vertices, faces)
+    }
+
+    #[test]
+    fn parses_header_elements_and_format() {
+        let data = b"ply\nformat binary_big_endian 1.0\ncomment made by hand\nelement vertex 3\nproperty float x\nelement face 1\nproperty list uchar uint vertex_indices\nend_header\nrest";
+        let (elements, binary, body_start) = parse_ply_header(data).unwrap();
+        assert_eq!(binary, Some(true));
+        assert_eq!(&data[body_start..], b"rest");
+        assert_eq!(elements.iter().map(|e| (e.name.as_str(), e.count, e.properties.len())).collect::<Vec<_>>(), [("vertex", 3, 1), ("face", 1, 1)]);
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let error = |data: &str| parse_ply_header(data.as_bytes()).err().unwrap();
+        assert_eq!(error("ply\nformat ascii 1.0\nelement vertex 1\n"), "missing end_header");
+        assert_eq!(error("plx\nformat ascii 1.0\nend_header\n"), "not a PLY file");
+        assert_eq!(error("ply\nformat binary_middle_endian 1.0\nend_header\n"), "unknown format 'binary_middle_endian'");
+        assert_eq!(error("ply\nelement vertex 1\nend_header\n"), "missing format line");
+        assert_eq!(error("ply\nformat ascii 1.0\nproperty float x\nend_header\n"), "property before any element");
+        assert_eq!(error("ply\nformat ascii 1.0\nelement vertex many\nend_header\n"), "invalid element count 'many'");
+        assert_eq!(error("ply\nformat ascii 1.0\nelement vertex 1\nproperty real x\nend_header\n"), "unknown type 'real'");
+        assert_eq!(error("ply\nformat ascii 1.0\nelement vertex 1\nvertices follow\nend_header\n"), "invalid header line 'vertices follow'");
+    }
+
+    #[test]
+    fn loads_ascii_and_binary_bodies_alike() {
+        let ascii = header("ascii", 4, 1) + "0 0 0\n1 0 0\n1 1 0\n0 1 0\n4 0 1 2 3\n";
+        let mut little = header("binary_little_endian", 4, 1).into_bytes();
+        let mut big = header("binary_big_endian", 4, 1).into_bytes();
+        for v in [[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]].iter().flatten() {
+            little.extend(v.to_le_bytes());
+            big.extend(v.to_be_bytes());
+        }
+        little.push(4);
+        big.push(4);
+        for i in 0..4i32 {
+            little.extend(i.to_le_bytes());
+            big.extend(i.to_be_bytes());
+        }
+        let ascii = load("ascii", ascii.as_bytes()).unwrap();
+        // The quad is split into a fan around its first corner
+        let mut triangles = ascii.triangles.clone();
+        triangles.sort();
+        assert_eq!(triangles, [[0, 1, 2], [0, 2, 3]]);
+        for (name, data) in [("little", little), ("big", big)] {
+            let mesh = load(name, &data).unwrap();
+            assert!(mesh.positions.len() == ascii.positions.len() && mesh.positions.iter().zip(ascii.positions.iter()).all(|(a, b)| (*a - *b).length() == 0.0), "{}", name);
+            assert_eq!(mesh.triangles, ascii.triangles, "{}", name);
+        }
+    }
+
+    #[test]
+    fn scales_integer_colors() {
+        let data = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\nproperty uchar red\nproperty uchar green\nproperty uchar blue\nelement face 1\nproperty list uchar int vertex_index\nend_header\n0 0 0 255 0 0\n1 0 0 0 255 0\n0 1 0 0 0 51\n3 0 1 2\n";
+        let mesh = load("colors", data.as_bytes()).unwrap();
+        assert_eq!(mesh.colors.len(), 3);
+        assert!((mesh.colors[2] - Rgb::new(0.0, 0.0, 0.2)).length() < 1e-6);
+        assert!(mesh.normals.is_empty() && mesh.uvs.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        let error = |name: &str, data: Vec<u8>| load(name, &data).err().unwrap();
+        assert!(error("bad_index", (header("ascii", 3, 1) + "0 0 0\n1 0 0\n0 1 0\n3 0 1 3\n").into_bytes()).ends_with("face refers to vertex 3, but there are 3"));
+        assert!(error("negative_index", (header("ascii", 3, 1) + "0 0 0\n1 0 0\n0 1 0\n3 0 1 -1\n").into_bytes()).ends_with("face refers to vertex -1, but there are 3"));
+        assert!(error("not_a_number", (header("ascii", 3, 1) + "0 0 0\n1 zero 0\n").into_bytes()).ends_with("expected a number, found 'zero'"));
+        assert!(error("short_ascii", (header("ascii", 3, 1) + "0 0 0\n1 0 0\n").into_bytes()).ends_with("unexpected end of file"));
+        assert!(error("no_faces", (header("ascii", 3, 0) + "0 0 0\n1 0 0\n0 1 0\n").into_bytes()).ends_with("no triangles"));
+
+        let mut truncated = header("binary_little_endian", 3, 1).into_bytes();
+        truncated.extend([0u8; 3 * 12 - 1]);
+        assert!(error("truncated", truncated).ends_with("unexpected end of file"));
+
+        // A list count far beyond the size of the file
+        let mut huge = header("binary_little_endian", 3, 1).replace("list uchar", "list uint").into_bytes();
+        huge.extend([0u8; 3 * 12]);
+        huge.extend(u32::MAX.to_le_bytes());
+        huge.extend([0u8; 8]);
+        assert!(error("huge_list", huge).ends_with("unexpected end of file"));
+    }
+}
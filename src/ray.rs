@@ -7,6 +7,8 @@ pub struct Ray {
     pub origin: Point3,
     pub direction: Vec3,
     pub time: f32,
+    // Hero wavelength in nanometres of a spectral path, 0 when rendering in RGB
+    pub wavelength: f32,
 }
 
 impl Ray {
@@ -14,7 +16,8 @@ impl Ray {
         Self {
             origin: origin,
             direction: direction,
-            time: time
+            time: time,
+            wavelength: 0.0
         }
     }
     pub fn reset(&mut self, origin: Point3, direction: Vec3, time: f32) {
@@ -1,63 +1,207 @@
 use image::{self, ImageBuffer};
 use indicatif::{ProgressBar, ProgressStyle};
-use std::time::SystemTime;
+use std::time::{SystemTime, Instant};
 use std::sync::Mutex;
-use linya::{Bar, Progress};
+use std::ops::Range;
 use num_cpus;
 use math::round::half_up;
-#[path = "camera.rs"] mod camera;
-pub use camera::*;
+#[path = "film.rs"] mod film;
+pub use film::*;
 use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
 
 
 
 
-pub fn ray_color(r: Ray, background: Rgb, scene: &SceneColliders, lights: &Geometry, depth: usize) -> Rgb {
-    if depth <= 0 {
+#[derive(Clone)]
+pub struct RenderSettings {
+    pub img_width: u32,
+    pub img_height: u32,
+    pub samples_per_pixel: usize,
+    pub max_depth: usize,
+    pub n_threads: usize,
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    pub pass_samples: usize,
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: u64,
+    // Relative error below which a pixel stops sampling; 0 disables adaptive sampling
+    pub adaptive_threshold: f32,
+    // Samples every pixel takes before its error estimate is trusted
+    pub min_samples: usize,
+    // Global seed every pixel sample derives its random numbers from
+    pub seed: u64,
+    pub sampler: SamplerType,
+    pub color: ColorPipeline,
+    // Trace paths at sampled wavelengths instead of in RGB
+    pub spectral: bool,
+}
+
+impl RenderSettings {
+    pub fn new(img_width: u32, img_height: u32, samples_per_pixel: usize, max_depth: usize) -> Self {
+        Self {
+            img_width: img_width,
+            img_height: img_height,
+            samples_per_pixel: samples_per_pixel,
+            max_depth: max_depth,
+            n_threads: num_cpus::get(),
+            tile_size: 32,
+            tile_order: TileOrder::Scanline,
+            pass_samples: 16,
+            checkpoint: None,
+            checkpoint_interval: 300,
+            adaptive_threshold: 0.0,
+            min_samples: 16,
+            seed: 0,
+            sampler: SamplerType::Independent,
+            color: ColorPipeline::default(),
+            spectral: false,
+        }
+    }
+}
+
+
+
+// A color as the path carries it: unchanged in RGB, as its spectrum's values at the path's wavelengths
+// in spectral mode
+fn spectrum(color: Rgb, wavelengths: &Option<Wavelengths>) -> Vec3 {
+    match wavelengths {
+        Some(wavelengths) => wavelengths.upsample(color),
+        None => color
+    }
+}
+
+// Power heuristic (Veach 1997) weight of a strategy with density `pdf_a` against one with density `pdf_b`
+fn power_heuristic(pdf_a: f32, pdf_b: f32) -> f32 {
+    let (a, b) = (pdf_a * pdf_a, pdf_b * pdf_b);
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+// Light sampling distribution at `origin`: the emissive geometry, the background if it emits, or an
+// even mix of the two. None when there is nothing to aim for.
+fn light_pdf(scene: &SceneColliders, background: &Background, origin: Point3) -> Option<PDF> {
+    let geometry = if scene.light_count() > 0 { Some(PDF::collidable_pdf(scene.lights.clone(), origin)) } else { None };
+    let environment = if background.is_light() { Some(PDF::background_pdf(background.clone())) } else { None };
+    match (geometry, environment) {
+        (Some(geometry), Some(environment)) => Some(PDF::mixture_pdf(geometry, environment, 0.5)),
+        (Some(geometry), None) => Some(geometry),
+        (None, Some(environment)) => Some(environment),
+        (None, None) => None
+    }
+}
+
+// Next event estimation: the light a shadow ray towards a point sampled on the lights finds, weighted
+// against the chance the material had of scattering there. Whatever the ray reaches first is what
+// it sees, a light, geometry blocking it or the background.
+fn sample_light(r_in: Ray, rec: &HitRecord, light_pdf: &PDF, scene: &SceneColliders, background: &Background, wavelengths: &Option<Wavelengths>, sampler: &mut Sampler) -> Rgb {
+    let dir = light_pdf.generate(sampler);
+    let pdf = light_pdf.value(dir);
+    if pdf <= 0.0 {
         return Rgb::origin();
     }
+    let mut shadow = Ray::new(rec.point, dir, r_in.time);
+    let f = rec.material.eval(r_in, rec, &shadow, &scene.atlas);
+    if f.x <= 0.0 && f.y <= 0.0 && f.z <= 0.0 {
+        return Rgb::origin();
+    }
+    let emitted = match scene.intersect(shadow, 0.001, f32::MAX) {
+        Some(light_rec) => light_rec.material.emitted(&light_rec, &scene.atlas),
+        None => background.radiance(dir, &scene.atlas)
+    };
+    let weight = power_heuristic(pdf, rec.material.scattering_pdf(r_in, rec.clone(), &mut shadow));
+    spectrum(f, wavelengths) * spectrum(emitted, wavelengths) * (weight / pdf)
+}
+
+// Light reaching `rec` from every point, spot and directional light that isn't blocked. Nothing can
+// hit these lights by chance, so they are always sampled and need no weighting.
+fn sample_delta_lights(r_in: Ray, rec: &HitRecord, scene: &SceneColliders, wavelengths: &Option<Wavelengths>) -> Rgb {
+    let mut radiance = Rgb::origin();
+    for light in scene.delta_lights.iter() {
+        let (wi, dist, incident) = light.illuminate(rec.point);
+        if incident.x <= 0.0 && incident.y <= 0.0 && incident.z <= 0.0 {
+            continue;
+        }
+        let shadow = Ray::new(rec.point, wi, r_in.time);
+        let f = rec.material.eval(r_in, rec, &shadow, &scene.atlas);
+        if f.x <= 0.0 && f.y <= 0.0 && f.z <= 0.0 {
+            continue;
+        }
+        if scene.occluded(shadow, 0.001, dist * (1.0 - 1e-4)) {
+            continue;
+        }
+        radiance = radiance + spectrum(f, wavelengths) * spectrum(incident, wavelengths);
+    }
+    return radiance;
+}
+
+// Traces one camera path. At every non-specular hit one direction is drawn towards the lights and one
+// from the material, and the emission each of them finds is combined with the power heuristic, so
+// small lights are found by light sampling and reflections of large ones by the material. Specular
+// bounces skip light sampling and see emission at full weight. Emissive backgrounds count as lights,
+// and point, spot and directional lights are added at every non-specular hit.
+// A ray with a wavelength starts a spectral path: colors are turned into spectra as the path meets
+// them, and the radiance found at its wavelengths is converted back to RGB at the end. Dispersive
+// materials send each wavelength its own way, so past them only the hero wavelength is followed.
+pub fn ray_color(r: Ray, background: &Background, scene: &SceneColliders, depth: usize, sampler: &mut Sampler) -> Rgb {
+    let wavelengths = if r.wavelength > 0.0 { Some(Wavelengths::new(r.wavelength)) } else { None };
+    let mut radiance = Rgb::origin();
+    let mut throughput = Rgb::new(1.0, 1.0, 1.0);
+    let mut ray = r;
+    let mut dispersed = false;
+    // Light sampling distribution at the previous hit and the density the material sampled `ray` with,
+    // used to weight the emission `ray` finds; None for camera rays and after specular bounces
+    let mut prev: Option<(PDF, f32)> = None;
 
-    let hit = scene.intersect(r, 0.001, f32::MAX);
-    match hit {
-        Some(rec) => {
-            let mut scattered = Ray::new(Vec3::origin(), Vec3::origin(), 0.0);
-            let mut attenuation = Vec3::origin();
-            let emitted = rec.material.emitted(rec.u, rec.v, rec.point, &scene.atlas);
-            let mut pdf: f32 = 0.0;
-            match rec.material.scatter(r, &mut attenuation, rec.clone(), &mut scattered, &mut pdf, &scene.atlas) {
-                true => {
-                    let p0 = PDF::collidable_pdf(lights.clone(), rec.point);
-                    let p1 = PDF::cosine_pdf(rec.normal);
-                    let mixture = PDF::mixture_pdf(p0, p1);
-                    scattered.reset(rec.point, mixture.generate(), r.time);
-                    let pdf_val = mixture.value(scattered.direction);
-                    return emitted + attenuation * rec.material.scattering_pdf(r, rec.clone(), &mut scattered) * ray_color(scattered, background, scene, lights, depth - 1) / pdf_val
-                },
-                false => return emitted
+    for bounce in 0..depth {
+        let weight = match &prev {
+            Some((light_pdf, bsdf_pdf)) => power_heuristic(*bsdf_pdf, light_pdf.value(ray.direction)),
+            None => 1.0
+        };
+        let rec = match scene.intersect(ray, 0.001, f32::MAX) {
+            Some(rec) => rec,
+            None => {
+                radiance = radiance + throughput * spectrum(background.radiance(ray.direction, &scene.atlas), &wavelengths) * weight;
+                break;
             }
-        },
-        None => { return background }
-    };
+        };
+        sampler.next_bounce();
+
+        let emitted = rec.material.emitted(&rec, &scene.atlas);
+        radiance = radiance + throughput * spectrum(emitted, &wavelengths) * weight;
+
+        let srec = match rec.material.scatter(ray, &rec, &scene.atlas, sampler) {
+            Some(srec) => srec,
+            None => break
+        };
+        prev = None;
+        if !srec.is_specular && bounce + 1 < depth {
+            radiance = radiance + throughput * sample_delta_lights(ray, &rec, scene, &wavelengths);
+            if let Some(light_pdf) = light_pdf(scene, background, rec.point) {
+                radiance = radiance + throughput * sample_light(ray, &rec, &light_pdf, scene, background, &wavelengths, sampler);
+                prev = Some((light_pdf, srec.pdf));
+            }
+        }
+        throughput = throughput * spectrum(srec.attenuation, &wavelengths);
+        if wavelengths.is_some() && !dispersed && rec.material.is_dispersive() {
+            // The companions are dropped and the hero stands in for all three wavelengths
+            throughput = throughput * Vec3::new(3.0, 0.0, 0.0);
+            dispersed = true;
+        }
+        ray = srec.scattered;
+        ray.wavelength = r.wavelength;
+    }
+    match &wavelengths {
+        Some(wavelengths) => wavelengths.to_rgb(radiance),
+        None => radiance
+    }
 }
 
-pub fn write_color(imbuf: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>, x: u32, y: u32, pixel_color: Rgb, samples_per_pixel: usize) {
-    let mut r = pixel_color.x;
-    let mut g = pixel_color.y;
-    let mut b  = pixel_color.z;
-    
+pub fn write_color(imbuf: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>, x: u32, y: u32, pixel_color: Rgb, samples_per_pixel: usize, pipeline: &ColorPipeline) {
     let scale = 1.0 / samples_per_pixel as f32;
-    r = (scale * r).sqrt();
-    g = (scale * g).sqrt();
-    b = (scale * b).sqrt();
-    
-    let ir = (256.0 * clamp(r, 0.0, 0.999)) as u8;
-    let ig = (256.0 * clamp(g, 0.0, 0.999)) as u8;
-    let ib = (256.0 * clamp(b, 0.0, 0.999)) as u8;
-
-    imbuf.put_pixel(x, y, image::Rgb([ir, ig, ib]));
+    imbuf.put_pixel(x, y, image::Rgb(pipeline.quantize(pixel_color * scale)));
 }
 
-pub fn render(mut imgbuf: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>, scene: &SceneColliders, lights: Geometry, cam: &Camera, background: Rgb, max_depth: usize, samples_per_pixel: usize) {
+pub fn render(mut imgbuf: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>, scene: &SceneColliders, cam: &Camera, background: &Background, max_depth: usize, samples_per_pixel: usize, pipeline: &ColorPipeline) {
     let img_width = imgbuf.width();
     let img_height = imgbuf.height();
     let bar = ProgressBar::new(img_width as u64 * img_height as u64);
@@ -69,14 +213,16 @@ pub fn render(mut imgbuf: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>, scene: &Sce
         for i in 0..img_width {
             let mut pixel_color = Rgb::origin();
             
-            for _ in 0..samples_per_pixel {
-                let u = (i as f32 + random()) / (img_width as f32 - 1.0);
-                let v = (j as f32 + random()) / (img_height as f32 - 1.0);
-                let r = cam.get_ray(u, v);
-                pixel_color = pixel_color + ray_color(r, background, &scene, &lights, max_depth);
+            for s in 0..samples_per_pixel {
+                let mut sampler = Sampler::new(SamplerType::Independent, 0, (j * img_width + i) as u64, s as u32, samples_per_pixel as u32);
+                let (du, dv) = sampler.get_2d();
+                let u = (i as f32 + du) / (img_width as f32 - 1.0);
+                let v = (j as f32 + dv) / (img_height as f32 - 1.0);
+                let r = cam.get_ray(u, v, &mut sampler);
+                pixel_color = pixel_color + ray_color(r, background, &scene, max_depth, &mut sampler);
             }
 
-            write_color(&mut imgbuf, i, img_height - j - 1, pixel_color, samples_per_pixel);
+            write_color(&mut imgbuf, i, img_height - j - 1, pixel_color, samples_per_pixel, pipeline);
             bar.inc(1);
         }
         bar.set_message(format!("{}", j));
@@ -86,80 +232,124 @@ pub fn render(mut imgbuf: &mut ImageBuffer<image::Rgb<u8>, Vec<u8>>, scene: &Sce
 
 
 
-pub fn render_worker(background: Rgb, img_width: u32, img_height: u32, samples_per_thread: usize, thread_cam: &Camera, thread_scene: &SceneColliders, lights: &Geometry, max_depth: usize, n_threads: usize, bar: &Bar, progress: &Mutex<Progress>) -> ImageBuffer<image::Rgb<f32>, Vec<f32>> {
-    let mut subimage = ImageBuffer::new(img_width, img_height);
-    for j in (0..img_height).rev() {
-        for i in 0..img_width {
-            let mut pixel_color = Rgb::origin();
-            
-            for _ in 0..samples_per_thread {
-                let u = (i as f32 + random()) / (img_width as f32 - 1.0);
-                let v = (j as f32 + random()) / (img_height as f32 - 1.0);
-                let r = thread_cam.get_ray(u, v);
-                pixel_color = pixel_color + ray_color(r, background, &thread_scene, lights, max_depth);
-            }
+pub fn sample_is_finite(color: Rgb) -> bool {
+    color.x.is_finite() && color.y.is_finite() && color.z.is_finite()
+}
 
-            let mut r = pixel_color.x;
-            let mut g = pixel_color.y;
-            let mut b  = pixel_color.z;
+// Renders every pixel of the tile with the range of sample indices given for it in the image sized
+// `samples` buffer and returns the statistics of the new samples per pixel in scanline order. Each
+// sample draws from its own `settings.sampler` seeded by `settings.seed`, the pixel and the sample index, so
+// the result is bit for bit the same for any tiling and thread count (and, up to floating point
+// rounding of the sums, for any pass size or resume point). Non-finite samples
+// are replaced by black so a single bad path cannot poison the whole pixel.
+pub fn render_tile(tile: &Tile, samples: &[Range<u32>], cam: &Camera, scene: &SceneColliders, background: &Background, settings: &RenderSettings) -> Vec<PixelStats> {
+    let (img_width, img_height) = (settings.img_width, settings.img_height);
+    let mut stats = Vec::with_capacity(tile.pixel_count());
 
-            if r != r { r = 0.0 };
-            if g != g { g = 0.0 };
-            if b != b { b = 0.0 };
-            
-            let scale = 1.0 / samples_per_thread as f32;
-            r = (scale * r).sqrt();
-            g = (scale * g).sqrt();
-            b = (scale * b).sqrt();
-            
-            let ir = 256.0 * clamp(r / n_threads as f32, 0.0, 0.999);
-            let ig = 256.0 * clamp(g / n_threads as f32, 0.0, 0.999);
-            let ib = 256.0 * clamp(b / n_threads as f32, 0.0, 0.999);
+    for y in tile.y0..tile.y1 {
+        let j = img_height - y - 1;
+        for i in tile.x0..tile.x1 {
+            let mut pixel = PixelStats::new();
+
+            let idx = y * img_width + i;
+            for s in samples[idx as usize].clone() {
+                let mut sampler = Sampler::new(settings.sampler, settings.seed, idx as u64, s, settings.samples_per_pixel as u32);
+                let (du, dv) = sampler.get_2d();
+                let u = (i as f32 + du) / (img_width as f32 - 1.0);
+                let v = (j as f32 + dv) / (img_height as f32 - 1.0);
+                let mut r = cam.get_ray(u, v, &mut sampler);
+                if settings.spectral {
+                    r.wavelength = Wavelengths::sample_hero(sampler.get_1d());
+                }
+                let sample = ray_color(r, background, scene, settings.max_depth, &mut sampler);
+                // Discarded samples still count, so every pixel reaches its budget
+                pixel.add_sample(if sample_is_finite(sample) { sample } else { Rgb::origin() });
+            }
 
-            subimage.put_pixel(i, img_height - j - 1, image::Rgb([ir, ig, ib]));
+            stats.push(pixel);
         }
-        progress.lock().unwrap().inc_and_draw(bar, 1);
     }
-    return subimage;
+    return stats;
 }
 
 
 
-pub fn render_multi(scene: SceneColliders, lights: Geometry, cam: Camera, background: Rgb, max_depth: usize, samples_per_pixel: usize, img_width: u32, img_height: u32) -> ImageBuffer<image::Rgb<u8>, Vec<u8>> {
-    let n_threads = num_cpus::get();
-    let samples_per_thread = samples_per_pixel / n_threads;
-    let global_buf: Mutex<ImageBuffer<image::Rgb<f32>, Vec<f32>>> = Mutex::new(ImageBuffer::new(img_width, img_height));
+// Renders progressively in passes of `settings.pass_samples` samples per pixel until every pixel of
+// `film` has `settings.samples_per_pixel` samples, so a film loaded from a checkpoint continues where
+// it left off. With a positive `settings.adaptive_threshold`, pixels past `settings.min_samples`
+// whose relative error falls below the threshold are skipped by later passes, and rendering ends
+// once every pixel has converged or reached the sample budget. Within a pass the frame is split into
+// tiles that are handed out in `settings.tile_order` to a rayon pool; idle workers steal the next
+// pending tile and add it to the shared film, so memory stays at one framebuffer regardless of the
+// thread count. The film is written to `settings.checkpoint` after the last pass and whenever
+// `settings.checkpoint_interval` seconds have passed since the previous checkpoint.
+pub fn render_multi(scene: SceneColliders, cam: Camera, background: Background, settings: &RenderSettings, film: Film) -> Film {
+    let (img_width, img_height) = (settings.img_width, settings.img_height);
+    let pool = ThreadPoolBuilder::new().num_threads(settings.n_threads.max(1)).build().unwrap();
+    let tiles = generate_tiles(img_width, img_height, settings.tile_size, settings.tile_order);
     let time_start = SystemTime::now();
-    let progress = Mutex::new(Progress::new());
-    
-    (0..n_threads).into_par_iter().for_each(|i| {
-        let bar = progress.lock().unwrap().bar(img_height as usize, format!("Rendering [Thread {}]", i + 1));
-        let subimage = render_worker(
-            background, img_width, img_height, samples_per_thread, &cam, 
-            &scene, &lights, max_depth, n_threads, &bar, &progress
-        );
-        let mut imgdata = global_buf.lock().unwrap();
-        for j in 0..img_width {
-            for k in 0..img_height {
-                let p = imgdata.get_pixel_mut(j, k);
-                let pix = *subimage.get_pixel(j, k);
-                p.0[0] += pix.0[0];
-                p.0[1] += pix.0[1];
-                p.0[2] += pix.0[2];
+
+    let target = settings.samples_per_pixel.max(1) as u32;
+    let min_samples = settings.min_samples.max(2) as u32;
+    let pass_samples = if settings.pass_samples == 0 { target } else { settings.pass_samples as u32 };
+    let film = Mutex::new(film);
+
+    // Progress counts the samples of converged pixels as done, so the bar reaches the end early
+    // rather than stalling when adaptive sampling cuts pixels short
+    let budget = target as u64 * img_width as u64 * img_height as u64;
+    let bar = ProgressBar::new(budget);
+    bar.set_style(ProgressStyle::with_template(
+        "Rendering... | {percent}% | Elapsed: {elapsed} | ETA: {eta} | Samples: {msg}\n{bar:100.white/white}"
+    ).unwrap());
+    let mut last_checkpoint = Instant::now();
+
+    loop {
+        // Sample indices each pixel renders in this pass, never overshooting its budget
+        let (samples, progress, done) = {
+            let film = film.lock().unwrap();
+            let samples: Vec<Range<u32>> = (0..film.pixels.len()).map(|idx| {
+                let first = film.pixels[idx].samples;
+                if film.needs_samples(idx, target, min_samples, settings.adaptive_threshold) {
+                    first..first + pass_samples.min(target - first)
+                } else {
+                    first..first
+                }
+            }).collect();
+            let progress: u64 = film.pixels.iter().zip(samples.iter()).map(|(p, s)| if s.is_empty() { target as u64 } else { p.samples as u64 }).sum();
+            (samples, progress, film.min_samples().min(target))
+        };
+        let n_active = samples.iter().filter(|s| !s.is_empty()).count();
+        bar.set_position(progress);
+        bar.set_message(format!("{}/{} ({} pixels active)", done, target, n_active));
+        if n_active == 0 {
+            break;
+        }
+
+        pool.install(|| tiles.iter().par_bridge().for_each(|tile| {
+            let stats = render_tile(tile, &samples, &cam, &scene, &background, settings);
+            film.lock().unwrap().add_tile(tile, &stats);
+        }));
+
+        if let Some(path) = &settings.checkpoint {
+            if last_checkpoint.elapsed().as_secs() >= settings.checkpoint_interval {
+                if let Err(e) = film.lock().unwrap().save_checkpoint(path) {
+                    bar.println(format!("warning: failed to write checkpoint: {}", e));
+                }
+                last_checkpoint = Instant::now();
             }
         }
-    });
-    
-    let imgbuf_f32 = global_buf.lock().unwrap().clone();
-    let mut finalbuf = ImageBuffer::new(img_width, img_height);
-    
-    for (x, y, pixel) in finalbuf.enumerate_pixels_mut() {
-        let pix = imgbuf_f32.get_pixel(x, y);
-        *pixel = image::Rgb([pix.0[0] as u8, pix.0[1] as u8, pix.0[2] as u8]);
     }
-    
+    bar.finish();
+
+    let film = film.into_inner().unwrap();
+    if let Some(path) = &settings.checkpoint {
+        if let Err(e) = film.save_checkpoint(path) {
+            eprintln!("warning: failed to write checkpoint: {}", e);
+        }
+    }
+
     let render_time = SystemTime::now().duration_since(time_start).unwrap().as_secs();
     println!("\nRendered in {}s ({}m)", render_time, half_up(render_time as f64 / 60.0, 2));
     
-    return finalbuf;
+    return film;
 }
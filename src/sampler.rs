@@ -0,0 +1,246 @@
+#[path = "utils.rs"] mod utils;
+pub use utils::*;
+
+
+// Dimensions consumed by the camera: pixel jitter (2), lens position (2), shutter time (1) and the
+// hero wavelength of spectral renders (1)
+const CAMERA_DIMENSIONS: u32 = 6;
+// Dimensions reserved for every bounce, so the dimension of a given decision does not depend on how
+// many numbers earlier bounces happened to draw
+const BOUNCE_DIMENSIONS: u32 = 8;
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+    137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223,
+    227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311,
+];
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SamplerType {
+    // Independent uniform random numbers
+    Independent,
+    // Jittered strata over the pixel's samples, shuffled independently per dimension
+    Stratified,
+    // Halton sequence with per pixel random digit permutations, independent numbers past the 64th dimension
+    Halton,
+    // Owen scrambled Sobol (0, 2) sequence, padded to higher dimensions by shuffling the sample order
+    Sobol,
+}
+
+impl SamplerType {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "independent" | "random" => Some(Self::Independent),
+            "stratified" => Some(Self::Stratified),
+            "halton" => Some(Self::Halton),
+            "sobol" => Some(Self::Sobol),
+            _ => None
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Independent => "independent",
+            Self::Stratified => "stratified",
+            Self::Halton => "halton",
+            Self::Sobol => "sobol",
+        }
+    }
+}
+
+
+fn to_unit_float(x: u32) -> f32 {
+    (x >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+}
+
+fn hash_u32(seed: u64, value: u64) -> u32 {
+    splitmix64(seed ^ splitmix64(value)) as u32
+}
+
+// Kensler's hash based permutation: element `i` of a random permutation of [0, l) chosen by `p`
+fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    return (i.wrapping_add(p)) % l;
+}
+
+// Burley, "Practical Hash-based Owen Scrambling" (2020)
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    return x;
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// First two dimensions of the Sobol sequence, as 32 bit fixed point fractions
+fn sobol_dim0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+fn sobol_dim1(mut index: u32) -> u32 {
+    let mut v = 1u32 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    return result;
+}
+
+fn scrambled_radical_inverse(base: u32, mut index: u32, seed: u64) -> f32 {
+    // Enough digits to resolve every f32 below one
+    let n_digits = (24.0 * 2f64.ln() / (base as f64).ln()).ceil() as u64;
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0;
+    let mut result = 0.0;
+    for digit_index in 0..n_digits {
+        let digit = index % base;
+        index /= base;
+        let permuted = permutation_element(digit, base, hash_u32(seed, digit_index));
+        inv_base_m *= inv_base;
+        result += permuted as f64 * inv_base_m;
+    }
+    return (result as f32).min(1.0 - f32::EPSILON / 2.0);
+}
+
+
+// Source of the random numbers of one camera path. Decisions that benefit from stratification draw
+// from numbered dimensions via `get_1d`/`get_2d`: the camera owns the first dimensions and every
+// bounce, started by `next_bounce`, gets a fixed block after that. `random` returns independent
+// numbers that consume no dimension, for rejection sampling and the like.
+#[derive(Clone)]
+pub struct Sampler {
+    pub sampler_type: SamplerType,
+    rng: Pcg32,
+    pixel_seed: u64,
+    sample_index: u32,
+    samples_per_pixel: u32,
+    dimension: u32,
+    bounce: u32,
+}
+
+impl Sampler {
+    // Sampler for sample `sample_index` of `pixel`. Every (seed, pixel, sample) triple draws the same
+    // numbers no matter how the work is scheduled, which makes renders reproducible.
+    pub fn new(sampler_type: SamplerType, seed: u64, pixel: u64, sample_index: u32, samples_per_pixel: u32) -> Self {
+        Self {
+            sampler_type: sampler_type,
+            rng: Pcg32::new(splitmix64(seed ^ splitmix64(sample_index as u64)), pixel),
+            pixel_seed: splitmix64(seed.wrapping_add(0x2545F4914F6CDD1D) ^ splitmix64(pixel)),
+            sample_index: sample_index,
+            samples_per_pixel: samples_per_pixel.max(1),
+            dimension: 0,
+            bounce: 0,
+        }
+    }
+
+    pub fn next_bounce(&mut self) {
+        self.dimension = CAMERA_DIMENSIONS + self.bounce * BOUNCE_DIMENSIONS;
+        self.bounce += 1;
+    }
+
+    fn dimension_seed(&self, dimension: u32) -> u64 {
+        splitmix64(self.pixel_seed ^ splitmix64(dimension as u64))
+    }
+
+    fn get_1d_stratified(&mut self, seed: u64) -> f32 {
+        let n = self.samples_per_pixel;
+        let stratum = permutation_element(self.sample_index % n, n, hash_u32(seed, (self.sample_index / n) as u64));
+        (stratum as f32 + self.rng.random()) / n as f32
+    }
+    fn get_2d_stratified(&mut self, seed: u64) -> (f32, f32) {
+        let n = self.samples_per_pixel;
+        let nx = (n as f32).sqrt().ceil() as u32;
+        let ny = n.div_ceil(nx);
+        let stratum = permutation_element(self.sample_index % n, nx * ny, hash_u32(seed, (self.sample_index / n) as u64));
+        let x = ((stratum % nx) as f32 + self.rng.random()) / nx as f32;
+        let y = ((stratum / nx) as f32 + self.rng.random()) / ny as f32;
+        (x.min(1.0 - f32::EPSILON / 2.0), y.min(1.0 - f32::EPSILON / 2.0))
+    }
+
+    fn get_1d_halton(&mut self, dimension: u32, seed: u64) -> f32 {
+        match PRIMES.get(dimension as usize) {
+            Some(base) => scrambled_radical_inverse(*base, self.sample_index, seed),
+            None => self.rng.random()
+        }
+    }
+
+    fn get_1d_sobol(&mut self, seed: u64) -> f32 {
+        let index = nested_uniform_scramble(self.sample_index, hash_u32(seed, 0));
+        to_unit_float(nested_uniform_scramble(sobol_dim0(index), hash_u32(seed, 1)))
+    }
+    fn get_2d_sobol(&mut self, seed: u64) -> (f32, f32) {
+        let index = nested_uniform_scramble(self.sample_index, hash_u32(seed, 0));
+        (
+            to_unit_float(nested_uniform_scramble(sobol_dim0(index), hash_u32(seed, 1))),
+            to_unit_float(nested_uniform_scramble(sobol_dim1(index), hash_u32(seed, 2)))
+        )
+    }
+
+    pub fn get_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let seed = self.dimension_seed(dimension);
+        match self.sampler_type {
+            SamplerType::Independent => self.rng.random(),
+            SamplerType::Stratified => self.get_1d_stratified(seed),
+            SamplerType::Halton => self.get_1d_halton(dimension, seed),
+            SamplerType::Sobol => self.get_1d_sobol(seed),
+        }
+    }
+    pub fn get_2d(&mut self) -> (f32, f32) {
+        let dimension = self.dimension;
+        self.dimension += 2;
+        let seed = self.dimension_seed(dimension);
+        match self.sampler_type {
+            SamplerType::Independent => (self.rng.random(), self.rng.random()),
+            SamplerType::Stratified => self.get_2d_stratified(seed),
+            SamplerType::Halton => {
+                let seed_y = self.dimension_seed(dimension + 1);
+                (self.get_1d_halton(dimension, seed), self.get_1d_halton(dimension + 1, seed_y))
+            },
+            SamplerType::Sobol => self.get_2d_sobol(seed),
+        }
+    }
+
+    pub fn random(&mut self) -> f32 {
+        self.rng.random()
+    }
+}
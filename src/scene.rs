@@ -0,0 +1,675 @@
+#[path = "gltf_import.rs"] mod gltf_import;
+pub use gltf_import::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+
+/*
+Scene description files are plain text, one statement per line. `#` starts a comment.
+
+    image <width> <height>
+    samples <samples per pixel>
+    depth <max depth>
+    background <r> <g> <b>
+    environment <path> [intensity <k>] [rotate <x|y|z> <degrees>]...
+    sky [elevation <degrees>] [azimuth <degrees>] [turbidity <t>] [intensity <k>] [sun_size <degrees>]
+    camera from <x> <y> <z> at <x> <y> <z> [up <x> <y> <z>] [fov <degrees>] [aperture <a>] [focus <dist>] [shutter <t0> <t1>]
+
+    texture <name> solid <r> <g> <b>
+    texture <name> checkered <r> <g> <b> <r> <g> <b>
+    texture <name> noise <scale> <turbulence>
+    texture <name> image <path>
+    texture <name> vertex_color
+
+    material <name> lambertian <texture | r g b>
+    material <name> glossy <r> <g> <b> <fuzz>
+    material <name> dielectric <refraction index | bk7 | fused_silica | flint | diamond | cauchy <a> <b>
+        | sellmeier <b1> <b2> <b3> <c1> <c2> <c3>>
+    material <name> conductor <gold | copper | aluminium | silver | eta_r eta_g eta_b k_r k_g k_b> <roughness>
+    material <name> rough_dielectric <refraction index> <roughness>
+    material <name> isotropic <r> <g> <b>
+    material <name> emissive <texture | r g b> [intensity <k>] [temperature <kelvin>] [power <watts>] [lumens <lm>]
+        [sides <front | back | both>]
+    material <name> principled <texture | r g b> [metallic <v>] [roughness <v>] [specular <v>] [specular_tint <v>]
+        [sheen <v>] [sheen_tint <v>] [clearcoat <v>] [clearcoat_gloss <v>] [transmission <v>] [ior <v>]
+
+Geometry statements add an object to the scene:
+
+    sphere <x> <y> <z> <radius> <material>
+    moving_sphere <x0> <y0> <z0> <x1> <y1> <z1> <t0> <t1> <radius> <material>
+    xyrect <x0> <x1> <y0> <y1> <k> <material>
+    xzrect <x0> <x1> <z0> <z1> <k> <material>
+    yzrect <y0> <y1> <z0> <z1> <k> <material>
+    cuboid <x0> <y0> <z0> <x1> <y1> <z1> <material>
+    triangle <x0> <y0> <z0> <x1> <y1> <z1> <x2> <y2> <z2> <material>
+    obj <path> <scale> [<material>] [select <object or group>]...
+    ply <path> <scale> [<material>]
+    stl <path> <scale> [<material>]
+    gltf <path> [scale <s>] [camera <index>]
+    instance <name> [rotate <x|y|z> <degrees>]... [translate <x> <y> <z>]...
+    medium <name> <density> <r> <g> <b>
+
+Point, spot and directional lights have no shape and can't be seen, only lit by:
+
+    point_light <x> <y> <z> <r> <g> <b> [decay <n>]
+    spot_light <x> <y> <z> <tx> <ty> <tz> <r> <g> <b> [cone <degrees>] [falloff <degrees>] [decay <n>]
+    directional_light <dx> <dy> <dz> <r> <g> <b>
+
+`environment` replaces the background with an equirectangular image (such as an `.hdr` or `.exr`
+sky) that lights the scene, scaled by the intensity and turned by the rotations in order. `sky`
+replaces it with a procedural daylight sky and sun disk (by default at 45 degrees elevation and
+azimuth 0, along +z, with turbidity 3 and a 0.53 degree sun). Its radiance is in kcd/m^2, so sunlit
+scenes usually want a negative exposure or a small intensity.
+
+Dielectrics named after a glass, or given Cauchy or Sellmeier coefficients (for wavelengths in
+micrometres), have an index of refraction that varies with wavelength and disperse light when
+rendering with `--spectral`. In RGB they refract at the index for 589.3 nm.
+
+An emissive material's texture is scaled by the intensity and tinted by the color of a black body
+at the given temperature. Setting a power (or a luminous flux, at 683 lumens per watt) instead sizes
+the emission so that each object it is put on emits that much in total, taking scene units as
+metres. Surfaces emit from both sides by default; `sides front` keeps only the side the outward
+normal points to (the outside of spheres and boxes, the positive side of rectangles).
+
+`obj` loads the triangles of a Wavefront OBJ file with their vertex normals and texture coordinates.
+Without a material the faces use the materials of the file's MTL libraries. `select` loads only the
+faces of the named objects or groups.
+
+`ply` and `stl` load the triangles of a PLY (ASCII or binary) or STL (ASCII or binary) file. Without a
+material, PLY meshes with vertex colors are colored by them and other meshes are light gray. The
+`vertex_color` texture lets any material use the vertex colors of the mesh it is put on.
+
+`gltf` loads the meshes, metallic-roughness materials, embedded or referenced textures and punctual
+lights of a glTF 2.0 file (`.gltf` or `.glb`) with their node transforms, scaled about the origin.
+When the scene file has no `camera` statement the glTF camera with the given index (0 by default)
+is used instead, with the image's aspect ratio. It can't be used in a group or with `define`.
+
+Prefixing a geometry statement with `define <name>` stores it under that name instead of adding it.
+Geometry with an emissive material is found and sampled as a light automatically. The `light`
+prefix of older scene files is still accepted and adds the geometry like any other statement.
+Point and spot lights are given by their intensity and fall off with the distance raised to `decay`
+(2, the physical inverse square law, by default). A spot light at the first point aims at the
+second and lights a cone `cone` degrees wide (45 by default) that fades out over its outermost
+`falloff` degrees (5 by default). A directional light shines along the given direction with its
+color as the irradiance it delivers to a surface facing it.
+Statements between `group <name>` and `end` are collected into a BVH stored under that name.
+
+Relative paths are resolved against the directory containing the scene file.
+*/
+
+
+struct SceneParser {
+    base_dir: PathBuf,
+    line: usize,
+
+    image_width: Option<u32>,
+    image_height: Option<u32>,
+    samples_per_pixel: usize,
+    max_depth: usize,
+    background: Background,
+
+    look_from: Option<Point3>,
+    look_at: Option<Point3>,
+    vup: Vec3,
+    vfov: f32,
+    aperture: f32,
+    focus_dist: Option<f32>,
+    shutter: (f32, f32),
+    // Camera taken from a glTF file, used when there is no camera statement
+    gltf_camera: Option<Camera>,
+
+    textures: HashMap<String, Texture>,
+    materials: HashMap<String, Material>,
+    definitions: HashMap<String, Geometry>,
+    groups: Vec<(String, Vec<Geometry>)>,
+
+    scene: SceneColliders,
+}
+
+impl SceneParser {
+    fn new(base_dir: PathBuf) -> Self {
+        Self {
+            base_dir: base_dir,
+            line: 0,
+
+            image_width: None,
+            image_height: None,
+            samples_per_pixel: 100,
+            max_depth: 50,
+            background: Background::constant(Rgb::origin()),
+
+            look_from: None,
+            look_at: None,
+            vup: Vec3::new(0.0, 1.0, 0.0),
+            vfov: 40.0,
+            aperture: 0.0,
+            focus_dist: None,
+            shutter: (0.0, 1.0),
+            gltf_camera: None,
+
+            textures: HashMap::new(),
+            materials: HashMap::new(),
+            definitions: HashMap::new(),
+            groups: Vec::new(),
+
+            scene: SceneColliders::new(),
+        }
+    }
+
+    fn error(&self, msg: String) -> String {
+        format!("line {}: {}", self.line, msg)
+    }
+    fn resolve_path(&self, path: &str) -> String {
+        let p = Path::new(path);
+        if p.is_absolute() {
+            return path.to_string();
+        }
+        self.base_dir.join(p).to_string_lossy().into_owned()
+    }
+
+    fn next_token<'a>(&self, tokens: &mut std::slice::Iter<&'a str>, what: &str) -> Result<&'a str, String> {
+        match tokens.next() {
+            Some(tok) => Ok(*tok),
+            None => Err(self.error(format!("expected {}", what)))
+        }
+    }
+    fn next_f32(&self, tokens: &mut std::slice::Iter<&str>) -> Result<f32, String> {
+        let tok = self.next_token(tokens, "a number")?;
+        tok.parse::<f32>().map_err(|_| self.error(format!("expected a number, found '{}'", tok)))
+    }
+    fn next_usize(&self, tokens: &mut std::slice::Iter<&str>) -> Result<usize, String> {
+        let tok = self.next_token(tokens, "an integer")?;
+        tok.parse::<usize>().map_err(|_| self.error(format!("expected an integer, found '{}'", tok)))
+    }
+    fn next_vec3(&self, tokens: &mut std::slice::Iter<&str>) -> Result<Vec3, String> {
+        Ok(Vec3::new(self.next_f32(tokens)?, self.next_f32(tokens)?, self.next_f32(tokens)?))
+    }
+    fn next_axis(&self, tokens: &mut std::slice::Iter<&str>) -> Result<Axis, String> {
+        let tok = self.next_token(tokens, "an axis")?;
+        match tok.to_lowercase().as_str() {
+            "x" => Ok(Axis::X),
+            "y" => Ok(Axis::Y),
+            "z" => Ok(Axis::Z),
+            _ => Err(self.error(format!("expected an axis (x, y or z), found '{}'", tok)))
+        }
+    }
+    fn next_material(&self, tokens: &mut std::slice::Iter<&str>) -> Result<Material, String> {
+        let name = self.next_token(tokens, "a material name")?;
+        match self.materials.get(name) {
+            Some(mat) => Ok(mat.clone()),
+            None => Err(self.error(format!("unknown material '{}'", name)))
+        }
+    }
+    fn next_definition(&self, tokens: &mut std::slice::Iter<&str>) -> Result<Geometry, String> {
+        let name = self.next_token(tokens, "a geometry name")?;
+        match self.definitions.get(name) {
+            Some(geometry) => Ok(geometry.clone()),
+            None => Err(self.error(format!("unknown geometry '{}'", name)))
+        }
+    }
+    fn next_texture(&self, tokens: &mut std::slice::Iter<&str>) -> Result<Texture, String> {
+        let tok = *tokens.clone().next().ok_or(self.error("expected a texture name or color".to_string()))?;
+        if tok.parse::<f32>().is_ok() {
+            return Ok(Texture::solid_color(self.next_vec3(tokens)?));
+        }
+        tokens.next();
+        match self.textures.get(tok) {
+            Some(texture) => Ok(texture.clone()),
+            None => Err(self.error(format!("unknown texture '{}'", tok)))
+        }
+    }
+    fn expect_end(&self, tokens: &mut std::slice::Iter<&str>) -> Result<(), String> {
+        match tokens.next() {
+            Some(tok) => Err(self.error(format!("unexpected '{}'", tok))),
+            None => Ok(())
+        }
+    }
+
+    fn parse_camera(&mut self, tokens: &mut std::slice::Iter<&str>) -> Result<(), String> {
+        while let Some(key) = tokens.next() {
+            match *key {
+                "from" => self.look_from = Some(self.next_vec3(tokens)?),
+                "at" => self.look_at = Some(self.next_vec3(tokens)?),
+                "up" => self.vup = self.next_vec3(tokens)?,
+                "fov" => self.vfov = self.next_f32(tokens)?,
+                "aperture" => self.aperture = self.next_f32(tokens)?,
+                "focus" => self.focus_dist = Some(self.next_f32(tokens)?),
+                "shutter" => self.shutter = (self.next_f32(tokens)?, self.next_f32(tokens)?),
+                _ => return Err(self.error(format!("unknown camera parameter '{}'", key)))
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_environment(&mut self, tokens: &mut std::slice::Iter<&str>) -> Result<(), String> {
+        let path = self.resolve_path(self.next_token(tokens, "an image path")?);
+        if !Path::new(&path).is_file() {
+            return Err(self.error(format!("image '{}' does not exist", path)));
+        }
+        let mut intensity = 1.0;
+        let mut rotations = Vec::new();
+        while let Some(key) = tokens.next() {
+            match *key {
+                "intensity" => intensity = self.next_f32(tokens)?,
+                "rotate" => rotations.push((self.next_axis(tokens)?, self.next_f32(tokens)?)),
+                _ => return Err(self.error(format!("unknown environment parameter '{}'", key)))
+            }
+        }
+        self.background = Background::environment_map(&self.scene.atlas, &path, intensity, &rotations).map_err(|e| self.error(e))?;
+        Ok(())
+    }
+
+    fn parse_sky(&mut self, tokens: &mut std::slice::Iter<&str>) -> Result<(), String> {
+        let (mut elevation, mut azimuth, mut turbidity, mut intensity, mut sun_size) = (45.0, 0.0, 3.0, 1.0, 0.53);
+        while let Some(key) = tokens.next() {
+            match *key {
+                "elevation" => elevation = self.next_f32(tokens)?,
+                "azimuth" => azimuth = self.next_f32(tokens)?,
+                "turbidity" => turbidity = self.next_f32(tokens)?,
+                "intensity" => intensity = self.next_f32(tokens)?,
+                "sun_size" => sun_size = self.next_f32(tokens)?,
+                _ => return Err(self.error(format!("unknown sky parameter '{}'", key)))
+            }
+        }
+        if turbidity < 1.7 {
+            return Err(self.error(format!("turbidity must be at least 1.7, found {}", turbidity)));
+        }
+        if sun_size <= 0.0 {
+            return Err(self.error(format!("sun_size must be positive, found {}", sun_size)));
+        }
+        self.background = Background::sky(elevation, azimuth, turbidity, intensity, sun_size);
+        Ok(())
+    }
+
+    fn parse_point_light(&mut self, tokens: &mut std::slice::Iter<&str>) -> Result<(), String> {
+        let position = self.next_vec3(tokens)?;
+        let intensity = self.next_vec3(tokens)?;
+        let mut decay = 2.0;
+        while let Some(key) = tokens.next() {
+            match *key {
+                "decay" => decay = self.next_f32(tokens)?,
+                _ => return Err(self.error(format!("unknown point_light parameter '{}'", key)))
+            }
+        }
+        self.scene.add_light(DeltaLight::point(position, intensity, decay));
+        Ok(())
+    }
+
+    fn parse_spot_light(&mut self, tokens: &mut std::slice::Iter<&str>) -> Result<(), String> {
+        let position = self.next_vec3(tokens)?;
+        let target = self.next_vec3(tokens)?;
+        let intensity = self.next_vec3(tokens)?;
+        let (mut cone, mut falloff, mut decay) = (45.0, 5.0, 2.0);
+        while let Some(key) = tokens.next() {
+            match *key {
+                "cone" => cone = self.next_f32(tokens)?,
+                "falloff" => falloff = self.next_f32(tokens)?,
+                "decay" => decay = self.next_f32(tokens)?,
+                _ => return Err(self.error(format!("unknown spot_light parameter '{}'", key)))
+            }
+        }
+        if (target - position).length() <= 0.0 {
+            return Err(self.error("spot_light target must differ from its position".to_string()));
+        }
+        self.scene.add_light(DeltaLight::spot(position, target, intensity, cone, falloff, decay));
+        Ok(())
+    }
+
+    fn parse_gltf(&mut self, tokens: &mut std::slice::Iter<&str>) -> Result<(), String> {
+        if !self.groups.is_empty() {
+            return Err(self.error("gltf can't be used inside a group".to_string()));
+        }
+        let path = self.resolve_path(self.next_token(tokens, "a glTF path")?);
+        if !Path::new(&path).is_file() {
+            return Err(self.error(format!("glTF file '{}' does not exist", path)));
+        }
+        let (mut scale, mut camera) = (1.0, None);
+        while let Some(key) = tokens.next() {
+            match *key {
+                "scale" => scale = self.next_f32(tokens)?,
+                "camera" => camera = Some(self.next_usize(tokens)?),
+                _ => return Err(self.error(format!("unknown gltf parameter '{}'", key)))
+            }
+        }
+        let cameras = self.scene.load_gltf(&path, scale).map_err(|e| self.error(e))?;
+        match camera {
+            Some(idx) if idx >= cameras.len() => return Err(self.error(format!("camera {} not found, the file has {}", idx, cameras.len()))),
+            Some(idx) => self.gltf_camera = Some(cameras[idx].clone()),
+            None => if self.gltf_camera.is_none() {
+                self.gltf_camera = cameras.first().cloned();
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_texture(&mut self, tokens: &mut std::slice::Iter<&str>) -> Result<(), String> {
+        let name = self.next_token(tokens, "a texture name")?;
+        let kind = self.next_token(tokens, "a texture type")?;
+        let texture = match kind {
+            "solid" => Texture::solid_color(self.next_vec3(tokens)?),
+            "checkered" => Texture::checkered(self.next_vec3(tokens)?, self.next_vec3(tokens)?),
+            "noise" => Texture::noise(self.next_f32(tokens)?, self.next_usize(tokens)?),
+            "vertex_color" => Texture::vertex_color(),
+            "image" => {
+                let path = self.resolve_path(self.next_token(tokens, "an image path")?);
+                if !Path::new(&path).is_file() {
+                    return Err(self.error(format!("image '{}' does not exist", path)));
+                }
+                self.scene.load_image(&path).map_err(|e| self.error(e))?
+            },
+            _ => return Err(self.error(format!("unknown texture type '{}'", kind)))
+        };
+        self.expect_end(tokens)?;
+        self.textures.insert(name.to_string(), texture);
+        Ok(())
+    }
+
+    fn parse_material(&mut self, tokens: &mut std::slice::Iter<&str>) -> Result<(), String> {
+        let name = self.next_token(tokens, "a material name")?;
+        let kind = self.next_token(tokens, "a material type")?;
+        let material = match kind {
+            "lambertian" => Material::lambertian(self.next_texture(tokens)?),
+            "glossy" => Material::glossy(self.next_vec3(tokens)?, self.next_f32(tokens)?),
+            "dielectric" => {
+                let tok = self.next_token(tokens, "a refraction index or glass name")?;
+                match tok {
+                    "cauchy" => Material::dispersive_dielectric(Dispersion::Cauchy(self.next_f32(tokens)?, self.next_f32(tokens)?)),
+                    "sellmeier" => {
+                        let (b, c) = (self.next_vec3(tokens)?, self.next_vec3(tokens)?);
+                        Material::dispersive_dielectric(Dispersion::Sellmeier([b.x, b.y, b.z], [c.x, c.y, c.z]))
+                    },
+                    _ => match tok.parse::<f32>() {
+                        Ok(refraction_index) => Material::dielectric(refraction_index),
+                        Err(_) => match Dispersion::preset(tok) {
+                            Some(dispersion) => Material::dispersive_dielectric(dispersion),
+                            None => return Err(self.error(format!("unknown glass '{}'", tok)))
+                        }
+                    }
+                }
+            },
+            "conductor" => {
+                let tok = *tokens.clone().next().ok_or(self.error("expected a metal name or complex index of refraction".to_string()))?;
+                if tok.parse::<f32>().is_ok() {
+                    Material::conductor(self.next_vec3(tokens)?, self.next_vec3(tokens)?, self.next_f32(tokens)?)
+                } else {
+                    tokens.next();
+                    match Material::conductor_preset(tok, self.next_f32(tokens)?) {
+                        Some(material) => material,
+                        None => return Err(self.error(format!("unknown metal '{}'", tok)))
+                    }
+                }
+            },
+            "rough_dielectric" => Material::rough_dielectric(self.next_f32(tokens)?, self.next_f32(tokens)?),
+            "isotropic" => Material::isotropic(self.next_vec3(tokens)?),
+            "emissive" => {
+                let texture = self.next_texture(tokens)?;
+                Material::emissive_texture(texture, self.parse_emission_params(tokens)?, &self.scene.atlas)
+            },
+            "principled" => {
+                let base_color = self.next_texture(tokens)?;
+                Material::principled(base_color, self.parse_principled_params(tokens)?)
+            },
+            _ => return Err(self.error(format!("unknown material type '{}'", kind)))
+        };
+        self.expect_end(tokens)?;
+        self.materials.insert(name.to_string(), material);
+        Ok(())
+    }
+
+    fn parse_emission_params(&self, tokens: &mut std::slice::Iter<&str>) -> Result<EmissionParams, String> {
+        let mut params = EmissionParams::new();
+        while let Some(key) = tokens.next() {
+            match *key {
+                "intensity" => params.intensity = self.next_f32(tokens)?,
+                "temperature" => params.tint = blackbody(self.next_f32(tokens)?),
+                "power" => params.power = Some(self.next_f32(tokens)?),
+                "lumens" => params.power = Some(self.next_f32(tokens)? / LUMENS_PER_WATT),
+                "sides" => {
+                    let tok = self.next_token(tokens, "front, back or both")?;
+                    params.sides = match tok {
+                        "front" => EmissionSides::Front,
+                        "back" => EmissionSides::Back,
+                        "both" => EmissionSides::Both,
+                        _ => return Err(self.error(format!("expected front, back or both, found '{}'", tok)))
+                    };
+                },
+                _ => return Err(self.error(format!("unknown emissive parameter '{}'", key)))
+            }
+        }
+        Ok(params)
+    }
+
+    fn parse_principled_params(&self, tokens: &mut std::slice::Iter<&str>) -> Result<PrincipledParams, String> {
+        let mut params = PrincipledParams::new();
+        while let Some(key) = tokens.next() {
+            let value = self.next_f32(tokens)?;
+            match *key {
+                "metallic" => params.metallic = value,
+                "roughness" => params.roughness = value,
+                "specular" => params.specular = value,
+                "specular_tint" => params.specular_tint = value,
+                "sheen" => params.sheen = value,
+                "sheen_tint" => params.sheen_tint = value,
+                "clearcoat" => params.clearcoat = value,
+                "clearcoat_gloss" => params.clearcoat_gloss = value,
+                "transmission" => params.transmission = value,
+                "ior" => params.ior = value,
+                _ => return Err(self.error(format!("unknown principled parameter '{}'", key)))
+            }
+        }
+        Ok(params)
+    }
+
+    fn parse_geometry(&mut self, kind: &str, tokens: &mut std::slice::Iter<&str>) -> Result<Geometry, String> {
+        let mut geometry = match kind {
+            "sphere" => Geometry::sphere(self.next_vec3(tokens)?, self.next_f32(tokens)?, self.next_material(tokens)?),
+            "moving_sphere" => Geometry::moving_sphere(
+                self.next_vec3(tokens)?, self.next_vec3(tokens)?,
+                self.next_f32(tokens)?, self.next_f32(tokens)?, self.next_f32(tokens)?,
+                self.next_material(tokens)?
+            ),
+            "xyrect" | "xzrect" | "yzrect" => {
+                let (a0, a1, b0, b1, k) = (self.next_f32(tokens)?, self.next_f32(tokens)?, self.next_f32(tokens)?, self.next_f32(tokens)?, self.next_f32(tokens)?);
+                let material = self.next_material(tokens)?;
+                match kind {
+                    "xyrect" => Geometry::xyrect(a0, a1, b0, b1, k, material),
+                    "xzrect" => Geometry::xzrect(a0, a1, b0, b1, k, material),
+                    _ => Geometry::yzrect(a0, a1, b0, b1, k, material),
+                }
+            },
+            "cuboid" => Geometry::cuboid(self.next_vec3(tokens)?, self.next_vec3(tokens)?, self.next_material(tokens)?),
+            "triangle" => Geometry::triangle(self.next_vec3(tokens)?, self.next_vec3(tokens)?, self.next_vec3(tokens)?, self.next_material(tokens)?),
+            "obj" => {
+                let path = self.resolve_path(self.next_token(tokens, "an obj path")?);
+                if !Path::new(&path).is_file() {
+                    return Err(self.error(format!("obj file '{}' does not exist", path)));
+                }
+                let scale = self.next_f32(tokens)?;
+                let material = match tokens.clone().next() {
+                    Some(tok) if *tok != "select" => Some(self.next_material(tokens)?),
+                    _ => None
+                };
+                let mut select = Vec::new();
+                while let Some(key) = tokens.next() {
+                    match *key {
+                        "select" => select.push(self.next_token(tokens, "an object or group name")?.to_string()),
+                        _ => return Err(self.error(format!("unknown obj parameter '{}'", key)))
+                    }
+                }
+                Geometry::load_obj(&path, scale, material, &select, &self.scene.atlas).map_err(|e| self.error(e))?
+            },
+            "ply" | "stl" => {
+                let path = self.resolve_path(self.next_token(tokens, "a mesh path")?);
+                if !Path::new(&path).is_file() {
+                    return Err(self.error(format!("{} file '{}' does not exist", kind, path)));
+                }
+                let scale = self.next_f32(tokens)?;
+                let material = match tokens.clone().next() {
+                    Some(_) => Some(self.next_material(tokens)?),
+                    None => None
+                };
+                let geometry = if kind == "ply" { Geometry::load_ply(&path, scale, material) } else { Geometry::load_stl(&path, scale, material) };
+                geometry.map_err(|e| self.error(e))?
+            },
+            "instance" => {
+                let mut geometry = self.next_definition(tokens)?;
+                while let Some(op) = tokens.next() {
+                    geometry = match *op {
+                        "rotate" => Geometry::instance_rotation(geometry, self.next_axis(tokens)?, self.next_f32(tokens)?),
+                        "translate" => Geometry::instance_translation(geometry, self.next_vec3(tokens)?),
+                        _ => return Err(self.error(format!("unknown instance transform '{}'", op)))
+                    };
+                }
+                geometry
+            },
+            "medium" => Geometry::constant_medium(self.next_definition(tokens)?, self.next_f32(tokens)?, self.next_vec3(tokens)?),
+            _ => return Err(self.error(format!("unknown statement '{}'", kind)))
+        };
+        self.expect_end(tokens)?;
+        // Emission given as a power is spread over the whole object, not each of its faces
+        let area = geometry.area();
+        if area > 0.0 {
+            geometry.resolve_emission_power(area);
+        }
+        Ok(geometry)
+    }
+
+    fn add(&mut self, geometry: Geometry) {
+        match self.groups.last_mut() {
+            Some((_name, members)) => members.push(geometry),
+            None => self.scene.add(geometry)
+        }
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let content = match line.find('#') {
+            Some(idx) => &line[..idx],
+            None => line
+        };
+        let words: Vec<&str> = content.split_whitespace().collect();
+        let mut tokens = words.iter();
+        let keyword = match tokens.next() {
+            Some(keyword) => *keyword,
+            None => return Ok(())
+        };
+
+        match keyword {
+            "image" => {
+                self.image_width = Some(self.next_usize(&mut tokens)? as u32);
+                self.image_height = Some(self.next_usize(&mut tokens)? as u32);
+                self.expect_end(&mut tokens)?;
+            },
+            "samples" => {
+                self.samples_per_pixel = self.next_usize(&mut tokens)?;
+                self.expect_end(&mut tokens)?;
+            },
+            "depth" => {
+                self.max_depth = self.next_usize(&mut tokens)?;
+                self.expect_end(&mut tokens)?;
+            },
+            "background" => {
+                self.background = Background::constant(self.next_vec3(&mut tokens)?);
+                self.expect_end(&mut tokens)?;
+            },
+            "environment" => self.parse_environment(&mut tokens)?,
+            "sky" => self.parse_sky(&mut tokens)?,
+            "camera" => self.parse_camera(&mut tokens)?,
+            "point_light" => self.parse_point_light(&mut tokens)?,
+            "spot_light" => self.parse_spot_light(&mut tokens)?,
+            "directional_light" => {
+                let direction = self.next_vec3(&mut tokens)?;
+                if direction.length() <= 0.0 {
+                    return Err(self.error("directional_light direction must not be zero".to_string()));
+                }
+                self.scene.add_light(DeltaLight::directional(direction, self.next_vec3(&mut tokens)?));
+                self.expect_end(&mut tokens)?;
+            },
+            "gltf" => self.parse_gltf(&mut tokens)?,
+            "texture" => self.parse_texture(&mut tokens)?,
+            "material" => self.parse_material(&mut tokens)?,
+            "define" => {
+                let name = self.next_token(&mut tokens, "a geometry name")?;
+                let kind = self.next_token(&mut tokens, "a geometry statement")?;
+                let geometry = self.parse_geometry(kind, &mut tokens)?;
+                self.definitions.insert(name.to_string(), geometry);
+            },
+            "light" => {
+                let kind = self.next_token(&mut tokens, "a geometry statement")?;
+                let geometry = self.parse_geometry(kind, &mut tokens)?;
+                self.add(geometry);
+            },
+            "group" => {
+                let name = self.next_token(&mut tokens, "a group name")?;
+                self.expect_end(&mut tokens)?;
+                self.groups.push((name.to_string(), Vec::new()));
+            },
+            "end" => {
+                self.expect_end(&mut tokens)?;
+                let (name, members) = match self.groups.pop() {
+                    Some(group) => group,
+                    None => return Err(self.error("'end' without a matching 'group'".to_string()))
+                };
+                if members.is_empty() {
+                    return Err(self.error(format!("group '{}' is empty", name)));
+                }
+                self.definitions.insert(name, Geometry::bvh_node(&members, self.shutter.0, self.shutter.1, 0, members.len()));
+            },
+            _ => {
+                let geometry = self.parse_geometry(keyword, &mut tokens)?;
+                self.add(geometry);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Scene, String> {
+        if let Some((name, _members)) = self.groups.last() {
+            return Err(format!("group '{}' is missing its 'end'", name));
+        }
+        let (image_width, image_height) = match (self.image_width, self.image_height) {
+            (Some(w), Some(h)) if w > 0 && h > 0 => (w, h),
+            (Some(_), Some(_)) => return Err("image dimensions must be positive".to_string()),
+            _ => return Err("missing 'image' statement".to_string())
+        };
+        let aspect_ratio = image_width as f32 / image_height as f32;
+        let (look_from, look_at) = match (self.look_from, self.look_at, &self.gltf_camera) {
+            (Some(look_from), Some(look_at), _) => (look_from, look_at),
+            (None, None, Some(cam)) => return Ok((cam.with_aspect_ratio(aspect_ratio), self.scene, self.background, aspect_ratio, image_width, image_height, self.samples_per_pixel, self.max_depth)),
+            _ => return Err("missing 'camera' statement with 'from' and 'at'".to_string())
+        };
+
+        let cam = Camera::new(
+            look_from,
+            look_at,
+            self.vup,
+            self.vfov,
+            self.aperture,
+            self.focus_dist.unwrap_or((look_from - look_at).length()),
+            aspect_ratio,
+            self.shutter.0, self.shutter.1
+        );
+
+        return Ok((cam, self.scene, self.background, aspect_ratio, image_width, image_height, self.samples_per_pixel, self.max_depth));
+    }
+}
+
+
+// Camera, objects and background of a scene, with its aspect ratio, image width and height, samples
+// per pixel and maximum depth
+pub type Scene = (Camera, SceneColliders, Background, f32, u32, u32, usize, usize);
+
+pub fn load_scene(path: &str) -> Result<Scene, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let base_dir = match Path::new(path).parent() {
+        Some(dir) => dir.to_path_buf(),
+        None => PathBuf::new()
+    };
+
+    let mut parser = SceneParser::new(base_dir);
+    for (i, line) in source.lines().enumerate() {
+        parser.line = i + 1;
+        parser.parse_line(line).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    parser.finish().map_err(|e| format!("{}: {}", path, e))
+}
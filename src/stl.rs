@@ -0,0 +1,154 @@
+#[path = "ply.rs"] mod ply;
+pub use ply::*;
+use std::collections::HashMap;
+use std::fs;
+
+
+// Corners of the triangles of a binary STL file: an 80 byte header, the triangle count and 50 bytes
+// per triangle (normal, three corners and an attribute word)
+fn read_binary_stl(data: &[u8]) -> Result<Vec<[f32; 3]>, String> {
+    let count = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+    if data.len() < 84 + 50 * count {
+        return Err(format!("expected {} triangles, but the file ends after {}", count, (data.len() - 84) / 50));
+    }
+    let mut corners = Vec::with_capacity(3 * count);
+    for triangle in data[84..84 + 50 * count].chunks_exact(50) {
+        for corner in 1..4 {
+            let value = |axis: usize| {
+                let at = 12 * corner + 4 * axis;
+                f32::from_le_bytes([triangle[at], triangle[at + 1], triangle[at + 2], triangle[at + 3]])
+            };
+            corners.push([value(0), value(1), value(2)]);
+        }
+    }
+    Ok(corners)
+}
+
+// Corners of the triangles of an ASCII STL file, taken from its `vertex` lines in order
+fn read_ascii_stl(source: &str) -> Result<Vec<[f32; 3]>, String> {
+    let mut corners = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.first() != Some(&"vertex") {
+            continue;
+        }
+        if words.len() != 4 {
+            return Err(format!("line {}: expected three coordinates after 'vertex'", number + 1));
+        }
+        let mut corner = [0.0; 3];
+        for axis in 0..3 {
+            corner[axis] = words[axis + 1].parse().map_err(|_| format!("line {}: expected a number, found '{}'", number + 1, words[axis + 1]))?;
+        }
+        corners.push(corner);
+    }
+    if corners.len() % 3 != 0 {
+        return Err(format!("{} vertices do not make whole triangles", corners.len()));
+    }
+    Ok(corners)
+}
+
+impl Geometry {
+    // Loads an STL file, ASCII or binary, scaled about the origin, into a mesh. Corners at the same
+    // position are merged into one vertex. STL files only hold flat facets, so the mesh has no
+    // normals or texture coordinates, and is light gray lambertian unless `material` is given.
+    pub fn load_stl(path: &str, scale: f32, material: Option<Material>) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        // Binary files may also start with "solid", so those that have the size their triangle count
+        // gives, or bytes past the header that aren't text, are read as binary
+        let size_matches = data.len() >= 84 && data.len() == 84 + 50 * u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+        let is_ascii = data.starts_with(b"solid") && !size_matches && data[..data.len().min(512)].is_ascii();
+        let corners = if is_ascii {
+            read_ascii_stl(&String::from_utf8_lossy(&data))
+        } else if data.len() >= 84 {
+            read_binary_stl(&data)
+        } else {
+            Err("not an STL file".to_string())
+        }.map_err(|e| format!("{}: {}", path, e))?;
+
+        let mut positions = Vec::new();
+        let mut vertex_index: HashMap<[u32; 3], u32> = HashMap::new();
+        let indices: Vec<u32> = corners.iter().map(|corner| *vertex_index.entry(corner.map(|x| x.to_bits())).or_insert_with(|| {
+            positions.push(Point3::new(corner[0], corner[1], corner[2]) * scale);
+            (positions.len() - 1) as u32
+        })).collect();
+        let triangles = indices.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect();
+
+        let material = material.unwrap_or(Material::lambertian(Texture::solid_color(Rgb::new(0.8, 0.8, 0.8))));
+        let mesh = MeshData::new(positions, Vec::new(), Vec::new(), Vec::new(), triangles, vec![material], Vec::new()).map_err(|e| format!("{}: {}", path, e))?;
+        if mesh.triangle_count() == 0 {
+            return Err(format!("{}: no triangles", path));
+        }
+        Ok(Geometry::mesh(mesh))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, data: &[u8]) -> Result<std::sync::Arc<MeshData>, String> {
+        load_test_mesh(&[(&format!("{}.stl", name), data)], |path| Geometry::load_stl(path, 1.0, None))
+    }
+    // Binary file holding the given triangles, with `header` at the start of its 80 byte header
+    fn binary(header: &[u8], triangles: &[[[f32; 3]; 3]]) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.resize(80, 0);
+        data.extend((triangles.len() as u32).to_le_bytes());
+        for triangle in triangles {
+            data.extend([0u8; 12]);
+            for value in triangle.iter().flatten() {
+                data.extend(value.to_le_bytes());
+            }
+            data.extend([0u8; 2]);
+        }
+        data
+    }
+    const SQUARE: [[[f32; 3]; 3]; 2] = [
+        [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]],
+        [[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]],
+    ];
+
+    #[test]
+    fn reads_ascii_vertices() {
+        let source = "solid square\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nvertex 1 1 0\nendloop\nendfacet\nendsolid square\n";
+        assert_eq!(read_ascii_stl(source).unwrap(), [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]]);
+    }
+
+    #[test]
+    fn rejects_malformed_ascii() {
+        assert_eq!(read_ascii_stl("solid\nvertex 0 0\n").err().unwrap(), "line 2: expected three coordinates after 'vertex'");
+        assert_eq!(read_ascii_stl("solid\nvertex 0 x 0\n").err().unwrap(), "line 2: expected a number, found 'x'");
+        assert_eq!(read_ascii_stl("solid\nvertex 0 0 0\nvertex 1 0 0\n").err().unwrap(), "2 vertices do not make whole triangles");
+    }
+
+    #[test]
+    fn rejects_truncated_binary() {
+        let mut data = binary(b"", &SQUARE);
+        data.truncate(data.len() - 1);
+        assert_eq!(read_binary_stl(&data).err().unwrap(), "expected 2 triangles, but the file ends after 1");
+    }
+
+    #[test]
+    fn merges_shared_corners() {
+        // Six corners, two of them repeated
+        let mesh = load("binary", &binary(b"", &SQUARE)).unwrap();
+        assert_eq!(mesh.positions.len(), 4);
+        assert!(mesh.normals.is_empty() && mesh.uvs.is_empty());
+    }
+
+    #[test]
+    fn reads_binary_files_whose_header_starts_with_solid() {
+        let mesh = load("solid_header", &binary(b"solid exported by a binary writer", &SQUARE)).unwrap();
+        assert_eq!(mesh.triangle_count(), 2);
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_stl() {
+        assert!(load("short", b"hello").err().unwrap().ends_with("not an STL file"));
+        assert!(load("empty_ascii", b"solid nothing\nendsolid nothing\n").err().unwrap().ends_with("no triangles"));
+        let mut junk = vec![0xffu8; 200];
+        junk[80..84].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(load("junk", &junk).err().unwrap().ends_with("expected 1000 triangles, but the file ends after 2"));
+    }
+}
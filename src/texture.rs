@@ -3,7 +3,8 @@ pub use perlin::*;
 
 use std::{fs::File, path::Path, io::BufReader, sync::Arc, sync::Mutex};
 
-use image::{self, GenericImageView, DynamicImage};
+use image::{self, GenericImageView, DynamicImage, ImageBuffer};
+use image::codecs::hdr::HdrDecoder;
 
 
 pub struct ImageTextureAtlas {
@@ -16,10 +17,44 @@ impl ImageTextureAtlas {
             images: Vec::new()
         }
     }
-    pub fn load(&mut self, path: &str) -> usize {
-        self.images.push(image::open(&Path::new(&path)).unwrap());
+    pub fn load(&mut self, path: &str) -> Result<usize, String> {
+        // The generic loader squeezes Radiance files into 8 bits, so they are decoded here to keep their range
+        let img = if path.to_lowercase().ends_with(".hdr") {
+            let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+            let decoder = HdrDecoder::new(BufReader::new(file)).map_err(|e| format!("{}: {}", path, e))?;
+            let meta = decoder.metadata();
+            let data: Vec<f32> = decoder.read_image_hdr().map_err(|e| format!("{}: {}", path, e))?.iter().flat_map(|p| p.0).collect();
+            DynamicImage::ImageRgb32F(ImageBuffer::from_raw(meta.width, meta.height, data).ok_or(format!("{}: image data does not match its size", path))?)
+        } else {
+            image::open(&Path::new(&path)).map_err(|e| format!("{}: {}", path, e))?
+        };
+        Ok(self.add(img))
+    }
+    // Adds an image decoded elsewhere, such as one embedded in a glTF file
+    pub fn add(&mut self, img: DynamicImage) -> usize {
+        self.images.push(img);
         return self.images.len() - 1;
     }
+    pub fn dimensions(&self, idx: usize) -> (u32, u32) {
+        self.images[idx].dimensions()
+    }
+    // Color of a pixel. Floating point images keep their full range, others are scaled to [0, 1].
+    pub fn texel(&self, idx: usize, i: u32, j: u32) -> Rgb {
+        match &self.images[idx] {
+            DynamicImage::ImageRgb32F(img) => {
+                let pixel = img.get_pixel(i, j);
+                Rgb::new(pixel.0[0], pixel.0[1], pixel.0[2])
+            },
+            DynamicImage::ImageRgba32F(img) => {
+                let pixel = img.get_pixel(i, j);
+                Rgb::new(pixel.0[0], pixel.0[1], pixel.0[2])
+            },
+            img => {
+                let pixel = img.get_pixel(i, j);
+                Rgb::new(pixel.0[0] as f32, pixel.0[1] as f32, pixel.0[2] as f32) / 255.0
+            }
+        }
+    }
 }
 
 
@@ -30,6 +65,8 @@ pub enum TextureType {
     Checkered(Rgb, Rgb),
     NoiseTexture(Perlin, f32, usize),
     ImageTexture(u32, u32, u32, usize),
+    // Color of the mesh vertices around the hit, interpolated
+    VertexColor,
 }
 
 #[derive(Clone)]
@@ -63,6 +100,13 @@ impl Texture {
         }
     }
 
+    // Colors the surface with the vertex colors of the mesh it is put on, and white elsewhere
+    pub fn vertex_color() -> Self {
+        Self {
+            texture_type: TextureType::VertexColor,
+        }
+    }
+
     fn get_solid_color(&self, color: &Rgb, _u: f32, _v: f32, _point: Point3) -> Rgb {
         *color
     }
@@ -93,12 +137,33 @@ impl Texture {
         return Rgb::new(pixel.0[0] as f32 * color_scale, pixel.0[1] as f32 * color_scale, pixel.0[2] as f32 * color_scale);
     }
 
-    pub fn get_color(&self, u: f32, v: f32, point: Point3, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Rgb {
+    // Mean color over the texture's surface, as `get_color` would return it. Noise averages to mid gray.
+    pub fn average_color(&self, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Rgb {
+        match &self.texture_type {
+            TextureType::SolidColor(color) => *color,
+            TextureType::Checkered(odd_color, even_color) => (*odd_color + *even_color) * 0.5,
+            TextureType::NoiseTexture(..) => Rgb::new(0.5, 0.5, 0.5),
+            // Unknown until the texture is put on a mesh
+            TextureType::VertexColor => Rgb::new(1.0, 1.0, 1.0),
+            TextureType::ImageTexture(_bytes_per_scanline, width, height, img_data_idx) => {
+                let atlas = atlas.lock().unwrap();
+                let mut sum = Rgb::origin();
+                for (_i, _j, pixel) in atlas.images[*img_data_idx].pixels() {
+                    sum = sum + Rgb::new(pixel.0[0] as f32, pixel.0[1] as f32, pixel.0[2] as f32);
+                }
+                sum / (255.0 * (*width as f32) * (*height as f32))
+            },
+        }
+    }
+
+    // `vertex_color` is the interpolated vertex color at the point, white for surfaces without one
+    pub fn get_color(&self, u: f32, v: f32, point: Point3, vertex_color: Rgb, atlas: &Arc<Mutex<ImageTextureAtlas>>) -> Rgb {
         match &self.texture_type {
             TextureType::SolidColor(color) => self.get_solid_color(color, u, v, point),
             TextureType::Checkered(odd_color, even_color) => self.get_checkered_color(odd_color, even_color, u, v, point),
             TextureType::NoiseTexture(noise, scale, turb) => self.get_noise_color(noise, scale, turb, u, v, point),
             TextureType::ImageTexture(_bytes_per_scanline, width, height, img_data_idx) => self.get_image_color(width, height, img_data_idx, u, v, point, atlas),
+            TextureType::VertexColor => vertex_color,
         }
     }
 }
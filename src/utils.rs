@@ -1,5 +1,6 @@
 pub use std::f32::consts::{PI, TAU};
-use rand::{Rng, thread_rng};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 
 #[derive(Clone, Copy)]
@@ -38,20 +39,98 @@ pub fn to_radians(degrees: f32) -> f32 {
     degrees * PI / 180.0
 }
 
+pub fn to_degrees(radians: f32) -> f32 {
+    radians * 180.0 / PI
+}
+
+
+pub fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E3779B97F4A7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D049BB133111EB);
+    x ^ (x >> 31)
+}
+
+
+// PCG32 generator (O'Neill 2014). Its output only depends on the seed and stream, never on the
+// thread it runs on, which is what makes renders reproducible.
+#[derive(Clone)]
+pub struct Pcg32 {
+    state: u64,
+    inc: u64,
+}
+
+impl Pcg32 {
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Self {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(splitmix64(seed));
+        rng.next_u32();
+        return rng;
+    }
+    // Generator derived from arbitrary bit patterns, for code that has no sampler in reach
+    pub fn from_hash(values: &[u32]) -> Self {
+        let mut h = 0xCBF29CE484222325u64;
+        for v in values.iter() {
+            h = splitmix64(h ^ *v as u64);
+        }
+        Self::new(h, 0)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(6364136223846793005).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+    // Uniform in [0, 1)
+    pub fn random(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+    }
+    pub fn randrange(&mut self, min_inclusive: f32, max_exclusive: f32) -> f32 {
+        min_inclusive + (max_exclusive - min_inclusive) * self.random()
+    }
+    pub fn randuint(&mut self, min_inclusive: usize, max_exclusive: usize) -> usize {
+        if max_exclusive <= min_inclusive {
+            return min_inclusive;
+        }
+        min_inclusive + ((self.next_u32() as u64 * (max_exclusive - min_inclusive) as u64) >> 32) as usize
+    }
+}
+
+
+// The free functions below draw from a per thread generator and are meant for building scenes
+// (random sphere fields, Perlin tables, ...), which happens on the main thread. It is seeded from
+// the global seed (0 unless `seed_random` is called), so scenes are the same on every run.
+static GLOBAL_SEED: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static SCENE_RNG: RefCell<Pcg32> = RefCell::new(Pcg32::new(GLOBAL_SEED.load(Ordering::Relaxed), 0));
+}
+
+pub fn seed_random(seed: u64) {
+    GLOBAL_SEED.store(seed, Ordering::Relaxed);
+    SCENE_RNG.with(|rng| *rng.borrow_mut() = Pcg32::new(seed, 0));
+}
+
 pub fn random() -> f32 {
-    return thread_rng().gen_range(0f32..1f32);
+    return SCENE_RNG.with(|rng| rng.borrow_mut().random());
 }
 
 pub fn randrange(min_inclusive: f32, max_exclusive: f32) -> f32 {
-    return thread_rng().gen_range(min_inclusive..max_exclusive);
+    return SCENE_RNG.with(|rng| rng.borrow_mut().randrange(min_inclusive, max_exclusive));
 }
 
 pub fn randint(min_inclusive: isize, max_inclusive: isize) -> isize {
-    return thread_rng().gen_range(min_inclusive..max_inclusive)
+    return min_inclusive + SCENE_RNG.with(|rng| rng.borrow_mut().randuint(0, (max_inclusive - min_inclusive) as usize)) as isize
 }
 
 pub fn randuint(min_inclusive: usize, max_inclusive: usize) -> usize {
-    return thread_rng().gen_range(min_inclusive..max_inclusive)
+    return SCENE_RNG.with(|rng| rng.borrow_mut().randuint(min_inclusive, max_inclusive))
 }
 
 pub fn clamp(x: f32, min: f32, max: f32) -> f32{
@@ -1,6 +1,6 @@
 use std::ops::{Add, Sub, Mul, Div, Neg, Index, IndexMut};
-#[path = "utils.rs"] mod utils;
-pub use utils::*;
+#[path = "sampler.rs"] mod sampler;
+pub use sampler::*;
 
 
 
@@ -216,13 +216,17 @@ impl ONB {
     pub fn local(&self, a: Vec3) -> Vec3 {
         self.u * a.x + self.v * a.y + self.w * a.z
     }
+    // Inverse of `local`: world space direction to (u, v, w) coordinates
+    pub fn to_local(&self, a: Vec3) -> Vec3 {
+        Vec3::new(a.dot(self.u), a.dot(self.v), a.dot(self.w))
+    }
 }
 
 
 
-pub fn random_in_unit_sphere() -> Vec3 {
+pub fn random_in_unit_sphere(sampler: &mut Sampler) -> Vec3 {
     loop {
-        let p = Vec3::randrange(-1.0, 1.0);
+        let p = Vec3::new(sampler.random(), sampler.random(), sampler.random()) * 2.0 - Vec3::new(1.0, 1.0, 1.0);
         if p.length_squared() >= 1.0 {
             continue;
         }
@@ -230,12 +234,12 @@ pub fn random_in_unit_sphere() -> Vec3 {
     }
 }
 
-pub fn random_unit_vec3() -> Vec3 {
-    random_in_unit_sphere().normalize()
+pub fn random_unit_vec3(sampler: &mut Sampler) -> Vec3 {
+    random_in_unit_sphere(sampler).normalize()
 }
 
-pub fn random_in_hemisphere(normal: Vec3) -> Vec3 {
-    let in_unit_sphere = random_in_unit_sphere();
+pub fn random_in_hemisphere(normal: Vec3, sampler: &mut Sampler) -> Vec3 {
+    let in_unit_sphere = random_in_unit_sphere(sampler);
     if in_unit_sphere.dot(normal) > 0.0 {
         return in_unit_sphere;
     }
@@ -244,19 +248,23 @@ pub fn random_in_hemisphere(normal: Vec3) -> Vec3 {
     }
 }
 
-pub fn random_in_unit_disk() -> Vec3 {
-    loop {
-        let p = Vec3::new(randrange(-1.0, 1.0), randrange(-1.0, 1.0), 0.0);
-        if p.length_squared() >= 1.0 {
-            continue;
-        }
-        return p;
+// Shirley's concentric mapping, which keeps the stratification of the 2D sample
+pub fn random_in_unit_disk(sampler: &mut Sampler) -> Vec3 {
+    let (u1, u2) = sampler.get_2d();
+    let (ox, oy) = (2.0 * u1 - 1.0, 2.0 * u2 - 1.0);
+    if ox == 0.0 && oy == 0.0 {
+        return Vec3::origin();
     }
+    let (r, theta) = if ox.abs() > oy.abs() {
+        (ox, PI / 4.0 * (oy / ox))
+    } else {
+        (oy, PI / 2.0 - PI / 4.0 * (ox / oy))
+    };
+    Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
 }
 
-pub fn random_cosin_direction() -> Vec3 {
-    let r1 = random();
-    let r2 = random();
+pub fn random_cosin_direction(sampler: &mut Sampler) -> Vec3 {
+    let (r1, r2) = sampler.get_2d();
     let z = (1.0 - r2).sqrt();
 
     let phi = TAU * r1;